@@ -1,8 +1,16 @@
+// Bits used for each coordinate of an encoded vertex, set by the pipeline as it
+// depends on the chunk size
+override NUM_BITS_IN_POS: u32 = 6u;
+
 // Define bindings and layouts
 // Storage buffer for chunk data
 @group(0) @binding(0)
 var<storage, read> chunkData : array<vec4<i32>>;
 
+// Block colours from the block registry, indexed by block id
+@group(0) @binding(1)
+var<storage, read> blockColours : array<vec4<f32>>;
+
 // Uniform buffer for matrices (e.g., projection and view)
 struct Uniforms {
     projection : mat4x4<f32>,
@@ -19,7 +27,8 @@ struct VertexInput {
 };
 
 struct VertexOutput {
-    @builtin(position) clip: vec4<f32>
+    @builtin(position) clip: vec4<f32>,
+    @location(0) @interpolate(flat) block: u32
 };
 
 fn create_translation_matrix(translation: vec4<i32>) -> mat4x4<f32> {
@@ -33,8 +42,7 @@ fn create_translation_matrix(translation: vec4<i32>) -> mat4x4<f32> {
 
 fn decode_vertex(vertex: u32) -> vec4<f32> {
     
-    let NUM_BITS_IN_POS: u32 = 6u;
-    let offset = 63u;
+    let offset = (1u << NUM_BITS_IN_POS) - 1u;
 
     let t1 = offset << NUM_BITS_IN_POS * 0;
     let z: u32 = (vertex & t1) >> (NUM_BITS_IN_POS * 0);
@@ -65,6 +73,9 @@ fn vs_main(input: VertexInput) -> VertexOutput {
 
     output.clip = uniforms.projection * uniforms.view * model * vertex;
 
+    // the block id is stored in the bits above the position
+    output.block = input.position >> (NUM_BITS_IN_POS * 3u);
+
     return output;
 }
 
@@ -77,21 +88,10 @@ struct FragmentOutput {
 fn fs_main(input: VertexOutput) -> FragmentOutput {
     var output: FragmentOutput;
 
-    // not implemented yet
-    var color_palettes: array<vec4<f32>, 8> = array<vec4<f32>, 8> (
-        vec4<f32>(1.0),
-        vec4<f32>(1.0),
-        vec4<f32>(1.0),
-        vec4<f32>(1.0),
-        vec4<f32>(1.0),
-        vec4<f32>(1.0),
-        vec4<f32>(1.0),
-        vec4<f32>(1.0),
-    );
-
-    let selector: u32 = u32(abs(floor(input.clip.y))) % 8u;
+    // unknown blocks use the colour of the last block
+    let block = min(input.block, arrayLength(&blockColours) - 1u);
 
-    output.color = color_palettes[selector];
+    output.color = blockColours[block];
 
     return output;
 }
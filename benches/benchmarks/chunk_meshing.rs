@@ -1,7 +1,12 @@
 use std::time::Duration;
 
 use criterion::{black_box, criterion_group, Criterion};
-use vvrs::chunk::{mesher::mesh, Chunk};
+use vvrs::chunk::{
+    block::{Block, Facing},
+    mesher::{mesh, mesh_with_neighbors},
+    neighborhood::ChunkNeighborhood,
+    Chunk, LocalBlockPos, CHUNK_SIZE,
+};
 
 fn build_and_mesh_chunk() {
     let chunk = Chunk::random();
@@ -9,15 +14,497 @@ fn build_and_mesh_chunk() {
     black_box(mesh(&chunk));
 }
 
+/// A chunk of rolling terrain, dirt under a layer of leaves, which has large
+/// flat areas to merge unlike the random chunk.
+fn terrain_chunk() -> Chunk {
+    let mut chunk = Chunk::default();
+    for x in 0..CHUNK_SIZE {
+        for z in 0..CHUNK_SIZE {
+            let height = CHUNK_SIZE / 2 + (x / 5 + z / 7) % 4;
+            chunk.fill_region(
+                LocalBlockPos(x, 0, z),
+                LocalBlockPos(x + 1, height, z + 1),
+                Block(1),
+            );
+            chunk.set_block(LocalBlockPos(x, height, z), Block(3));
+        }
+    }
+    chunk
+}
+
+/// The mesher from before the faces were merged with bit planes, copied as it
+/// was to compare against. `LocalBlockPos::safe_sub` is private, so it's
+/// copied too.
+mod hashmap_mesher {
+    use std::{collections::HashMap, time::Instant};
+
+    use vvrs::chunk::{
+        block::{Block, Facing},
+        neighborhood::ChunkNeighborhood,
+        Chunk, ChunkDimTy, ColumnMask, EncodedVertex, LocalBlockPos, CHUNK_SIZE, MAX_BLOCK_ID,
+        NUM_BITS_IN_POS,
+    };
+
+    /// Offset to the neighbouring block in the direction of each face.
+    const FACE_OFFSETS: [(i32, i32, i32); 6] = [
+        (0, 0, -1),
+        (0, 1, 0),
+        (1, 0, 0),
+        (0, 0, 1),
+        (0, -1, 0),
+        (-1, 0, 0),
+    ];
+
+    /// Returns the mesh of the chunk. The resulting chunk is split by the direction
+    /// of the faces. Everything outside the chunk is treated as air, see
+    /// `mesh_with_neighbors` to cull the faces on its borders.
+    /// The greedy face merging is a fairly naive implmenetation and doesn't use
+    /// binary operations on the face mask. Doesn't seem like it will be a
+    /// bottleneck yet, but it can always be changed.
+    pub fn mesh(chunk: &Chunk) -> [Vec<EncodedVertex>; 6] {
+        mesh_with_neighbors(&ChunkNeighborhood::new(chunk))
+    }
+
+    /// Returns the mesh of the center chunk of `hood`. Faces on the border of the
+    /// chunk are culled against the neighbouring chunks, neighbours that aren't
+    /// loaded count as air.
+    pub fn mesh_with_neighbors(hood: &ChunkNeighborhood) -> [Vec<EncodedVertex>; 6] {
+        // chunks made of a single block don't need to be culled or merged, unless
+        // a neighbour can hide part of their outside
+        if let Some(block) = hood.center().uniform_block() {
+            let isolated = Facing::ALL.iter().all(|f| hood.neighbor(*f).is_none());
+            if isolated || !block.is_visible() {
+                return mesh_uniform(block);
+            }
+        }
+
+        mesh_culled(hood)
+    }
+
+    /// Mesh of a chunk filled with a single block. Faces between two of the same
+    /// block are always hidden, so only the outside of the chunk is left.
+    fn mesh_uniform(block: Block) -> [Vec<EncodedVertex>; 6] {
+        if !block.is_visible() {
+            return Default::default();
+        }
+
+        let min = LocalBlockPos(0, 0, 0);
+        let max = LocalBlockPos(CHUNK_SIZE - 1, CHUNK_SIZE - 1, CHUNK_SIZE - 1);
+        std::array::from_fn(|axis| create_quad(axis, min, max, block))
+    }
+
+    fn mesh_culled(hood: &ChunkNeighborhood) -> [Vec<EncodedVertex>; 6] {
+        let cull_time = Instant::now();
+        let chunk = hood.center();
+
+        // the chunk keeps a binary representation of the opaque and the visible
+        // blocks, so we can cull the faces that are hidden by an opaque neighbour
+        let o = |x: usize, y: usize| chunk.opaque_column(x as ChunkDimTy, y as ChunkDimTy);
+        let v = |x: usize, y: usize| chunk.visible_column(x as ChunkDimTy, y as ChunkDimTy);
+
+        // the same for the columns of the neighbouring chunks, which pad the
+        // masks on the borders. Unloaded neighbours are all air
+        let last = CHUNK_SIZE as usize - 1;
+        let n = |facing: Facing, x: usize, y: usize| {
+            hood.neighbor(facing).map_or(0, |chunk| {
+                chunk.opaque_column(x as ChunkDimTy, y as ChunkDimTy)
+            })
+        };
+
+        // for each axis (direction), we want to create a map of the faces,
+        // ! with the block type !
+        // also hashmaps don't allocate until the first insert, so allocating them
+        // is fine
+        let mut data = [
+            HashMap::<LocalBlockPos, Block>::new(),
+            HashMap::<LocalBlockPos, Block>::new(),
+            HashMap::<LocalBlockPos, Block>::new(),
+            HashMap::<LocalBlockPos, Block>::new(),
+            HashMap::<LocalBlockPos, Block>::new(),
+            HashMap::<LocalBlockPos, Block>::new(),
+        ];
+
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                // the rows past the edges come from the neighbouring chunks
+                let x = x as usize;
+                let y = y as usize;
+                // cull z faces, shifting in the last block of the neighbouring
+                // columns
+                let previous_block = n(Facing::North, x, y) >> last;
+                let z_quads_forward = v(x, y) & !((o(x, y) << 1) | previous_block);
+                add_faces(hood, &mut data[0], x, y, 0, z_quads_forward);
+
+                let next_block = (n(Facing::South, x, y) & 1) << last;
+                let z_quads_backward = v(x, y) & !((o(x, y) >> 1) | next_block);
+                add_faces(hood, &mut data[3], x, y, 3, z_quads_backward);
+
+                // cull y faces
+                let next_row = if y == last {
+                    n(Facing::Up, x, 0)
+                } else {
+                    o(x, y + 1)
+                };
+                let y_quads_forward = v(x, y) & !next_row;
+                add_faces(hood, &mut data[1], x, y, 1, y_quads_forward);
+
+                let previous_row = if y == 0 {
+                    n(Facing::Down, x, last)
+                } else {
+                    o(x, y - 1)
+                };
+                let y_quads_backward = v(x, y) & !previous_row;
+                add_faces(hood, &mut data[4], x, y, 4, y_quads_backward);
+
+                // cull x faces
+                let next_row = if x == last {
+                    n(Facing::East, 0, y)
+                } else {
+                    o(x + 1, y)
+                };
+                let x_quads_forward = v(x, y) & !next_row;
+                add_faces(hood, &mut data[2], x, y, 2, x_quads_forward);
+
+                let previous_row = if x == 0 {
+                    n(Facing::West, last, y)
+                } else {
+                    o(x - 1, y)
+                };
+                let x_quads_backward = v(x, y) & !previous_row;
+                add_faces(hood, &mut data[5], x, y, 5, x_quads_backward);
+            }
+        }
+        log::debug!("Culling quads took {}us", cull_time.elapsed().as_micros());
+
+        // the vertex data itself
+        let mut mesh = [
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        ];
+
+        let mesh_time = Instant::now();
+        for i in 0..6 {
+            mesh[i] = greedy_merge(&mut data[i], i);
+        }
+        log::debug!("Merging quads took {}us", mesh_time.elapsed().as_micros());
+
+        mesh
+    }
+
+    /// Decodes the visible faces from the culling step. Faces between two of the
+    /// same see-through block are dropped here, as the masks can't tell them apart
+    /// from faces between different blocks.
+    fn add_faces(
+        hood: &ChunkNeighborhood,
+        data: &mut HashMap<LocalBlockPos, Block>,
+        x: usize,
+        y: usize,
+        axis: usize,
+        faces: ColumnMask,
+    ) {
+        let (dx, dy, dz) = FACE_OFFSETS[axis];
+
+        let mut faces = faces;
+
+        let mut z = 0;
+
+        while faces != 0 {
+            let leading = faces.leading_zeros() as ChunkDimTy; // why does this always return a u32?
+
+            // shifting the bits can cause an overflow if we're not careful
+            // about how we do it
+            faces <<= leading; // shift over the 0s
+            faces -= 1 << (CHUNK_SIZE - 1); // subtract the most significant bit
+            faces <<= 1; // shift it over now that it's 0
+
+            z += leading + 1;
+
+            let pos = LocalBlockPos(
+                x as ChunkDimTy,
+                y as ChunkDimTy,
+                CHUNK_SIZE as ChunkDimTy - z,
+            );
+            let block = hood.center().get_block(&pos);
+
+            if !block.is_opaque() {
+                let neighbour =
+                    hood.get_block(pos.0 as i32 + dx, pos.1 as i32 + dy, pos.2 as i32 + dz);
+                if neighbour == Some(block) {
+                    continue;
+                }
+            }
+
+            data.insert(pos, block);
+        }
+    }
+
+    /// Greedy mesh the quads,
+    /// note: this is not guaranteed to produce optimal meshes
+    /// THIS ALGORITHM HAS A BUG IN IT FFS
+    fn greedy_merge(hm: &mut HashMap<LocalBlockPos, Block>, axis: usize) -> Vec<EncodedVertex> {
+        // create output mesh data vec
+        let mut output = Vec::<EncodedVertex>::new();
+
+        let growth_axes = match axis as u32 % 3 {
+            0 => [LocalBlockPos(1, 0, 0), LocalBlockPos(0, 1, 0)], // xy-plane
+            1 => [LocalBlockPos(1, 0, 0), LocalBlockPos(0, 0, 1)], // xz-plane
+            2 => [LocalBlockPos(0, 1, 0), LocalBlockPos(0, 0, 1)], // yz-plane
+            _ => [LocalBlockPos(1, 1, 1), LocalBlockPos(1, 1, 1)],
+        };
+
+        while !hm.is_empty() {
+            // get an element
+            let (pos, block) = hm.iter().take(1).collect::<Vec<_>>()[0];
+            let pos = *pos; // we clone the values to avoid appease the borrow checker
+            let block = *block;
+
+            hm.remove(&pos);
+
+            let i = growth_axes[0];
+            let j = growth_axes[1];
+
+            let mut quad1 = pos;
+            let mut quad2 = pos;
+
+            // check block forward in the row
+            while let Some(b) = hm.get(&LocalBlockPos(quad2.0 + i.0, quad2.1 + i.1, quad2.2 + i.2))
+            {
+                if b == &block {
+                    hm.remove(&LocalBlockPos(quad2.0 + i.0, quad2.1 + i.1, quad2.2 + i.2));
+                    quad2 = LocalBlockPos(quad2.0 + i.0, quad2.1 + i.1, quad2.2 + i.2);
+                } else {
+                    break;
+                }
+            }
+
+            // check the blocks backward in the row
+            while let Some(t) = safe_sub(&quad1, &i) {
+                if let Some(b) = hm.get(&t) {
+                    if b == &block {
+                        hm.remove(&t);
+                        quad1 = t;
+                    } else {
+                        break;
+                    }
+                } else {
+                    break;
+                }
+            }
+
+            let column_length = 1
+                + (quad2.0 - quad1.0) * i.0
+                + (quad2.1 - quad1.1) * i.1
+                + (quad2.2 - quad1.2) * i.2;
+
+            // check column backward
+            let mut can_grow = true;
+            while can_grow {
+                let Some(t) = safe_sub(&quad1, &j) else {
+                    break;
+                };
+
+                let mut to_remove = vec![];
+                for l in 0..column_length {
+                    let a = LocalBlockPos(t.0 + l * i.0, t.1 + l * i.1, t.2 + l * i.2);
+                    let c = hm.get(&a);
+
+                    if let Some(b) = c {
+                        if b == &block {
+                            to_remove.push(a);
+                            continue;
+                        }
+                    }
+
+                    can_grow = false;
+                }
+
+                if can_grow {
+                    quad1 = t;
+                    for k in to_remove {
+                        hm.remove(&k);
+                    }
+                }
+            }
+
+            // check column forward
+            can_grow = true;
+            while can_grow {
+                let t = LocalBlockPos(quad2.0 + j.0, quad2.1 + j.1, quad2.2 + j.2);
+
+                let mut to_remove = vec![];
+                for l in 0..column_length {
+                    let Some(a) = safe_sub(&t, &LocalBlockPos(l * i.0, l * i.1, l * i.2)) else {
+                        can_grow = false;
+                        break;
+                    };
+
+                    if let Some(b) = hm.get(&a) {
+                        if b == &block {
+                            to_remove.push(a);
+                            continue;
+                        }
+                    }
+
+                    can_grow = false;
+                }
+
+                if can_grow {
+                    quad2 = t;
+                    for k in to_remove {
+                        hm.remove(&k);
+                    }
+                }
+            }
+            output.append(&mut create_quad(axis, quad1, quad2, block));
+        }
+
+        output
+    }
+
+    /// Encode the vertices of a quad, defined by two opposite corners.
+    fn create_quad(
+        axis: usize, // Axis along which the face is oriented: 0-5 for six cube faces
+        LocalBlockPos(c1x, c1y, c1z): LocalBlockPos,
+        LocalBlockPos(c2x, c2y, c2z): LocalBlockPos,
+        block: Block,
+    ) -> Vec<EncodedVertex> {
+        // Determine the min and max bounds of the corners
+
+        // the positions are 0-31 inclusive, whereas
+        // the vertices are 0-32 inclusive, so encoding them
+        // with 5 bits causes issues at the upper ends of the blocks
+        // we still need to add one to quad2,
+        let min_x = c1x.min(c2x + 1);
+        let max_x = c1x.max(c2x + 1);
+        let min_y = c1y.min(c2y + 1);
+        let max_y = c1y.max(c2y + 1);
+        let min_z = c1z.min(c2z + 1);
+        let max_z = c1z.max(c2z + 1);
+
+        // Generate vertices based on the axis
+        match axis {
+            2 => vec![
+                // +X face
+                encode_vertex(max_x, min_y, min_z, block),
+                encode_vertex(max_x, max_y, min_z, block),
+                encode_vertex(max_x, max_y, max_z, block),
+                encode_vertex(max_x, min_y, min_z, block),
+                encode_vertex(max_x, max_y, max_z, block),
+                encode_vertex(max_x, min_y, max_z, block),
+            ],
+            5 => vec![
+                // -X face
+                encode_vertex(min_x, min_y, min_z, block),
+                encode_vertex(min_x, max_y, min_z, block),
+                encode_vertex(min_x, max_y, max_z, block),
+                encode_vertex(min_x, min_y, min_z, block),
+                encode_vertex(min_x, max_y, max_z, block),
+                encode_vertex(min_x, min_y, max_z, block),
+            ],
+            1 => vec![
+                // +Y face
+                encode_vertex(min_x, max_y, min_z, block),
+                encode_vertex(max_x, max_y, min_z, block),
+                encode_vertex(max_x, max_y, max_z, block),
+                encode_vertex(min_x, max_y, min_z, block),
+                encode_vertex(max_x, max_y, max_z, block),
+                encode_vertex(min_x, max_y, max_z, block),
+            ],
+            4 => vec![
+                // -Y face
+                encode_vertex(min_x, min_y, min_z, block),
+                encode_vertex(max_x, min_y, min_z, block),
+                encode_vertex(max_x, min_y, max_z, block),
+                encode_vertex(min_x, min_y, min_z, block),
+                encode_vertex(max_x, min_y, max_z, block),
+                encode_vertex(min_x, min_y, max_z, block),
+            ],
+            3 => vec![
+                // +Z face
+                encode_vertex(min_x, min_y, max_z, block),
+                encode_vertex(max_x, min_y, max_z, block),
+                encode_vertex(max_x, max_y, max_z, block),
+                encode_vertex(min_x, min_y, max_z, block),
+                encode_vertex(max_x, max_y, max_z, block),
+                encode_vertex(min_x, max_y, max_z, block),
+            ],
+            0 => vec![
+                // -Z face
+                encode_vertex(min_x, min_y, min_z, block),
+                encode_vertex(max_x, min_y, min_z, block),
+                encode_vertex(max_x, max_y, min_z, block),
+                encode_vertex(min_x, min_y, min_z, block),
+                encode_vertex(max_x, max_y, min_z, block),
+                encode_vertex(min_x, max_y, min_z, block),
+            ],
+            _ => panic!("Invalid axis value: must be 0-5"),
+        }
+    }
+
+    /// Helper function to encode a vertex position and the block id into a single
+    /// value. The block id takes up the bits above the position, the block state
+    /// isn't encoded.
+    fn encode_vertex(x: ChunkDimTy, y: ChunkDimTy, z: ChunkDimTy, block: Block) -> EncodedVertex {
+        // anything bigger would spill into the next coordinate
+        debug_assert!(x <= CHUNK_SIZE && y <= CHUNK_SIZE && z <= CHUNK_SIZE);
+        debug_assert!(block.id() < MAX_BLOCK_ID);
+
+        let mut output = block.id();
+        output <<= NUM_BITS_IN_POS;
+        output |= x;
+        output <<= NUM_BITS_IN_POS;
+        output |= y;
+        output <<= NUM_BITS_IN_POS;
+        output |= z;
+
+        EncodedVertex(output)
+    }
+
+    fn safe_sub(p1: &LocalBlockPos, p2: &LocalBlockPos) -> Option<LocalBlockPos> {
+        let x = p1.0.checked_sub(p2.0);
+        let y = p1.1.checked_sub(p2.1);
+        let z = p1.2.checked_sub(p2.2);
+
+        if x.is_none() || y.is_none() || z.is_none() {
+            return None;
+        }
+
+        Some(LocalBlockPos(x.unwrap(), y.unwrap(), z.unwrap()))
+    }
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("chunk meshing overhead");
 
     let chunk = Chunk::random();
     group.bench_function("mesh random chunk", |b| b.iter(|| black_box(mesh(&chunk))));
+    group.bench_function("mesh random chunk (hashmap)", |b| {
+        b.iter(|| black_box(hashmap_mesher::mesh(&chunk)))
+    });
+
+    let chunk = terrain_chunk();
+    group.bench_function("mesh terrain chunk", |b| b.iter(|| black_box(mesh(&chunk))));
+    group.bench_function("mesh terrain chunk (hashmap)", |b| {
+        b.iter(|| black_box(hashmap_mesher::mesh(&chunk)))
+    });
 
     let chunk = Chunk::full();
     group.bench_function("mesh full chunk", |b| b.iter(|| black_box(mesh(&chunk))));
 
+    // a chunk with solid neighbours on every side only has to cull
+    let hood = Facing::ALL
+        .into_iter()
+        .fold(ChunkNeighborhood::new(&chunk), |hood, facing| {
+            hood.with_neighbor(facing, &chunk)
+        });
+    group.bench_function("mesh buried chunk", |b| {
+        b.iter(|| black_box(mesh_with_neighbors(&hood)))
+    });
+
     group.bench_function("build and mesh chunk", |b| b.iter(build_and_mesh_chunk));
 
     group.finish();
@@ -1,24 +1,244 @@
+use super::registry::{BlockDefinition, BlockRegistry};
+
 /// since this is a tuple struct, it has the same memory as just a u32,
 /// or so I believe
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+///
+/// The low bits are the type id, which is used to look up the block's
+/// properties in the global `BlockRegistry`. The bits above it hold per-block
+/// state, so two blocks of the same type can differ without needing new ids:
+///
+/// | bits  | field   |
+/// |-------|---------|
+/// | 0-11  | type id |
+/// | 12-14 | facing  |
+/// | 15-18 | variant |
+/// | 19-22 | level   |
+/// | 23-26 | age     |
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Block(pub u32);
 
-impl Block {
-    pub fn is_solid(&self) -> bool {
-        match self.0 {
-            1 => true,  // dirt
-            2 => true,  // stone
-            3 => false, // leaves
-            _ => false,
+const _: () = assert!(std::mem::size_of::<Block>() == 4);
+
+const FACING_SHIFT: u32 = Block::ID_BITS;
+const FACING_BITS: u32 = 3;
+const VARIANT_SHIFT: u32 = FACING_SHIFT + FACING_BITS;
+const VARIANT_BITS: u32 = 4;
+const LEVEL_SHIFT: u32 = VARIANT_SHIFT + VARIANT_BITS;
+const LEVEL_BITS: u32 = 4;
+const AGE_SHIFT: u32 = LEVEL_SHIFT + LEVEL_BITS;
+const AGE_BITS: u32 = 4;
+
+/// Direction a block is facing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Facing {
+    /// -Z
+    North = 1,
+    /// +Z
+    South = 2,
+    /// +X
+    East = 3,
+    /// -X
+    West = 4,
+    /// +Y
+    Up = 5,
+    /// -Y
+    Down = 6,
+}
+
+impl Facing {
+    pub const ALL: [Facing; 6] = [
+        Facing::North,
+        Facing::South,
+        Facing::East,
+        Facing::West,
+        Facing::Up,
+        Facing::Down,
+    ];
+
+    pub fn offset(&self) -> (i32, i32, i32) {
+        match self {
+            Facing::North => (0, 0, -1),
+            Facing::South => (0, 0, 1),
+            Facing::East => (1, 0, 0),
+            Facing::West => (-1, 0, 0),
+            Facing::Up => (0, 1, 0),
+            Facing::Down => (0, -1, 0),
         }
     }
 
-    pub fn get_uv(&self) -> (f32, f32) {
-        match self.0 {
-            1 => (0.0, 0.0),
-            2 => (0.125, 0.0),
-            3 => (0.25, 0.0),
-            _ => (0.0, 0.0),
+    /// The facing pointing along `offset`, if it is one of the six unit
+    /// offsets.
+    pub fn from_offset(offset: (i32, i32, i32)) -> Option<Self> {
+        Facing::ALL.into_iter().find(|f| f.offset() == offset)
+    }
+
+    pub fn opposite(&self) -> Self {
+        match self {
+            Facing::North => Facing::South,
+            Facing::South => Facing::North,
+            Facing::East => Facing::West,
+            Facing::West => Facing::East,
+            Facing::Up => Facing::Down,
+            Facing::Down => Facing::Up,
+        }
+    }
+
+    fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            1 => Some(Facing::North),
+            2 => Some(Facing::South),
+            3 => Some(Facing::East),
+            4 => Some(Facing::West),
+            5 => Some(Facing::Up),
+            6 => Some(Facing::Down),
+            _ => None,
         }
     }
 }
+
+impl Block {
+    /// Number of bits used for the type id.
+    pub const ID_BITS: u32 = 12;
+    pub const MAX_VARIANT: u8 = (1 << VARIANT_BITS) - 1;
+    pub const MAX_LEVEL: u8 = (1 << LEVEL_BITS) - 1;
+    pub const MAX_AGE: u8 = (1 << AGE_BITS) - 1;
+
+    /// A block of the type with no state set.
+    pub fn new(id: u32) -> Self {
+        debug_assert!(id < 1 << Self::ID_BITS, "block id {} is too large", id);
+        Block(id & ((1 << Self::ID_BITS) - 1))
+    }
+
+    /// The type id, without any state.
+    pub fn id(&self) -> u32 {
+        self.get_bits(0, Self::ID_BITS)
+    }
+
+    /// Whether both blocks are the same type, regardless of state.
+    pub fn same_type(&self, other: &Block) -> bool {
+        self.id() == other.id()
+    }
+
+    pub fn facing(&self) -> Option<Facing> {
+        Facing::from_bits(self.get_bits(FACING_SHIFT, FACING_BITS))
+    }
+
+    pub fn with_facing(self, facing: Option<Facing>) -> Self {
+        self.with_bits(FACING_SHIFT, FACING_BITS, facing.map_or(0, |f| f as u32))
+    }
+
+    pub fn variant(&self) -> u8 {
+        self.get_bits(VARIANT_SHIFT, VARIANT_BITS) as u8
+    }
+
+    pub fn with_variant(self, variant: u8) -> Self {
+        debug_assert!(variant <= Self::MAX_VARIANT);
+        self.with_bits(VARIANT_SHIFT, VARIANT_BITS, variant as u32)
+    }
+
+    /// Fill level, e.g. how full a fluid cell is.
+    pub fn level(&self) -> u8 {
+        self.get_bits(LEVEL_SHIFT, LEVEL_BITS) as u8
+    }
+
+    pub fn with_level(self, level: u8) -> Self {
+        debug_assert!(level <= Self::MAX_LEVEL);
+        self.with_bits(LEVEL_SHIFT, LEVEL_BITS, level as u32)
+    }
+
+    pub fn age(&self) -> u8 {
+        self.get_bits(AGE_SHIFT, AGE_BITS) as u8
+    }
+
+    pub fn with_age(self, age: u8) -> Self {
+        debug_assert!(age <= Self::MAX_AGE);
+        self.with_bits(AGE_SHIFT, AGE_BITS, age as u32)
+    }
+
+    pub fn definition(&self) -> Option<&'static BlockDefinition> {
+        BlockRegistry::global().get(*self)
+    }
+
+    /// Whether the block hides the faces behind it, and blocks sight for the
+    /// visibility graph.
+    pub fn is_opaque(&self) -> bool {
+        BlockRegistry::global().is_opaque(*self)
+    }
+
+    /// Whether the block has any faces to draw.
+    pub fn is_visible(&self) -> bool {
+        BlockRegistry::global().is_visible(*self)
+    }
+
+    /// Whether things can't pass through the block.
+    pub fn is_collidable(&self) -> bool {
+        BlockRegistry::global().is_collidable(*self)
+    }
+
+    pub fn get_uv(&self) -> (f32, f32) {
+        BlockRegistry::global().uv(*self)
+    }
+
+    fn get_bits(&self, shift: u32, bits: u32) -> u32 {
+        (self.0 >> shift) & ((1 << bits) - 1)
+    }
+
+    fn with_bits(self, shift: u32, bits: u32, value: u32) -> Self {
+        let mask = ((1 << bits) - 1) << shift;
+        Block((self.0 & !mask) | ((value << shift) & mask))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_is_separate_from_id() {
+        let water = Block::new(7)
+            .with_level(8)
+            .with_facing(Some(Facing::East))
+            .with_variant(3)
+            .with_age(Block::MAX_AGE);
+
+        assert_eq!(water.id(), 7);
+        assert_eq!(water.level(), 8);
+        assert_eq!(water.facing(), Some(Facing::East));
+        assert_eq!(water.variant(), 3);
+        assert_eq!(water.age(), Block::MAX_AGE);
+
+        assert!(water.same_type(&Block::new(7)));
+        assert_ne!(water, Block::new(7));
+        assert_ne!(water, water.with_level(Block::MAX_LEVEL));
+    }
+
+    #[test]
+    fn setting_state_only_changes_its_field() {
+        let block = Block::new(Block::MAX_AGE as u32)
+            .with_level(Block::MAX_LEVEL)
+            .with_variant(Block::MAX_VARIANT);
+
+        let block = block.with_level(0).with_facing(Some(Facing::Down));
+        assert_eq!(block.level(), 0);
+        assert_eq!(block.variant(), Block::MAX_VARIANT);
+        assert_eq!(block.facing(), Some(Facing::Down));
+        assert_eq!(block.age(), 0);
+
+        assert_eq!(block.with_facing(None).facing(), None);
+    }
+
+    #[test]
+    fn stateless_blocks_match_their_id() {
+        assert_eq!(Block::new(3), Block(3));
+        assert_eq!(Block(3).facing(), None);
+        assert_eq!(Block(3).level(), 0);
+    }
+
+    #[test]
+    fn properties_ignore_state() {
+        let dirt = Block::new(1).with_variant(2).with_facing(Some(Facing::Up));
+
+        assert!(dirt.is_opaque() && dirt.is_collidable() && dirt.is_visible());
+        assert_eq!(dirt.definition().map(|d| d.name.as_str()), Some("dirt"));
+    }
+}
@@ -1,13 +1,43 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, path::Path};
 
-use crate::{chunk::ChunkPos, player::Player, window_state::WindowState};
+use crate::{
+    chunk::{registry::BlockRegistry, ChunkPos},
+    player::Player,
+    window_state::WindowState,
+    world::{
+        generator::{ChunkGenerator, RandomGenerator},
+        meta::{WorldError, WorldMeta},
+        region::{RegionError, RegionStorage},
+        template::{Template, Transform},
+        World, WorldBlockPos,
+    },
+};
 
-use super::{pool::ChunkPool, Chunk};
+use super::{
+    block::{Block, Facing},
+    pool::ChunkPool,
+    Chunk,
+};
 
-#[derive(Default)]
 pub struct ChunkManager {
     pool: ChunkPool,
-    loaded_chunks: HashSet<ChunkPos>,
+    world: World,
+    /// Where changed chunks are saved, nothing is saved if there's no world
+    /// directory.
+    storage: Option<RegionStorage>,
+    /// Makes the chunks that haven't been saved.
+    generator: Box<dyn ChunkGenerator>,
+}
+
+impl Default for ChunkManager {
+    fn default() -> Self {
+        Self {
+            pool: ChunkPool::default(),
+            world: World::default(),
+            storage: None,
+            generator: Box::new(RandomGenerator::default()),
+        }
+    }
 }
 
 impl ChunkManager {
@@ -15,9 +45,41 @@ impl ChunkManager {
         self.pool = ChunkPool::initialize(state);
     }
 
+    /// Saves changed chunks to `dir` and loads chunks from it, instead of
+    /// generating them, if they were saved before. Worlds saved by older
+    /// versions are upgraded, and a saved seed replaces the generator's.
+    /// Set the generator first, so new worlds record the right one.
+    pub fn open_world(&mut self, dir: impl AsRef<Path>) -> Result<(), WorldError> {
+        let dir = dir.as_ref();
+        let registry = BlockRegistry::global();
+        let meta = WorldMeta::open(dir, self.generator.as_ref(), registry)?;
+
+        if meta.generator != self.generator.name() {
+            log::warn!(
+                "World was made by the {} generator, new chunks come from {}",
+                meta.generator,
+                self.generator.name()
+            );
+        }
+        if meta.registry != registry.to_text() {
+            log::warn!("Block definitions have changed since the world was saved");
+        }
+
+        self.generator.set_seed(meta.seed);
+        self.storage = Some(RegionStorage::new(dir)?);
+        Ok(())
+    }
+
+    /// Generates new chunks with `generator`. Chunks that are already loaded
+    /// or saved are kept as they are.
+    pub fn set_generator(&mut self, generator: impl ChunkGenerator + 'static) {
+        log::info!("Generating chunks with {}", generator.name());
+        self.generator = Box::new(generator);
+    }
+
     /// Recalculates the chunks that need to be loaded, and loads them.
     pub fn load_chunks(&mut self, state: &WindowState, player: &Player) {
-        let mut chunks_to_remove: HashSet<_> = self.loaded_chunks.iter().cloned().collect();
+        let mut chunks_to_remove: HashSet<_> = self.world.chunk_positions().cloned().collect();
         let mut chunks_to_add = Vec::<ChunkPos>::new();
 
         let pos = player.get_chunk_pos();
@@ -28,7 +90,7 @@ impl ChunkManager {
                 for z in (pos.0 - r)..=(pos.0 + r) {
                     let new_pos = ChunkPos(x, y, z);
 
-                    if !self.loaded_chunks.contains(&new_pos) {
+                    if !self.world.contains_chunk(&new_pos) {
                         chunks_to_add.push(new_pos);
                     }
 
@@ -37,24 +99,425 @@ impl ChunkManager {
             }
         }
 
-        // remove the chunks and add their memory address to the free list
+        // remove the chunks and add their memory address to the free list.
+        // Chunks that couldn't be saved stay loaded, so the next call retries
+        let mut unloaded = Vec::new();
         for chunk_pos in chunks_to_remove {
-            self.pool.remove_chunk(chunk_pos);
+            match self.unload_chunk(chunk_pos) {
+                Ok(()) => unloaded.push(chunk_pos),
+                Err(e) => log::error!(
+                    "Couldn't save chunk {:?}, keeping it loaded: {}",
+                    chunk_pos,
+                    e
+                ),
+            }
         }
 
-        for chunk_pos in chunks_to_add {
-            self.pool.add_chunk(state, chunk_pos, Chunk::random());
-            self.loaded_chunks.insert(chunk_pos);
+        // every new chunk is in the world before meshing, so they can cull
+        // the faces between each other
+        for &chunk_pos in &chunks_to_add {
+            let chunk = self.saved_or_generated(chunk_pos);
+            self.world.insert_chunk(chunk_pos, chunk);
+        }
+        for chunk_pos in self.chunks_to_remesh(chunks_to_add, unloaded) {
+            self.remesh(state, chunk_pos);
         }
 
         let [x, y] = self.pool.allocated_percent();
         log::info!("Chunk manager statistics ----");
-        log::info!("Number of loaded chunks: {}", self.loaded_chunks.len());
+        log::info!("Number of loaded chunks: {}", self.world.len());
+        log::info!(
+            "Chunk memory usage: {:.2}MiB",
+            self.world.memory_usage() as f32 / 2u32.pow(20) as f32
+        );
         log::info!("Vertex buffer usage: {:.2}%", 100.0 * x);
         log::info!("Storage buffer usage: {:.2}%", 100.0 * y)
     }
 
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    /// Sets a block and remeshes its chunk, and the neighbouring chunks if
+    /// the block is on their border. The chunk is saved when it is unloaded.
+    pub fn set_block(&mut self, state: &WindowState, pos: WorldBlockPos, b: Block) {
+        if self.edit_block(pos, b) {
+            self.remesh_with_neighbors(state, [pos.chunk_pos()]);
+            return;
+        }
+
+        self.remesh(state, pos.chunk_pos());
+        for chunk_pos in bordering_chunks(pos) {
+            self.remesh(state, chunk_pos);
+        }
+    }
+
+    /// Pastes a template with its minimum corner at `origin`, and remeshes
+    /// the chunks it changed. The chunks it covers are loaded first, so
+    /// pasting outside the loaded area doesn't replace them with empty ones.
+    pub fn paste_template(
+        &mut self,
+        state: &WindowState,
+        template: &Template,
+        origin: WorldBlockPos,
+        transform: Transform,
+    ) {
+        let (min, max) = template.bounds(origin, transform);
+        if max.0 <= min.0 || max.1 <= min.1 || max.2 <= min.2 {
+            return;
+        }
+
+        let first = min.chunk_pos();
+        let last = WorldBlockPos(max.0 - 1, max.1 - 1, max.2 - 1).chunk_pos();
+        let mut loaded = Vec::new();
+        for x in first.0..=last.0 {
+            for y in first.1..=last.1 {
+                for z in first.2..=last.2 {
+                    let chunk_pos = ChunkPos(x, y, z);
+                    if self.ensure_loaded(chunk_pos) {
+                        loaded.push(chunk_pos);
+                    }
+                }
+            }
+        }
+
+        let mut changed = template.paste(&mut self.world, origin, transform);
+        log::debug!(
+            "Pasted template {} into {} chunks",
+            template.name(),
+            changed.len()
+        );
+
+        // newly loaded chunks need meshing even if the template didn't change
+        // them
+        changed.extend(loaded);
+        self.remesh_with_neighbors(state, changed);
+    }
+
+    /// Saves every changed chunk, e.g. before exiting. Chunks that couldn't
+    /// be saved stay dirty.
+    pub fn save_dirty(&mut self) {
+        let dirty: Vec<_> = self.world.dirty_chunks().cloned().collect();
+        for chunk_pos in dirty {
+            if let Err(e) = self.save_chunk(chunk_pos) {
+                log::error!("Couldn't save chunk {:?}: {}", chunk_pos, e);
+            }
+        }
+    }
+
+    /// Sets a block, loading its chunk first so editing outside the loaded
+    /// area doesn't replace the chunk with an empty one. Returns whether the
+    /// chunk had to be loaded.
+    fn edit_block(&mut self, pos: WorldBlockPos, b: Block) -> bool {
+        let loaded = self.ensure_loaded(pos.chunk_pos());
+        self.world.set_block(pos, b);
+        loaded
+    }
+
+    /// Loads the chunk from the world directory or the generator if it isn't
+    /// loaded yet. Returns whether it had to be loaded.
+    fn ensure_loaded(&mut self, chunk_pos: ChunkPos) -> bool {
+        if self.world.contains_chunk(&chunk_pos) {
+            return false;
+        }
+        let chunk = self.saved_or_generated(chunk_pos);
+        self.world.insert_chunk(chunk_pos, chunk);
+        true
+    }
+
+    /// Rebuilds the mesh of a loaded chunk.
+    fn remesh(&mut self, state: &WindowState, chunk_pos: ChunkPos) {
+        let Some(hood) = self.world.neighborhood(&chunk_pos) else {
+            return;
+        };
+        self.pool.remove_chunk(chunk_pos);
+        self.pool.add_chunk(state, chunk_pos, &hood);
+    }
+
+    /// Rebuilds the meshes of `chunks`, and of the loaded chunks next to them
+    /// whose borders they can hide or uncover.
+    fn remesh_with_neighbors(
+        &mut self,
+        state: &WindowState,
+        chunks: impl IntoIterator<Item = ChunkPos>,
+    ) {
+        for chunk_pos in self.with_loaded_neighbors(chunks) {
+            self.remesh(state, chunk_pos);
+        }
+    }
+
+    /// `chunks` together with the loaded chunks sharing a face with them.
+    fn with_loaded_neighbors(
+        &self,
+        chunks: impl IntoIterator<Item = ChunkPos>,
+    ) -> HashSet<ChunkPos> {
+        let mut all = HashSet::new();
+        for chunk_pos in chunks {
+            all.insert(chunk_pos);
+            all.extend(Facing::ALL.into_iter().filter_map(|facing| {
+                let (dx, dy, dz) = facing.offset();
+                let neighbor = ChunkPos(chunk_pos.0 + dx, chunk_pos.1 + dy, chunk_pos.2 + dz);
+                self.world.contains_chunk(&neighbor).then_some(neighbor)
+            }));
+        }
+        all
+    }
+
+    /// The loaded chunks whose meshes change when `added` are loaded and
+    /// `unloaded` are gone: the added chunks and their neighbours, and the
+    /// neighbours of the unloaded chunks, whose faces on that side are no
+    /// longer hidden.
+    fn chunks_to_remesh(
+        &self,
+        added: impl IntoIterator<Item = ChunkPos>,
+        unloaded: impl IntoIterator<Item = ChunkPos>,
+    ) -> HashSet<ChunkPos> {
+        let mut chunks = self.with_loaded_neighbors(added);
+        chunks.extend(
+            self.with_loaded_neighbors(unloaded)
+                .into_iter()
+                .filter(|pos| self.world.contains_chunk(pos)),
+        );
+        chunks
+    }
+
+    /// Saves the chunk and removes it. If it can't be saved it is kept loaded
+    /// and dirty, so its changes aren't lost.
+    fn unload_chunk(&mut self, chunk_pos: ChunkPos) -> Result<(), RegionError> {
+        self.save_chunk(chunk_pos)?;
+        self.pool.remove_chunk(chunk_pos);
+        self.world.remove_chunk(&chunk_pos);
+        Ok(())
+    }
+
+    /// Writes the chunk to the world directory if it has changed.
+    fn save_chunk(&mut self, chunk_pos: ChunkPos) -> Result<(), RegionError> {
+        let Some(storage) = &mut self.storage else {
+            return Ok(());
+        };
+        if !self.world.is_dirty(&chunk_pos) {
+            return Ok(());
+        }
+        let Some(chunk) = self.world.get_chunk(&chunk_pos) else {
+            return Ok(());
+        };
+
+        storage.save_chunk(&chunk_pos, chunk)?;
+        self.world.mark_clean(&chunk_pos);
+        Ok(())
+    }
+
+    /// The saved version of the chunk if there is one, otherwise a newly
+    /// generated one.
+    fn saved_or_generated(&mut self, chunk_pos: ChunkPos) -> Chunk {
+        if let Some(storage) = &mut self.storage {
+            match storage.load_chunk(&chunk_pos) {
+                Ok(Some(chunk)) => return chunk,
+                Ok(None) => {}
+                Err(e) => {
+                    log::error!("Couldn't load chunk {:?}, regenerating: {}", chunk_pos, e);
+
+                    // keep the damaged data around, but out of the way
+                    if let RegionError::CorruptChunk { .. } = e {
+                        match storage.quarantine_chunk(&chunk_pos) {
+                            Ok(Some(path)) => log::warn!(
+                                "Moved damaged chunk {:?} to {}",
+                                chunk_pos,
+                                path.display()
+                            ),
+                            Ok(None) => {}
+                            Err(e) => {
+                                log::error!("Couldn't quarantine chunk {:?}: {}", chunk_pos, e)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        self.generator.generate(chunk_pos)
+    }
+
     pub fn render(&self, state: &WindowState, player: &Player) {
         self.pool.render(state, player, ());
     }
 }
+
+/// The chunks other than its own that a block shares a face with, i.e. the
+/// neighbours whose mesh can change when it does.
+fn bordering_chunks(pos: WorldBlockPos) -> impl Iterator<Item = ChunkPos> {
+    let own = pos.chunk_pos();
+    Facing::ALL.into_iter().filter_map(move |facing| {
+        let (dx, dy, dz) = facing.offset();
+        let chunk_pos = WorldBlockPos(pos.0 + dx, pos.1 + dy, pos.2 + dz).chunk_pos();
+        (chunk_pos != own).then_some(chunk_pos)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{LocalBlockPos, CHUNK_SIZE};
+
+    use super::*;
+
+    #[test]
+    fn blocks_on_borders_touch_the_neighboring_chunks() {
+        let last = CHUNK_SIZE as i32 - 1;
+
+        assert_eq!(bordering_chunks(WorldBlockPos(3, 4, 5)).count(), 0);
+        assert_eq!(
+            bordering_chunks(WorldBlockPos(0, 4, last)).collect::<Vec<_>>(),
+            [ChunkPos(0, 0, 1), ChunkPos(-1, 0, 0)]
+        );
+
+        // corners touch three chunks, but not the diagonal ones
+        let corner: HashSet<_> = bordering_chunks(WorldBlockPos(-1, -1, -1)).collect();
+        assert_eq!(
+            corner,
+            HashSet::from([
+                ChunkPos(0, -1, -1),
+                ChunkPos(-1, 0, -1),
+                ChunkPos(-1, -1, 0)
+            ])
+        );
+    }
+
+    #[test]
+    fn loaded_neighbors_are_remeshed_too() {
+        let mut manager = ChunkManager::default();
+        for pos in [ChunkPos(0, 0, 0), ChunkPos(1, 0, 0), ChunkPos(0, 2, 0)] {
+            manager.world.insert_chunk(pos, Chunk::default());
+        }
+
+        assert_eq!(
+            manager.with_loaded_neighbors([ChunkPos(0, 1, 0)]),
+            HashSet::from([ChunkPos(0, 1, 0), ChunkPos(0, 0, 0), ChunkPos(0, 2, 0)])
+        );
+        assert_eq!(
+            manager.with_loaded_neighbors([ChunkPos(0, 0, 0), ChunkPos(5, 5, 5)]),
+            HashSet::from([ChunkPos(0, 0, 0), ChunkPos(1, 0, 0), ChunkPos(5, 5, 5)])
+        );
+    }
+
+    #[test]
+    fn neighbors_of_unloaded_chunks_are_remeshed() {
+        let mut manager = ChunkManager::default();
+        for pos in [ChunkPos(0, 0, 0), ChunkPos(0, 1, 0), ChunkPos(1, 1, 0)] {
+            manager.world.insert_chunk(pos, Chunk::default());
+        }
+        manager.unload_chunk(ChunkPos(0, 1, 0)).unwrap();
+
+        // the unloaded chunk itself has no mesh to rebuild
+        assert_eq!(
+            manager.chunks_to_remesh([], [ChunkPos(0, 1, 0)]),
+            HashSet::from([ChunkPos(0, 0, 0), ChunkPos(1, 1, 0)])
+        );
+        assert_eq!(
+            manager.chunks_to_remesh([ChunkPos(1, 0, 0)], [ChunkPos(0, 1, 0)]),
+            HashSet::from([ChunkPos(1, 0, 0), ChunkPos(0, 0, 0), ChunkPos(1, 1, 0)])
+        );
+    }
+
+    #[test]
+    fn dirty_chunks_come_back_after_unloading() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = ChunkManager::default();
+        manager.open_world(dir.path()).unwrap();
+
+        let changed = ChunkPos(0, 0, 0);
+        let untouched = ChunkPos(1, 0, 0);
+        manager.world.insert_chunk(changed, Chunk::default());
+        manager.world.insert_chunk(untouched, Chunk::default());
+        manager.world.set_block(WorldBlockPos(3, 4, 5), Block(2));
+
+        manager.unload_chunk(changed).unwrap();
+        manager.unload_chunk(untouched).unwrap();
+        assert!(manager.world.is_empty());
+
+        let chunk = manager.saved_or_generated(changed);
+        assert_eq!(chunk.get_block(&LocalBlockPos(3, 4, 5)), Block(2));
+        assert_eq!(chunk.iter_non_air().count(), 1);
+
+        // clean chunks aren't saved, so they are generated again
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+        assert!(storage.load_chunk(&untouched).unwrap().is_none());
+    }
+
+    #[test]
+    fn editing_unloaded_chunks_keeps_their_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = ChunkManager::default();
+        manager.open_world(dir.path()).unwrap();
+
+        let chunk_pos = ChunkPos(0, 0, 0);
+        manager.world.insert_chunk(chunk_pos, Chunk::default());
+        manager.world.set_block(WorldBlockPos(3, 4, 5), Block(2));
+        manager.unload_chunk(chunk_pos).unwrap();
+
+        assert!(manager.edit_block(WorldBlockPos(6, 7, 8), Block(3)));
+        assert!(!manager.edit_block(WorldBlockPos(6, 7, 9), Block(3)));
+        manager.save_dirty();
+        manager.unload_chunk(chunk_pos).unwrap();
+
+        let chunk = manager.saved_or_generated(chunk_pos);
+        assert_eq!(chunk.get_block(&LocalBlockPos(3, 4, 5)), Block(2));
+        assert_eq!(chunk.get_block(&LocalBlockPos(6, 7, 8)), Block(3));
+        assert_eq!(chunk.iter_non_air().count(), 3);
+    }
+
+    #[test]
+    fn chunks_that_cant_be_saved_stay_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let world_dir = dir.path().join("world");
+        let mut manager = ChunkManager::default();
+        manager.open_world(&world_dir).unwrap();
+
+        let pos = ChunkPos(0, 0, 0);
+        manager.world.insert_chunk(pos, Chunk::default());
+        manager.world.set_block(WorldBlockPos(3, 4, 5), Block(2));
+
+        // a file in place of the world directory can't hold region files
+        std::fs::remove_dir_all(&world_dir).unwrap();
+        std::fs::write(&world_dir, b"").unwrap();
+
+        assert!(manager.unload_chunk(pos).is_err());
+        manager.save_dirty();
+        assert!(manager.world.is_dirty(&pos));
+        assert_eq!(manager.world.get_block(&WorldBlockPos(3, 4, 5)), Block(2));
+
+        // once the directory is back the changes can still be saved
+        std::fs::remove_file(&world_dir).unwrap();
+        std::fs::create_dir(&world_dir).unwrap();
+        manager.unload_chunk(pos).unwrap();
+        assert!(!manager.world.contains_chunk(&pos));
+        let chunk = manager.saved_or_generated(pos);
+        assert_eq!(chunk.get_block(&LocalBlockPos(3, 4, 5)), Block(2));
+    }
+
+    #[test]
+    fn damaged_chunks_are_quarantined_and_generated_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = ChunkManager::default();
+        manager.open_world(dir.path()).unwrap();
+
+        let pos = ChunkPos(0, 0, 0);
+        manager.world.insert_chunk(pos, Chunk::default());
+        manager.world.set_block(WorldBlockPos(3, 4, 5), Block(2));
+        manager.unload_chunk(pos).unwrap();
+
+        // cut the chunk off, as if saving it was interrupted
+        let path = dir.path().join("r.0.0.0.vvr");
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 1).unwrap();
+
+        let chunk = manager.saved_or_generated(pos);
+        assert_ne!(chunk.get_block(&LocalBlockPos(3, 4, 5)), Block(2));
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("quarantine"))
+                .unwrap()
+                .count(),
+            1
+        );
+        assert!(crate::world::region::verify(dir.path()).unwrap().is_ok());
+    }
+}
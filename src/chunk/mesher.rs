@@ -1,259 +1,279 @@
-use std::{collections::HashMap, time::Instant};
-
-use crate::chunk::{block::Block, ChunkDimTy, LocalBlockPos, CHUNK_SIZE};
-
-use super::{Chunk, EncodedVertex, NUM_BITS_IN_POS};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
+
+use crate::chunk::{
+    block::{Block, Facing},
+    neighborhood::ChunkNeighborhood,
+    ChunkDimTy, ColumnMask, LocalBlockPos, CHUNK_SIZE,
+};
+
+use super::{Chunk, EncodedVertex, MAX_BLOCK_ID, NUM_BITS_IN_POS};
+
+/// Offset to the neighbouring block in the direction of each face.
+const FACE_OFFSETS: [(i32, i32, i32); 6] = [
+    (0, 0, -1),
+    (0, 1, 0),
+    (1, 0, 0),
+    (0, 0, 1),
+    (0, -1, 0),
+    (-1, 0, 0),
+];
 
 /// Returns the mesh of the chunk. The resulting chunk is split by the direction
-/// of the faces.
-/// The greedy face merging is a fairly naive implmenetation and doesn't use
-/// binary operations on the face mask. Doesn't seem like it will be a
-/// bottleneck yet, but it can always be changed.
+/// of the faces. Everything outside the chunk is treated as air, see
+/// `mesh_with_neighbors` to cull the faces on its borders.
+/// Faces are culled and merged with binary operations on the column masks, a
+/// slice and block type at a time.
 pub fn mesh(chunk: &Chunk) -> [Vec<EncodedVertex>; 6] {
-    let cull_time = Instant::now();
-
-    // first we want create a binary representation of only the solid blocks,
-    // so we can cull the non-visible faces that don't touch air
-    let mut t = [[ChunkDimTy::default(); CHUNK_SIZE as usize]; CHUNK_SIZE as usize];
+    mesh_with_neighbors(&ChunkNeighborhood::new(chunk))
+}
 
-    for (LocalBlockPos(x, y, z), block) in chunk.data.iter() {
-        if block.is_solid() {
-            t[*x as usize][*y as usize] |= 1 << z;
+/// Returns the mesh of the center chunk of `hood`. Faces on the border of the
+/// chunk are culled against the neighbouring chunks, neighbours that aren't
+/// loaded count as air.
+pub fn mesh_with_neighbors(hood: &ChunkNeighborhood) -> [Vec<EncodedVertex>; 6] {
+    // chunks made of a single block don't need to be culled or merged, unless
+    // a neighbour can hide part of their outside
+    if let Some(block) = hood.center().uniform_block() {
+        let isolated = Facing::ALL.iter().all(|f| hood.neighbor(*f).is_none());
+        if isolated || !block.is_visible() {
+            return mesh_uniform(block);
         }
     }
 
-    // for each axis (direction), we want to create a map of the faces,
-    // ! with the block type !
-    // also hashmaps don't allocate until the first insert, so allocating them
-    // is fine
-    let mut data = [
-        HashMap::<LocalBlockPos, Block>::new(),
-        HashMap::<LocalBlockPos, Block>::new(),
-        HashMap::<LocalBlockPos, Block>::new(),
-        HashMap::<LocalBlockPos, Block>::new(),
-        HashMap::<LocalBlockPos, Block>::new(),
-        HashMap::<LocalBlockPos, Block>::new(),
-    ];
-
-    for x in 0..CHUNK_SIZE {
-        for y in 0..CHUNK_SIZE {
-            // the next next_row and previous_row default to zero to make the
-            // faces show on the edges, if we add padding, just disregard them
-            let x = x as usize;
-            let y = y as usize;
-            // cull z faces
-            let z_quads_forward = t[x][y] & !(t[x][y] << 1);
-            add_faces(chunk, &mut data[0], x, y, z_quads_forward);
-
-            let z_quads_backward = t[x][y] & !(t[x][y] >> 1);
-            add_faces(chunk, &mut data[3], x, y, z_quads_backward);
-
-            // cull y faces
-            let next_row = if y + 1 >= CHUNK_SIZE as usize {
-                0
-            } else {
-                t[x][y + 1]
-            };
-            let y_quads_forward = t[x][y] & !next_row;
-            add_faces(chunk, &mut data[1], x, y, y_quads_forward);
+    mesh_culled(hood)
+}
+
+/// Mesh of a chunk filled with a single block. Faces between two of the same
+/// block are always hidden, so only the outside of the chunk is left.
+fn mesh_uniform(block: Block) -> [Vec<EncodedVertex>; 6] {
+    if !block.is_visible() {
+        return Default::default();
+    }
+
+    let min = LocalBlockPos(0, 0, 0);
+    let max = LocalBlockPos(CHUNK_SIZE - 1, CHUNK_SIZE - 1, CHUNK_SIZE - 1);
+    std::array::from_fn(|axis| create_quad(axis, min, max, block))
+}
+
+fn mesh_culled(hood: &ChunkNeighborhood) -> [Vec<EncodedVertex>; 6] {
+    let cull_time = Instant::now();
+    let chunk = hood.center();
+
+    // the chunk keeps a binary representation of the opaque and the visible
+    // blocks, so we can cull the faces that are hidden by an opaque neighbour
+    let o = |x: usize, y: usize| chunk.opaque_column(x as ChunkDimTy, y as ChunkDimTy);
+    let v = |x: usize, y: usize| chunk.visible_column(x as ChunkDimTy, y as ChunkDimTy);
+
+    // the same for the columns of the neighbouring chunks, which pad the
+    // masks on the borders. Unloaded neighbours are all air
+    let last = CHUNK_SIZE as usize - 1;
+    let n = |facing: Facing, x: usize, y: usize| {
+        hood.neighbor(facing).map_or(0, |chunk| {
+            chunk.opaque_column(x as ChunkDimTy, y as ChunkDimTy)
+        })
+    };
 
-            let previous_row = if y as i32 - 1 < 0 { 0 } else { t[x][y - 1] };
-            let y_quads_backward = t[x][y] & !previous_row;
-            add_faces(chunk, &mut data[4], x, y, y_quads_backward);
+    // for each axis (direction), we want to sort the faces into bit planes,
+    // ! by block type !
+    let mut planes: [FacePlanes; 6] = std::array::from_fn(FacePlanes::new);
 
-            // cull x faces
-            let next_row = if x + 1 >= CHUNK_SIZE as usize {
-                0
+    for x in 0..CHUNK_SIZE as usize {
+        for y in 0..CHUNK_SIZE as usize {
+            // the rows past the edges come from the neighbouring chunks
+            let next_y = if y == last {
+                n(Facing::Up, x, 0)
+            } else {
+                o(x, y + 1)
+            };
+            let previous_y = if y == 0 {
+                n(Facing::Down, x, last)
             } else {
-                t[x + 1][y]
+                o(x, y - 1)
             };
-            let x_quads_forward = t[x][y] & !next_row;
-            add_faces(chunk, &mut data[2], x, y, x_quads_forward);
+            let next_x = if x == last {
+                n(Facing::East, 0, y)
+            } else {
+                o(x + 1, y)
+            };
+            let previous_x = if x == 0 {
+                n(Facing::West, last, y)
+            } else {
+                o(x - 1, y)
+            };
+            // z faces shift in the last block of the neighbouring columns
+            let previous_z = n(Facing::North, x, y) >> last;
+            let next_z = (n(Facing::South, x, y) & 1) << last;
+
+            let faces = [
+                v(x, y) & !((o(x, y) << 1) | previous_z),
+                v(x, y) & !next_y,
+                v(x, y) & !next_x,
+                v(x, y) & !((o(x, y) >> 1) | next_z),
+                v(x, y) & !previous_y,
+                v(x, y) & !previous_x,
+            ];
+            if faces.iter().all(|f| *f == 0) {
+                continue;
+            }
 
-            let previous_row = if x as i32 - 1 < 0 { 0 } else { t[x - 1][y] };
-            let x_quads_backward = t[x][y] & !previous_row;
-            add_faces(chunk, &mut data[5], x, y, x_quads_backward);
+            let blocks = chunk.column(x as ChunkDimTy, y as ChunkDimTy);
+            for (axis, faces) in faces.into_iter().enumerate() {
+                add_faces(hood, &mut planes[axis], &blocks, x, y, faces);
+            }
         }
     }
     log::debug!("Culling quads took {}us", cull_time.elapsed().as_micros());
 
-    // the vertex data itself
-    let mut mesh = [
-        Vec::new(),
-        Vec::new(),
-        Vec::new(),
-        Vec::new(),
-        Vec::new(),
-        Vec::new(),
-    ];
-
     let mesh_time = Instant::now();
-    for i in 0..6 {
-        mesh[i] = greedy_merge(&mut data[i], i);
-    }
+    let mesh = planes.map(FacePlanes::merge);
     log::debug!("Merging quads took {}us", mesh_time.elapsed().as_micros());
 
     mesh
 }
 
-/// Decodes the visible faces from the culling step
+/// Sorts the visible faces of a column from the culling step into the planes
+/// of their block. Faces between two of the same see-through block are
+/// dropped here, as the masks can't tell them apart from faces between
+/// different blocks.
 fn add_faces(
-    chunk: &Chunk,
-    data: &mut HashMap<LocalBlockPos, Block>,
+    hood: &ChunkNeighborhood,
+    planes: &mut FacePlanes,
+    blocks: &[Block; CHUNK_SIZE as usize],
     x: usize,
     y: usize,
-    faces: ChunkDimTy,
+    faces: ColumnMask,
 ) {
-    let mut faces = faces;
-
-    let mut z = 0;
+    let (dx, dy, dz) = FACE_OFFSETS[planes.axis];
 
+    let mut faces = faces;
     while faces != 0 {
-        let leading = faces.leading_zeros() as ChunkDimTy; // why does this always return a u32?
-
-        // shifting the bits can cause an overflow if we're not careful
-        // about how we do it
-        faces <<= leading; // shift over the 0s
-        faces -= 1 << (CHUNK_SIZE - 1); // subtract the most significant bit
-        faces <<= 1; // shift it over now that it's 0
-
-        z += leading + 1;
-
-        data.insert(
-            LocalBlockPos(
-                x as ChunkDimTy,
-                y as ChunkDimTy,
-                CHUNK_SIZE as ChunkDimTy - z,
-            ),
-            chunk.get_block(&LocalBlockPos(
-                x as ChunkDimTy,
-                y as ChunkDimTy,
-                CHUNK_SIZE as ChunkDimTy - z,
-            )),
-        );
-    }
-}
-
-/// Greedy mesh the quads,
-/// note: this is not guaranteed to produce optimal meshes
-/// THIS ALGORITHM HAS A BUG IN IT FFS
-fn greedy_merge(hm: &mut HashMap<LocalBlockPos, Block>, axis: usize) -> Vec<EncodedVertex> {
-    // create output mesh data vec
-    let mut output = Vec::<EncodedVertex>::new();
-
-    let growth_axes = match axis as u32 % 3 {
-        0 => [LocalBlockPos(1, 0, 0), LocalBlockPos(0, 1, 0)], // xy-plane
-        1 => [LocalBlockPos(1, 0, 0), LocalBlockPos(0, 0, 1)], // xz-plane
-        2 => [LocalBlockPos(0, 1, 0), LocalBlockPos(0, 0, 1)], // yz-plane
-        _ => [LocalBlockPos(1, 1, 1), LocalBlockPos(1, 1, 1)],
-    };
+        let z = faces.trailing_zeros();
+        faces &= faces - 1; // clear the lowest bit
 
-    while !hm.is_empty() {
-        // get an element
-        let (pos, block) = hm.iter().take(1).collect::<Vec<_>>()[0];
-        let pos = *pos; // we clone the values to avoid appease the borrow checker
-        let block = *block;
+        let pos = LocalBlockPos(x as ChunkDimTy, y as ChunkDimTy, z);
+        let block = blocks[z as usize];
 
-        hm.remove(&pos);
-
-        let i = growth_axes[0];
-        let j = growth_axes[1];
-
-        let mut quad1 = pos;
-        let mut quad2 = pos;
-
-        // check block forward in the row
-        while let Some(b) = hm.get(&LocalBlockPos(quad2.0 + i.0, quad2.1 + i.1, quad2.2 + i.2)) {
-            if b == &block {
-                hm.remove(&LocalBlockPos(quad2.0 + i.0, quad2.1 + i.1, quad2.2 + i.2));
-                quad2 = LocalBlockPos(quad2.0 + i.0, quad2.1 + i.1, quad2.2 + i.2);
-            } else {
-                break;
+        if !block.is_opaque() {
+            let neighbour = hood.get_block(pos.0 as i32 + dx, pos.1 as i32 + dy, pos.2 as i32 + dz);
+            if neighbour == Some(block) {
+                continue;
             }
         }
 
-        // check the blocks backward in the row
-        while let Some(t) = LocalBlockPos::safe_sub(&quad1, &i) {
-            if let Some(b) = hm.get(&t) {
-                if b == &block {
-                    hm.remove(&t);
-                    quad1 = t;
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
-        }
+        planes.insert(pos, block);
+    }
+}
 
-        let column_length =
-            1 + (quad2.0 - quad1.0) * i.0 + (quad2.1 - quad1.1) * i.1 + (quad2.2 - quad1.2) * i.2;
+/// The visible faces in one direction as bit planes, a stack of them for each
+/// block type. Each plane is a slice of the chunk along the face normal, with
+/// a mask per row.
+struct FacePlanes {
+    axis: usize,
+    /// `CHUNK_SIZE` planes of `CHUNK_SIZE` rows for each block.
+    planes: HashMap<Block, Vec<ColumnMask>>,
+}
 
-        // check column backward
-        let mut can_grow = true;
-        while can_grow {
-            let Some(t) = LocalBlockPos::safe_sub(&quad1, &j) else {
-                break;
-            };
+impl FacePlanes {
+    fn new(axis: usize) -> Self {
+        Self {
+            axis,
+            planes: HashMap::new(),
+        }
+    }
 
-            let mut to_remove = vec![];
-            for l in 0..column_length {
-                let a = LocalBlockPos(t.0 + l * i.0, t.1 + l * i.1, t.2 + l * i.2);
-                let c = hm.get(&a);
+    /// The plane, row and bit of a block position. Rows run along x or y and
+    /// the bits along z, except for the z faces, which can only be sliced
+    /// across z.
+    fn slot(&self, LocalBlockPos(x, y, z): LocalBlockPos) -> (ChunkDimTy, ChunkDimTy, ChunkDimTy) {
+        match self.axis % 3 {
+            0 => (z, y, x), // xy-plane
+            1 => (y, x, z), // xz-plane
+            _ => (x, y, z), // yz-plane
+        }
+    }
 
-                if let Some(b) = c {
-                    if b == &block {
-                        to_remove.push(a);
-                        continue;
-                    }
-                }
+    /// The block position at a plane, row and bit, the inverse of `slot`.
+    fn position(&self, plane: ChunkDimTy, row: ChunkDimTy, bit: ChunkDimTy) -> LocalBlockPos {
+        match self.axis % 3 {
+            0 => LocalBlockPos(bit, row, plane),
+            1 => LocalBlockPos(row, plane, bit),
+            _ => LocalBlockPos(plane, row, bit),
+        }
+    }
 
-                can_grow = false;
-            }
+    fn insert(&mut self, pos: LocalBlockPos, block: Block) {
+        let (plane, row, bit) = self.slot(pos);
+        let size = CHUNK_SIZE as usize;
+        let planes = self
+            .planes
+            .entry(block)
+            .or_insert_with(|| vec![0; size * size]);
+        planes[plane as usize * size + row as usize] |= 1 << bit;
+    }
 
-            if can_grow {
-                quad1 = t;
-                for k in to_remove {
-                    hm.remove(&k);
-                }
+    /// Greedy meshes the planes of each block into quads. Doesn't always find
+    /// the fewest quads, but they never overlap.
+    fn merge(mut self) -> Vec<EncodedVertex> {
+        let mut output = Vec::new();
+        let size = CHUNK_SIZE as usize;
+
+        // sorted so the mesh doesn't depend on the order of the hashmap
+        let mut blocks: Vec<_> = self.planes.keys().copied().collect();
+        blocks.sort_by_key(|b| b.0);
+
+        for block in blocks {
+            let mut planes = self.planes.remove(&block).unwrap_or_default();
+            for (plane, rows) in planes.chunks_mut(size).enumerate() {
+                merge_plane(rows, |first_row, last_row, first_bit, last_bit| {
+                    let plane = plane as ChunkDimTy;
+                    let min = self.position(plane, first_row, first_bit);
+                    let max = self.position(plane, last_row, last_bit);
+                    output.append(&mut create_quad(self.axis, min, max, block));
+                });
             }
         }
 
-        // check column forward
-        can_grow = true;
-        while can_grow {
-            let t = LocalBlockPos(quad2.0 + j.0, quad2.1 + j.1, quad2.2 + j.2);
-
-            let mut to_remove = vec![];
-            for l in 0..column_length {
-                let Some(a) =
-                    LocalBlockPos::safe_sub(&t, &LocalBlockPos(l * i.0, l * i.1, l * i.2))
-                else {
-                    can_grow = false;
-                    break;
-                };
+        output
+    }
+}
 
-                if let Some(b) = hm.get(&a) {
-                    if b == &block {
-                        to_remove.push(a);
-                        continue;
-                    }
-                }
+/// Merges the set bits of a plane into rectangles, clearing them. Each
+/// rectangle starts as the run of bits from the lowest set bit of a row, and
+/// grows over the next rows for as long as they have the whole run set.
+/// `quad` gets the first and last row and the first and last bit, inclusive.
+fn merge_plane(
+    rows: &mut [ColumnMask],
+    mut quad: impl FnMut(ChunkDimTy, ChunkDimTy, ChunkDimTy, ChunkDimTy),
+) {
+    for first in 0..rows.len() {
+        while rows[first] != 0 {
+            let start = rows[first].trailing_zeros();
+            let width = (rows[first] >> start).trailing_ones();
+            // a run as wide as the chunk would overflow the shift
+            let run = if width == CHUNK_SIZE {
+                ColumnMask::MAX
+            } else {
+                ((1 << width) - 1) << start
+            };
+            rows[first] &= !run;
 
-                can_grow = false;
+            let mut last = first;
+            while last + 1 < rows.len() && rows[last + 1] & run == run {
+                last += 1;
+                rows[last] &= !run;
             }
 
-            if can_grow {
-                quad2 = t;
-                for k in to_remove {
-                    hm.remove(&k);
-                }
-            }
+            quad(
+                first as ChunkDimTy,
+                last as ChunkDimTy,
+                start,
+                start + width - 1,
+            );
         }
-        output.append(&mut create_quad(axis, quad1, quad2));
     }
-
-    output
 }
 
 /// Encode the vertices of a quad, defined by two opposite corners.
@@ -261,6 +281,7 @@ fn create_quad(
     axis: usize, // Axis along which the face is oriented: 0-5 for six cube faces
     LocalBlockPos(c1x, c1y, c1z): LocalBlockPos,
     LocalBlockPos(c2x, c2y, c2z): LocalBlockPos,
+    block: Block,
 ) -> Vec<EncodedVertex> {
     // Determine the min and max bounds of the corners
 
@@ -279,65 +300,72 @@ fn create_quad(
     match axis {
         2 => vec![
             // +X face
-            encode_vertex(max_x, min_y, min_z),
-            encode_vertex(max_x, max_y, min_z),
-            encode_vertex(max_x, max_y, max_z),
-            encode_vertex(max_x, min_y, min_z),
-            encode_vertex(max_x, max_y, max_z),
-            encode_vertex(max_x, min_y, max_z),
+            encode_vertex(max_x, min_y, min_z, block),
+            encode_vertex(max_x, max_y, min_z, block),
+            encode_vertex(max_x, max_y, max_z, block),
+            encode_vertex(max_x, min_y, min_z, block),
+            encode_vertex(max_x, max_y, max_z, block),
+            encode_vertex(max_x, min_y, max_z, block),
         ],
         5 => vec![
             // -X face
-            encode_vertex(min_x, min_y, min_z),
-            encode_vertex(min_x, max_y, min_z),
-            encode_vertex(min_x, max_y, max_z),
-            encode_vertex(min_x, min_y, min_z),
-            encode_vertex(min_x, max_y, max_z),
-            encode_vertex(min_x, min_y, max_z),
+            encode_vertex(min_x, min_y, min_z, block),
+            encode_vertex(min_x, max_y, min_z, block),
+            encode_vertex(min_x, max_y, max_z, block),
+            encode_vertex(min_x, min_y, min_z, block),
+            encode_vertex(min_x, max_y, max_z, block),
+            encode_vertex(min_x, min_y, max_z, block),
         ],
         1 => vec![
             // +Y face
-            encode_vertex(min_x, max_y, min_z),
-            encode_vertex(max_x, max_y, min_z),
-            encode_vertex(max_x, max_y, max_z),
-            encode_vertex(min_x, max_y, min_z),
-            encode_vertex(max_x, max_y, max_z),
-            encode_vertex(min_x, max_y, max_z),
+            encode_vertex(min_x, max_y, min_z, block),
+            encode_vertex(max_x, max_y, min_z, block),
+            encode_vertex(max_x, max_y, max_z, block),
+            encode_vertex(min_x, max_y, min_z, block),
+            encode_vertex(max_x, max_y, max_z, block),
+            encode_vertex(min_x, max_y, max_z, block),
         ],
         4 => vec![
             // -Y face
-            encode_vertex(min_x, min_y, min_z),
-            encode_vertex(max_x, min_y, min_z),
-            encode_vertex(max_x, min_y, max_z),
-            encode_vertex(min_x, min_y, min_z),
-            encode_vertex(max_x, min_y, max_z),
-            encode_vertex(min_x, min_y, max_z),
+            encode_vertex(min_x, min_y, min_z, block),
+            encode_vertex(max_x, min_y, min_z, block),
+            encode_vertex(max_x, min_y, max_z, block),
+            encode_vertex(min_x, min_y, min_z, block),
+            encode_vertex(max_x, min_y, max_z, block),
+            encode_vertex(min_x, min_y, max_z, block),
         ],
         3 => vec![
             // +Z face
-            encode_vertex(min_x, min_y, max_z),
-            encode_vertex(max_x, min_y, max_z),
-            encode_vertex(max_x, max_y, max_z),
-            encode_vertex(min_x, min_y, max_z),
-            encode_vertex(max_x, max_y, max_z),
-            encode_vertex(min_x, max_y, max_z),
+            encode_vertex(min_x, min_y, max_z, block),
+            encode_vertex(max_x, min_y, max_z, block),
+            encode_vertex(max_x, max_y, max_z, block),
+            encode_vertex(min_x, min_y, max_z, block),
+            encode_vertex(max_x, max_y, max_z, block),
+            encode_vertex(min_x, max_y, max_z, block),
         ],
         0 => vec![
             // -Z face
-            encode_vertex(min_x, min_y, min_z),
-            encode_vertex(max_x, min_y, min_z),
-            encode_vertex(max_x, max_y, min_z),
-            encode_vertex(min_x, min_y, min_z),
-            encode_vertex(max_x, max_y, min_z),
-            encode_vertex(min_x, max_y, min_z),
+            encode_vertex(min_x, min_y, min_z, block),
+            encode_vertex(max_x, min_y, min_z, block),
+            encode_vertex(max_x, max_y, min_z, block),
+            encode_vertex(min_x, min_y, min_z, block),
+            encode_vertex(max_x, max_y, min_z, block),
+            encode_vertex(min_x, max_y, min_z, block),
         ],
         _ => panic!("Invalid axis value: must be 0-5"),
     }
 }
 
-/// Helper function to encode a vertex position into a single value.
-fn encode_vertex(x: ChunkDimTy, y: ChunkDimTy, z: ChunkDimTy) -> EncodedVertex {
-    let mut output = 0;
+/// Helper function to encode a vertex position and the block id into a single
+/// value. The block id takes up the bits above the position, the block state
+/// isn't encoded.
+fn encode_vertex(x: ChunkDimTy, y: ChunkDimTy, z: ChunkDimTy, block: Block) -> EncodedVertex {
+    // anything bigger would spill into the next coordinate
+    debug_assert!(x <= CHUNK_SIZE && y <= CHUNK_SIZE && z <= CHUNK_SIZE);
+    debug_assert!(block.id() < MAX_BLOCK_ID);
+
+    let mut output = block.id();
+    output <<= NUM_BITS_IN_POS;
     output |= x;
     output <<= NUM_BITS_IN_POS;
     output |= y;
@@ -347,22 +375,209 @@ fn encode_vertex(x: ChunkDimTy, y: ChunkDimTy, z: ChunkDimTy) -> EncodedVertex {
     EncodedVertex(output)
 }
 
+/// A single block face, by the direction it faces (the index of its mesh in
+/// the output of `mesh`), the block it belongs to and the block's type id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Face {
+    pub axis: usize,
+    pub pos: LocalBlockPos,
+    pub block_id: u32,
+}
+
+/// Differences between a mesh and the faces its chunk exposes, see `validate`.
+/// Each list is sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MeshReport {
+    /// Exposed faces that no quad covers.
+    pub missing: Vec<Face>,
+    /// Faces covered by more than one quad, listed once for each extra quad.
+    pub duplicated: Vec<Face>,
+    /// Faces covered by a quad that aren't exposed. A face drawn with the
+    /// wrong block is spurious, and the right one is missing.
+    pub spurious: Vec<Face>,
+    /// Quads, by direction and index, that aren't a rectangle of one block
+    /// type lying on a face of its direction. They aren't rasterised.
+    pub malformed: Vec<(usize, usize)>,
+}
+
+impl MeshReport {
+    /// Whether the mesh covers every exposed face exactly once.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+            && self.duplicated.is_empty()
+            && self.spurious.is_empty()
+            && self.malformed.is_empty()
+    }
+}
+
+/// Checks a mesh of `chunk` by rasterising its quads back into faces and
+/// comparing them to the faces the chunk exposes, with everything outside the
+/// chunk as air. Meant for tests and debugging, it is far slower than meshing.
+/// The winding of the triangles isn't checked, the chunks are drawn without
+/// back face culling.
+pub fn validate(chunk: &Chunk, mesh: &[Vec<EncodedVertex>; 6]) -> MeshReport {
+    validate_with_neighbors(&ChunkNeighborhood::new(chunk), mesh)
+}
+
+/// Like `validate`, for a mesh from `mesh_with_neighbors`.
+pub fn validate_with_neighbors(
+    hood: &ChunkNeighborhood,
+    mesh: &[Vec<EncodedVertex>; 6],
+) -> MeshReport {
+    let mut report = MeshReport::default();
+
+    let mut expected = exposed_faces(hood);
+    let mut drawn = HashSet::new();
+    for (axis, vertices) in mesh.iter().enumerate() {
+        // a trailing partial quad is malformed too
+        for (index, quad) in vertices.chunks(6).enumerate() {
+            let Some(faces) = rasterise_quad(axis, quad) else {
+                report.malformed.push((axis, index));
+                continue;
+            };
+
+            for face in faces {
+                if !drawn.insert(face) {
+                    report.duplicated.push(face);
+                } else if !expected.remove(&face) {
+                    report.spurious.push(face);
+                }
+            }
+        }
+    }
+    report.missing.extend(expected);
+
+    let key = |f: &Face| (f.axis, f.pos.0, f.pos.1, f.pos.2, f.block_id);
+    report.missing.sort_by_key(key);
+    report.duplicated.sort_by_key(key);
+    report.spurious.sort_by_key(key);
+    report
+}
+
+/// Every face of the center chunk that should be drawn, checked a block at a
+/// time: visible blocks next to a block that isn't opaque, unless it's the same
+/// see-through block.
+fn exposed_faces(hood: &ChunkNeighborhood) -> HashSet<Face> {
+    let mut faces = HashSet::new();
+
+    for (pos, block) in hood.center().iter_non_air() {
+        if !block.is_visible() {
+            continue;
+        }
+
+        for (axis, (dx, dy, dz)) in FACE_OFFSETS.into_iter().enumerate() {
+            let neighbour = hood.get_block(pos.0 as i32 + dx, pos.1 as i32 + dy, pos.2 as i32 + dz);
+            let hidden = neighbour.is_some_and(|n| n.is_opaque() || n == block);
+            if !hidden {
+                faces.insert(Face {
+                    axis,
+                    pos,
+                    block_id: block.id(),
+                });
+            }
+        }
+    }
+
+    faces
+}
+
+/// The faces covered by the six vertices of a quad, `None` if they aren't the
+/// two triangles of a rectangle on the right side of a row of blocks.
+fn rasterise_quad(axis: usize, quad: &[EncodedVertex]) -> Option<Vec<Face>> {
+    if quad.len() != 6 {
+        return None;
+    }
+    let block_id = quad[0].block_id();
+    if quad.iter().any(|v| v.block_id() != block_id) {
+        return None;
+    }
+
+    let positions: Vec<[ChunkDimTy; 3]> = quad
+        .iter()
+        .map(|v| {
+            let (x, y, z) = v.position();
+            [x, y, z]
+        })
+        .collect();
+    let min: [ChunkDimTy; 3] =
+        std::array::from_fn(|i| positions.iter().map(|p| p[i]).min().unwrap_or(0));
+    let max: [ChunkDimTy; 3] =
+        std::array::from_fn(|i| positions.iter().map(|p| p[i]).max().unwrap_or(0));
+
+    // flat along the face normal, with some area across it
+    let (dx, dy, dz) = FACE_OFFSETS[axis];
+    let offset = [dx, dy, dz];
+    let normal = offset.iter().position(|d| *d != 0)?;
+    let flat = (0..3).all(|i| (i == normal) == (min[i] == max[i]));
+
+    if !flat {
+        return None;
+    }
+
+    // each triangle is three corners of the rectangle, and leaves out the
+    // corner opposite the one the other leaves out, so they meet along a
+    // diagonal instead of overlapping
+    let corners: Vec<[ChunkDimTy; 3]> = (0..4)
+        .map(|c| {
+            let mut across = [c & 1 == 1, c & 2 == 2].into_iter();
+            std::array::from_fn(|i| {
+                if i != normal && across.next() == Some(true) {
+                    max[i]
+                } else {
+                    min[i]
+                }
+            })
+        })
+        .collect();
+    let left_out = |triangle: &[[ChunkDimTy; 3]]| {
+        let mut unused = corners
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| !triangle.contains(c));
+        match (unused.next(), unused.next()) {
+            (Some((index, _)), None) if triangle.iter().all(|p| corners.contains(p)) => Some(index),
+            _ => None,
+        }
+    };
+    match (left_out(&positions[0..3]), left_out(&positions[3..6])) {
+        (Some(a), Some(b)) if a ^ b == 3 => {}
+        _ => return None,
+    }
+
+    // faces on the positive side lie on the far side of their block
+    let mut range: [std::ops::Range<ChunkDimTy>; 3] = std::array::from_fn(|i| min[i]..max[i]);
+    range[normal] = match offset[normal] {
+        1 if min[normal] > 0 => min[normal] - 1..min[normal],
+        -1 if min[normal] < CHUNK_SIZE => min[normal]..min[normal] + 1,
+        _ => return None,
+    };
+
+    let mut faces = Vec::new();
+    for x in range[0].clone() {
+        for y in range[1].clone() {
+            for z in range[2].clone() {
+                faces.push(Face {
+                    axis,
+                    pos: LocalBlockPos(x, y, z),
+                    block_id,
+                });
+            }
+        }
+    }
+    Some(faces)
+}
+
 #[cfg(test)]
 mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     use super::*;
 
-    #[allow(dead_code)]
-    fn decode_vertex(v: &EncodedVertex) -> (ChunkDimTy, ChunkDimTy, ChunkDimTy) {
-        let mut t = v.0;
-
-        let z = t & 63;
-        t >>= NUM_BITS_IN_POS;
-        let y = t & 63;
-        t >>= NUM_BITS_IN_POS;
-        let x = t & 63;
-
-        (x, y, z)
+    #[test]
+    fn vertices_decode_to_their_position() {
+        let v = encode_vertex(CHUNK_SIZE, 3, 0, Block::new(2).with_level(1));
+        assert_eq!(v.position(), (CHUNK_SIZE, 3, 0));
+        assert_eq!(v.block_id(), 2);
     }
 
     #[test]
@@ -378,6 +593,358 @@ mod tests {
         assert!(y == Block(0));
     }
 
+    #[test]
+    fn block_state_prevents_merging() {
+        let water = Block::new(1).with_level(Block::MAX_LEVEL);
+
+        let mut chunk = Chunk::default();
+        chunk.set_block(LocalBlockPos(0, 0, 0), water);
+        chunk.set_block(LocalBlockPos(1, 0, 0), water);
+
+        // the same block merges into a single top face
+        assert_eq!(mesh(&chunk)[1].len(), 6);
+
+        // a half full cell next to a full one can't be merged
+        chunk.set_block(
+            LocalBlockPos(1, 0, 0),
+            water.with_level(Block::MAX_LEVEL / 2),
+        );
+        let data = mesh(&chunk);
+        assert_eq!(data[1].len(), 12);
+
+        // but both are still the same colour
+        assert!(data[1].iter().all(|v| v.0 >> (3 * NUM_BITS_IN_POS) == 1));
+    }
+
+    #[test]
+    fn see_through_blocks_have_faces() {
+        let leaves = Block(3);
+
+        let mut chunk = Chunk::default();
+        chunk.set_block(LocalBlockPos(4, 4, 4), Block(1));
+        chunk.set_block(LocalBlockPos(5, 4, 4), leaves);
+
+        let data = mesh(&chunk);
+
+        // the dirt can be seen through the leaves, so it keeps its +X face,
+        // but the leaves' -X face is hidden by the dirt. Different blocks
+        // can't be merged, so every other direction has two quads
+        for (axis, faces) in data.iter().enumerate() {
+            let expected = if axis == 5 { 6 } else { 12 };
+            assert_eq!(faces.len(), expected, "axis {}", axis);
+        }
+    }
+
+    #[test]
+    fn faces_between_same_see_through_blocks_are_culled() {
+        let leaves = Block(3);
+
+        let mut chunk = Chunk::default();
+        chunk.set_block(LocalBlockPos(4, 4, 4), leaves);
+        chunk.set_block(LocalBlockPos(5, 4, 4), leaves);
+
+        let data = mesh(&chunk);
+        for faces in data {
+            assert_eq!(faces.len(), 6);
+        }
+    }
+
+    fn sorted_quads(mesh: &[Vec<EncodedVertex>; 6]) -> Vec<Vec<Vec<u32>>> {
+        mesh.iter()
+            .map(|faces| {
+                let mut quads: Vec<_> = faces
+                    .chunks(6)
+                    .map(|q| q.iter().map(|v| v.0).collect::<Vec<_>>())
+                    .collect();
+                quads.sort();
+                quads
+            })
+            .collect()
+    }
+
+    #[test]
+    fn uniform_mesh_matches_culled_mesh() {
+        for block in [Block(0), Block(1), Block(3)] {
+            let mut chunk = Chunk::default();
+            chunk.fill(block);
+
+            assert!(chunk.uniform_block().is_some());
+            assert_eq!(
+                sorted_quads(&mesh(&chunk)),
+                sorted_quads(&mesh_culled(&ChunkNeighborhood::new(&chunk))),
+                "{:?}",
+                block
+            );
+        }
+    }
+
+    #[test]
+    fn buried_chunks_have_no_faces() {
+        let chunk = Chunk::full();
+        let hood = Facing::ALL
+            .into_iter()
+            .fold(ChunkNeighborhood::new(&chunk), |hood, facing| {
+                hood.with_neighbor(facing, &chunk)
+            });
+
+        let data = mesh_with_neighbors(&hood);
+        assert!(data.iter().all(|faces| faces.is_empty()));
+    }
+
+    #[test]
+    fn faces_are_culled_against_neighbors() {
+        let chunk = Chunk::full();
+        let east = Chunk::full();
+        let mut west = Chunk::default();
+        west.set_block(LocalBlockPos(CHUNK_SIZE - 1, 0, 0), Block(1));
+
+        let hood = ChunkNeighborhood::new(&chunk)
+            .with_neighbor(Facing::East, &east)
+            .with_neighbor(Facing::West, &west);
+        let data = mesh_with_neighbors(&hood);
+
+        // +X is fully hidden, -X has a hole where the west block is, and the
+        // rest are still whole sides of the chunk
+        assert!(data[2].is_empty());
+        assert!(data[5].len() > 6);
+        assert!(data[5]
+            .iter()
+            .all(|v| v.position().0 == 0 && v.block_id() == 1));
+        for axis in [0, 1, 3, 4] {
+            assert_eq!(data[axis].len(), 6, "axis {}", axis);
+        }
+
+        // the neighbours are meshed the other way around
+        let east_hood = ChunkNeighborhood::new(&east).with_neighbor(Facing::West, &chunk);
+        assert!(mesh_with_neighbors(&east_hood)[5].is_empty());
+        let west_hood = ChunkNeighborhood::new(&west).with_neighbor(Facing::East, &chunk);
+        assert!(mesh_with_neighbors(&west_hood)[2].is_empty());
+    }
+
+    #[test]
+    fn see_through_blocks_are_culled_across_chunks() {
+        let leaves = Block(3);
+
+        let mut chunk = Chunk::default();
+        chunk.set_block(LocalBlockPos(4, CHUNK_SIZE - 1, 4), leaves);
+        chunk.set_block(LocalBlockPos(6, CHUNK_SIZE - 1, 4), Block(1));
+        let mut up = Chunk::default();
+        up.set_block(LocalBlockPos(4, 0, 4), leaves);
+        up.set_block(LocalBlockPos(6, 0, 4), leaves);
+
+        let hood = ChunkNeighborhood::new(&chunk).with_neighbor(Facing::Up, &up);
+        let data = mesh_with_neighbors(&hood);
+
+        // leaves next to leaves are culled, but the dirt can be seen through
+        // the leaves above it
+        assert_eq!(data[1].len(), 6);
+        assert_eq!(data[1][0].block_id(), 1);
+    }
+
+    /// Number of block faces covered by the quads in each direction. The
+    /// quads themselves depend on the order they are merged in.
+    fn face_area(mesh: &[Vec<EncodedVertex>; 6]) -> [u32; 6] {
+        mesh.each_ref().map(|faces| {
+            faces
+                .chunks(6)
+                .map(|quad| {
+                    let size = |axis: fn((u32, u32, u32)) -> u32| {
+                        let values = quad.iter().map(|v| axis(v.position()));
+                        values.clone().max().unwrap() - values.min().unwrap()
+                    };
+                    let (x, y, z) = (size(|p| p.0), size(|p| p.1), size(|p| p.2));
+                    x.max(1) * y.max(1) * z.max(1)
+                })
+                .sum()
+        })
+    }
+
+    #[test]
+    fn unloaded_neighbors_are_air() {
+        let chunk = Chunk::random();
+        let expected = face_area(&mesh(&chunk));
+        assert_eq!(
+            expected,
+            face_area(&mesh_with_neighbors(&ChunkNeighborhood::new(&chunk)))
+        );
+
+        // an empty neighbour doesn't hide anything either
+        let air = Chunk::default();
+        let hood = ChunkNeighborhood::new(&chunk).with_neighbor(Facing::Down, &air);
+        assert_eq!(expected, face_area(&mesh_with_neighbors(&hood)));
+
+        // but a full one hides the whole bottom layer
+        let full = Chunk::full();
+        let hood = ChunkNeighborhood::new(&chunk).with_neighbor(Facing::Down, &full);
+        let bottom = (0..CHUNK_SIZE)
+            .flat_map(|x| (0..CHUNK_SIZE).map(move |z| LocalBlockPos(x, 0, z)))
+            .filter(|pos| chunk.is_visible(pos))
+            .count() as u32;
+        let mut culled = expected;
+        culled[4] -= bottom;
+        assert_eq!(culled, face_area(&mesh_with_neighbors(&hood)));
+    }
+
+    #[test]
+    fn planes_merge_into_rectangles() {
+        let mut rows = [0b0110, 0b0110, 0b1111, 0b1001, ColumnMask::MAX];
+        let mut quads = Vec::new();
+        merge_plane(&mut rows, |a, b, c, d| quads.push((a, b, c, d)));
+
+        assert_eq!(
+            quads,
+            [
+                (0, 2, 1, 2),
+                (2, 4, 0, 0),
+                (2, 4, 3, 3),
+                (4, 4, 1, 2),
+                (4, 4, 4, CHUNK_SIZE - 1),
+            ]
+        );
+        assert!(rows.iter().all(|row| *row == 0));
+    }
+
+    /// A chunk of a few block types with random states, either as noise or
+    /// as boxes, so there is something to merge.
+    fn random_chunk(rng: &mut StdRng) -> Chunk {
+        let palette = [
+            Block(1),
+            Block(2),
+            Block(3),
+            Block(3).with_level(2),
+            Block(1).with_variant(1),
+        ];
+        let kinds = rng.gen_range(1..=palette.len());
+        let block = |rng: &mut StdRng| palette[rng.gen_range(0..kinds)];
+
+        let mut chunk = Chunk::default();
+        if rng.gen_bool(0.5) {
+            let density = rng.gen::<f64>();
+            for (pos, _) in Chunk::default().iter() {
+                if rng.gen_bool(density) {
+                    chunk.set_block(pos, block(rng));
+                }
+            }
+        } else {
+            for _ in 0..rng.gen_range(1..12) {
+                let mut corner = || {
+                    let a = rng.gen_range(0..CHUNK_SIZE);
+                    let b = rng.gen_range(0..=CHUNK_SIZE);
+                    (a.min(b), a.max(b))
+                };
+                let ((x0, x1), (y0, y1), (z0, z1)) = (corner(), corner(), corner());
+                let b = block(rng);
+                chunk.fill_region(LocalBlockPos(x0, y0, z0), LocalBlockPos(x1, y1, z1), b);
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn random_chunks_have_every_face_once() {
+        // the mesh of another chunk doesn't pass
+        let other = random_chunk(&mut StdRng::seed_from_u64(100));
+        let chunk = random_chunk(&mut StdRng::seed_from_u64(101));
+        assert!(!validate(&chunk, &mesh(&other)).is_ok());
+
+        let chunk = Chunk::random();
+        let report = validate(&chunk, &mesh(&chunk));
+        assert!(report.is_ok(), "{:?}", report);
+
+        for seed in 0..12 {
+            let chunk = random_chunk(&mut StdRng::seed_from_u64(seed));
+            let report = validate(&chunk, &mesh(&chunk));
+            assert!(report.is_ok(), "seed {}: {:?}", seed, report);
+        }
+    }
+
+    #[test]
+    fn random_neighbors_are_culled_exactly() {
+        for seed in 0..6 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let center = random_chunk(&mut rng);
+            let neighbors: Vec<_> = Facing::ALL.map(|_| random_chunk(&mut rng)).into();
+
+            // leave some of the neighbours unloaded
+            let hood = Facing::ALL.into_iter().zip(&neighbors).fold(
+                ChunkNeighborhood::new(&center),
+                |hood, (facing, chunk)| {
+                    if rng.gen_bool(0.7) {
+                        hood.with_neighbor(facing, chunk)
+                    } else {
+                        hood
+                    }
+                },
+            );
+
+            let report = validate_with_neighbors(&hood, &mesh_with_neighbors(&hood));
+            assert!(report.is_ok(), "seed {}: {:?}", seed, report);
+        }
+    }
+
+    #[test]
+    fn broken_meshes_are_reported() {
+        let mut chunk = Chunk::default();
+        chunk.fill_region(LocalBlockPos(1, 1, 1), LocalBlockPos(4, 2, 3), Block(2));
+        let good = mesh(&chunk);
+        assert!(validate(&chunk, &good).is_ok());
+        let top = |x, z| Face {
+            axis: 1,
+            pos: LocalBlockPos(x, 1, z),
+            block_id: 2,
+        };
+        let all_top: Vec<_> = (1..4)
+            .flat_map(|x| (1..3).map(move |z| top(x, z)))
+            .collect();
+
+        // a quad left out
+        let mut broken = good.clone();
+        broken[1].clear();
+        assert_eq!(validate(&chunk, &broken).missing, all_top);
+
+        // a quad drawn twice
+        let mut broken = good.clone();
+        broken[1].extend(good[1].clone());
+        let report = validate(&chunk, &broken);
+        assert_eq!(report.duplicated, all_top);
+        assert!(report.missing.is_empty() && report.spurious.is_empty());
+
+        // a quad drawn with the wrong block
+        let mut broken = good.clone();
+        broken[1] = good[1]
+            .iter()
+            .map(|v| EncodedVertex(v.0 + (1 << (3 * NUM_BITS_IN_POS))))
+            .collect();
+        let report = validate(&chunk, &broken);
+        assert_eq!(report.missing, all_top);
+        assert!(report.spurious.iter().all(|f| f.block_id == 3));
+        assert_eq!(report.spurious.len(), all_top.len());
+
+        // a quad moved up a block
+        let mut broken = good.clone();
+        broken[1] = good[1]
+            .iter()
+            .map(|v| EncodedVertex(v.0 + (1 << NUM_BITS_IN_POS)))
+            .collect();
+        let report = validate(&chunk, &broken);
+        assert_eq!(report.missing, all_top);
+        assert_eq!(report.spurious[0].pos, LocalBlockPos(1, 2, 1));
+
+        // vertices that aren't a quad
+        let mut broken = good.clone();
+        broken[1].pop();
+        broken[3][2] = broken[3][0];
+        let report = validate(&chunk, &broken);
+        assert_eq!(report.malformed, [(1, 0), (3, 0)]);
+        assert!(!report.missing.is_empty());
+    }
+
+    #[test]
+    fn empty_chunk_has_no_mesh() {
+        let data = mesh(&Chunk::default());
+        assert!(data.iter().all(|faces| faces.is_empty()));
+    }
+
     #[test]
     fn can_mesh_chunk() {
         let chunk = Chunk::full();
@@ -1,36 +1,153 @@
-use std::collections::HashMap;
-
-use block::Block;
+use block::{Block, Facing};
+use palette::PalettedStorage;
 
 pub mod block;
 pub mod manager;
 pub mod mesher;
+pub mod neighborhood;
+pub mod palette;
 pub mod pool;
+pub mod registry;
+pub mod serialize;
 pub mod traverse;
 pub mod visibility;
 
+#[cfg(all(feature = "chunk-16", feature = "chunk-64"))]
+compile_error!("Only one of the `chunk-16` and `chunk-64` features can be enabled");
+
+/// Bitmask with one bit per block along a chunk column. The chunk size is the
+/// width of this type, so it is picked with the `chunk-16` and `chunk-64`
+/// features, or 32 if neither is enabled.
+#[cfg(feature = "chunk-16")]
+pub type ColumnMask = u16;
+#[cfg(feature = "chunk-64")]
+pub type ColumnMask = u64;
+#[cfg(not(any(feature = "chunk-16", feature = "chunk-64")))]
+pub type ColumnMask = u32;
+
 pub type ChunkDimTy = u32;
 
 /// Used for encoding the vertex position in a single vertex. We add a bit to encompass when the vertex pos is the max (ie 32 in a 32 bit chunk won't fit inside 5 bits). This can probably be changed if padding is added.
-pub const NUM_BITS_IN_POS: ChunkDimTy =
-    ChunkDimTy::ilog2(8 * std::mem::size_of::<ChunkDimTy>() as ChunkDimTy) as ChunkDimTy + 1 as ChunkDimTy;
-pub const CHUNK_SIZE: ChunkDimTy = (std::mem::size_of::<ChunkDimTy>() * 8) as ChunkDimTy;
+pub const NUM_BITS_IN_POS: ChunkDimTy = CHUNK_SIZE.ilog2() + 1;
+pub const CHUNK_SIZE: ChunkDimTy = ColumnMask::BITS;
+
+/// Block ids are stored in the bits of an encoded vertex left over after the
+/// position, so that the shader can look up the block colour. They also have
+/// to fit in the id bits of a `Block`.
+pub const MAX_BLOCK_ID: u32 = 1 << min(32 - 3 * NUM_BITS_IN_POS, Block::ID_BITS);
+
+const fn min(a: u32, b: u32) -> u32 {
+    if a < b {
+        a
+    } else {
+        b
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
-pub struct EncodedVertex(pub ChunkDimTy);
+pub struct EncodedVertex(pub u32);
 
 impl EncodedVertex {
-    pub fn to_untyped(&self) -> ChunkDimTy {
+    pub fn to_untyped(&self) -> u32 {
         self.0
     }
+
+    /// Position of the vertex in the chunk, each coordinate is between 0 and
+    /// `CHUNK_SIZE` inclusive.
+    pub fn position(&self) -> (ChunkDimTy, ChunkDimTy, ChunkDimTy) {
+        let mask = (1 << NUM_BITS_IN_POS) - 1;
+
+        let z = self.0 & mask;
+        let y = (self.0 >> NUM_BITS_IN_POS) & mask;
+        let x = (self.0 >> (2 * NUM_BITS_IN_POS)) & mask;
+
+        (x, y, z)
+    }
+
+    /// Type id of the block the vertex belongs to.
+    pub fn block_id(&self) -> u32 {
+        self.0 >> (3 * NUM_BITS_IN_POS)
+    }
+}
+
+/// Errors from accessing blocks in a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkError {
+    /// The position is outside of the chunk.
+    OutOfBounds(LocalBlockPos),
+}
+
+impl std::fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkError::OutOfBounds(pos) => write!(
+                f,
+                "block position {:?} is outside of the chunk (size {})",
+                pos, CHUNK_SIZE
+            ),
+        }
+    }
 }
 
+impl std::error::Error for ChunkError {}
+
 /// Block position relative to the chunk.
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub struct LocalBlockPos(pub ChunkDimTy, pub ChunkDimTy, pub ChunkDimTy);
 
 impl LocalBlockPos {
+    /// Creates a position, checking that it is inside the chunk.
+    pub fn new(x: ChunkDimTy, y: ChunkDimTy, z: ChunkDimTy) -> Result<Self, ChunkError> {
+        let pos = LocalBlockPos(x, y, z);
+        if !pos.in_bounds() {
+            return Err(ChunkError::OutOfBounds(pos));
+        }
+
+        Ok(pos)
+    }
+
+    /// Creates a position from signed coordinates, returning `None` if it is
+    /// outside of the chunk.
+    pub fn from_signed(x: i32, y: i32, z: i32) -> Option<Self> {
+        let x = ChunkDimTy::try_from(x).ok()?;
+        let y = ChunkDimTy::try_from(y).ok()?;
+        let z = ChunkDimTy::try_from(z).ok()?;
+
+        LocalBlockPos::new(x, y, z).ok()
+    }
+
+    pub fn in_bounds(&self) -> bool {
+        self.0 < CHUNK_SIZE && self.1 < CHUNK_SIZE && self.2 < CHUNK_SIZE
+    }
+
+    /// The position moved by the offset, or `None` if that is outside of the
+    /// chunk.
+    pub fn offset(&self, dx: i32, dy: i32, dz: i32) -> Option<Self> {
+        Self::from_signed(self.0 as i32 + dx, self.1 as i32 + dy, self.2 as i32 + dz)
+    }
+
+    /// The positions sharing a face with this one, skipping those outside of
+    /// the chunk.
+    pub fn face_neighbors(&self) -> impl Iterator<Item = LocalBlockPos> {
+        let pos = *self;
+        Facing::ALL.into_iter().filter_map(move |facing| {
+            let (dx, dy, dz) = facing.offset();
+            pos.offset(dx, dy, dz)
+        })
+    }
+
+    /// The 26 positions sharing a face, edge or corner with this one, skipping
+    /// those outside of the chunk.
+    pub fn neighbors(&self) -> impl Iterator<Item = LocalBlockPos> {
+        let pos = *self;
+        (-1..=1)
+            .flat_map(|dx| (-1..=1).map(move |dy| (dx, dy)))
+            .flat_map(|(dx, dy)| (-1..=1).map(move |dz| (dx, dy, dz)))
+            .filter(|offset| *offset != (0, 0, 0))
+            .filter_map(move |(dx, dy, dz)| pos.offset(dx, dy, dz))
+    }
+
     fn safe_sub(p1: &LocalBlockPos, p2: &LocalBlockPos) -> Option<LocalBlockPos> {
         let x = p1.0.checked_sub(p2.0);
         let y = p1.1.checked_sub(p2.1);
@@ -51,28 +168,242 @@ impl LocalBlockPos {
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub struct ChunkPos(pub i32, pub i32, pub i32);
 
-#[derive(Default)]
+/// Number of blocks stored in a single chunk.
+pub const CHUNK_VOLUME: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+/// Blocks are stored in palette compressed storage, indexed so that each
+/// z-column is contiguous. This matches the layout of the opacity and
+/// visibility masks used by the mesher, where each column is a single
+/// `ColumnMask` with one bit per z position.
 pub struct Chunk {
-    pub data: HashMap<LocalBlockPos, Block>,
+    data: PalettedStorage,
+
+    /// One bitmask per (x, y) column, bit z is set if the block is opaque.
+    opaque: Vec<ColumnMask>,
+    /// One bitmask per (x, y) column, bit z is set if the block is visible.
+    visible: Vec<ColumnMask>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self {
+            data: PalettedStorage::new(CHUNK_VOLUME, Block(0)),
+            opaque: vec![0; (CHUNK_SIZE * CHUNK_SIZE) as usize],
+            visible: vec![0; (CHUNK_SIZE * CHUNK_SIZE) as usize],
+        }
+    }
 }
 
-/// Should add bounds checking for set/get
 impl Chunk {
+    /// Returns the block at the position. The position isn't checked in
+    /// release builds, use `try_get_block` if it might be out of bounds.
     pub fn get_block(&self, pos: &LocalBlockPos) -> Block {
-        *self.data.get(pos).unwrap_or(&Block(0))
+        debug_assert!(pos.in_bounds(), "{:?} is out of bounds", pos);
+        self.data.get(Self::index(pos))
     }
 
+    /// Sets the block at the position. The position isn't checked in release
+    /// builds, use `try_set_block` if it might be out of bounds.
     pub fn set_block(&mut self, pos: LocalBlockPos, b: Block) {
-        self.data.insert(pos, b);
+        debug_assert!(pos.in_bounds(), "{:?} is out of bounds", pos);
+        self.data.set(Self::index(&pos), b);
+        self.update_masks(Self::column_index(pos.0, pos.1), 1 << pos.2, b);
+    }
+
+    pub fn try_get_block(&self, pos: &LocalBlockPos) -> Result<Block, ChunkError> {
+        if !pos.in_bounds() {
+            return Err(ChunkError::OutOfBounds(*pos));
+        }
+
+        Ok(self.get_block(pos))
+    }
+
+    pub fn try_set_block(&mut self, pos: LocalBlockPos, b: Block) -> Result<(), ChunkError> {
+        if !pos.in_bounds() {
+            return Err(ChunkError::OutOfBounds(pos));
+        }
+
+        self.set_block(pos, b);
+        Ok(())
+    }
+
+    /// Sets every block in the chunk to `b`.
+    pub fn fill(&mut self, b: Block) {
+        self.data.fill(b);
+        self.opaque
+            .fill(if b.is_opaque() { ColumnMask::MAX } else { 0 });
+        self.visible
+            .fill(if b.is_visible() { ColumnMask::MAX } else { 0 });
+    }
+
+    /// Sets every block between `min` (inclusive) and `max` (exclusive) to `b`.
+    pub fn fill_region(&mut self, min: LocalBlockPos, max: LocalBlockPos, b: Block) {
+        let max = LocalBlockPos(
+            max.0.min(CHUNK_SIZE),
+            max.1.min(CHUNK_SIZE),
+            max.2.min(CHUNK_SIZE),
+        );
+
+        if min.2 >= max.2 {
+            return;
+        }
+
+        // bits min.2..max.2 of a column
+        let mask = (ColumnMask::MAX >> (CHUNK_SIZE - (max.2 - min.2))) << min.2;
+
+        for x in min.0..max.0 {
+            for y in min.1..max.1 {
+                let start = Self::index(&LocalBlockPos(x, y, min.2));
+                for i in start..start + (max.2 - min.2) as usize {
+                    self.data.set(i, b);
+                }
+
+                self.update_masks(Self::column_index(x, y), mask, b);
+            }
+        }
+    }
+
+    /// All the blocks in the chunk, ordered by x, then y, then z.
+    pub fn blocks(&self) -> impl Iterator<Item = Block> + '_ {
+        (0..CHUNK_VOLUME).map(|i| self.data.get(i))
+    }
+
+    /// All the blocks in the chunk with their positions, ordered by x, then y,
+    /// then z.
+    pub fn iter(&self) -> impl Iterator<Item = (LocalBlockPos, Block)> + '_ {
+        (0..CHUNK_VOLUME).map(|i| (Self::position(i), self.data.get(i)))
+    }
+
+    /// The blocks that aren't air, with their positions. Chunks that are only
+    /// air don't look at any blocks.
+    pub fn iter_non_air(&self) -> impl Iterator<Item = (LocalBlockPos, Block)> + '_ {
+        let len = if self.palette().iter().any(|b| b.id() != 0) {
+            CHUNK_VOLUME
+        } else {
+            0
+        };
+
+        (0..len)
+            .map(|i| (Self::position(i), self.data.get(i)))
+            .filter(|(_, b)| b.id() != 0)
+    }
+
+    /// The blocks between `min` (inclusive) and `max` (exclusive), with their
+    /// positions. The box is clamped to the chunk.
+    pub fn iter_box(
+        &self,
+        min: LocalBlockPos,
+        max: LocalBlockPos,
+    ) -> impl Iterator<Item = (LocalBlockPos, Block)> + '_ {
+        let max = LocalBlockPos(
+            max.0.min(CHUNK_SIZE),
+            max.1.min(CHUNK_SIZE),
+            max.2.min(CHUNK_SIZE),
+        );
+
+        (min.0..max.0)
+            .flat_map(move |x| (min.1..max.1).map(move |y| (x, y)))
+            .flat_map(move |(x, y)| (min.2..max.2).map(move |z| LocalBlockPos(x, y, z)))
+            .map(|pos| (pos, self.get_block(&pos)))
+    }
+
+    /// The blocks sharing a face with `pos`, with their positions. Neighbours
+    /// in other chunks are skipped, see `ChunkNeighborhood` to read past the
+    /// edge.
+    pub fn face_neighbors(
+        &self,
+        pos: &LocalBlockPos,
+    ) -> impl Iterator<Item = (LocalBlockPos, Block)> + '_ {
+        pos.face_neighbors().map(|p| (p, self.get_block(&p)))
+    }
+
+    /// The 26 blocks around `pos`, with their positions. Neighbours in other
+    /// chunks are skipped.
+    pub fn neighbors(
+        &self,
+        pos: &LocalBlockPos,
+    ) -> impl Iterator<Item = (LocalBlockPos, Block)> + '_ {
+        pos.neighbors().map(|p| (p, self.get_block(&p)))
+    }
+
+    /// The blocks in the column at (x, y), ordered by z.
+    pub fn column(&self, x: ChunkDimTy, y: ChunkDimTy) -> [Block; CHUNK_SIZE as usize] {
+        let start = Self::index(&LocalBlockPos(x, y, 0));
+        std::array::from_fn(|z| self.data.get(start + z))
+    }
+
+    /// Bitmask of the opaque blocks in the column at (x, y), bit z is set if
+    /// the block at z is opaque.
+    pub fn opaque_column(&self, x: ChunkDimTy, y: ChunkDimTy) -> ColumnMask {
+        self.opaque[Self::column_index(x, y)]
+    }
+
+    /// All the opaque column bitmasks, ordered by x, then y.
+    pub fn opaque_columns(&self) -> &[ColumnMask] {
+        &self.opaque
+    }
+
+    /// Bitmask of the visible blocks in the column at (x, y), bit z is set if
+    /// the block at z is visible.
+    pub fn visible_column(&self, x: ChunkDimTy, y: ChunkDimTy) -> ColumnMask {
+        self.visible[Self::column_index(x, y)]
+    }
+
+    /// All the visible column bitmasks, ordered by x, then y.
+    pub fn visible_columns(&self) -> &[ColumnMask] {
+        &self.visible
+    }
+
+    pub fn is_opaque(&self, pos: &LocalBlockPos) -> bool {
+        self.opaque_column(pos.0, pos.1) & (1 << pos.2) != 0
+    }
+
+    pub fn is_visible(&self, pos: &LocalBlockPos) -> bool {
+        self.visible_column(pos.0, pos.1) & (1 << pos.2) != 0
+    }
+
+    /// The block the whole chunk is made of, if it only holds one block. A
+    /// chunk that has had other blocks set in it is only known to be uniform
+    /// after it is compacted.
+    pub fn uniform_block(&self) -> Option<Block> {
+        self.data.uniform()
+    }
+
+    /// Whether the chunk is known to have nothing in it to draw.
+    pub fn is_empty(&self) -> bool {
+        self.uniform_block().is_some_and(|b| !b.is_visible())
+    }
+
+    /// The distinct blocks stored in the chunk. May contain blocks that are no
+    /// longer used until the chunk is compacted.
+    pub fn palette(&self) -> &[Block] {
+        self.data.palette()
+    }
+
+    /// Drops unused block types from the palette, shrinking the storage.
+    pub fn compact(&mut self) {
+        self.data.compact();
+    }
+
+    /// Approximate number of bytes used by the chunk.
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() + self.data.memory_usage()
+            - std::mem::size_of::<PalettedStorage>()
+            + (self.opaque.capacity() + self.visible.capacity()) * std::mem::size_of::<ColumnMask>()
     }
 
     pub fn random() -> Self {
+        Self::random_from(&mut rand::thread_rng())
+    }
+
+    /// Like `random`, but reproducible for a seeded `rng`.
+    pub fn random_from(rng: &mut impl rand::Rng) -> Self {
         let mut chunk = Chunk::default();
 
         for i in 0..CHUNK_SIZE {
             for j in 0..CHUNK_SIZE {
                 for k in 0..CHUNK_SIZE {
-                    if rand::random::<u8>() < u8::MAX - 1 {
+                    if rng.gen::<u8>() < u8::MAX - 1 {
                         chunk.set_block(LocalBlockPos(i, j, k), Block(1));
                     }
                 }
@@ -84,15 +415,209 @@ impl Chunk {
 
     pub fn full() -> Self {
         let mut chunk = Chunk::default();
+        chunk.fill(Block(1));
 
-        for i in 0..CHUNK_SIZE {
-            for j in 0..CHUNK_SIZE {
-                for k in 0..CHUNK_SIZE {
-                    chunk.set_block(LocalBlockPos(i, j, k), Block(1));
-                }
+        chunk
+    }
+
+    /// Sets or clears the `mask` bits of a column depending on the block.
+    fn update_masks(&mut self, column: usize, mask: ColumnMask, b: Block) {
+        let set = |c: &mut ColumnMask, on: bool| {
+            if on {
+                *c |= mask;
+            } else {
+                *c &= !mask;
             }
-        }
+        };
+
+        set(&mut self.opaque[column], b.is_opaque());
+        set(&mut self.visible[column], b.is_visible());
+    }
+
+    fn index(pos: &LocalBlockPos) -> usize {
+        Self::column_index(pos.0, pos.1) * CHUNK_SIZE as usize + pos.2 as usize
+    }
+
+    fn column_index(x: ChunkDimTy, y: ChunkDimTy) -> usize {
+        (x * CHUNK_SIZE + y) as usize
+    }
+
+    /// Inverse of `index`.
+    fn position(i: usize) -> LocalBlockPos {
+        let size = CHUNK_SIZE as usize;
+        LocalBlockPos(
+            (i / (size * size)) as ChunkDimTy,
+            (i / size % size) as ChunkDimTy,
+            (i % size) as ChunkDimTy,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_sets_every_block() {
+        let mut chunk = Chunk::default();
+        chunk.fill(Block(2));
+
+        assert!(chunk.blocks().all(|b| b == Block(2)));
+        assert!(chunk.opaque_columns().iter().all(|c| *c == ColumnMask::MAX));
+        assert!(chunk
+            .visible_columns()
+            .iter()
+            .all(|c| *c == ColumnMask::MAX));
+
+        chunk.fill(Block(0));
+        assert!(chunk.opaque_columns().iter().all(|c| *c == 0));
+        assert!(chunk.visible_columns().iter().all(|c| *c == 0));
+    }
+
+    #[test]
+    fn fill_region_updates_column_masks() {
+        let mut chunk = Chunk::default();
+        chunk.fill_region(LocalBlockPos(1, 2, 3), LocalBlockPos(3, 4, 6), Block(1));
+
+        assert_eq!(chunk.opaque_column(1, 2), 0b111000);
+        assert_eq!(chunk.opaque_column(2, 3), 0b111000);
+        assert_eq!(chunk.opaque_column(3, 3), 0);
+        assert_eq!(chunk.get_block(&LocalBlockPos(2, 3, 5)), Block(1));
+        assert_eq!(chunk.get_block(&LocalBlockPos(2, 3, 6)), Block(0));
+
+        // clearing part of the region only clears those bits
+        chunk.fill_region(LocalBlockPos(1, 2, 4), LocalBlockPos(2, 3, 5), Block(0));
+        assert_eq!(chunk.opaque_column(1, 2), 0b101000);
+        assert_eq!(chunk.visible_column(1, 2), 0b101000);
+    }
+
+    #[test]
+    fn column_is_ordered_by_z() {
+        let mut chunk = Chunk::default();
+        chunk.set_block(LocalBlockPos(4, 5, 7), Block(3));
+
+        let column = chunk.column(4, 5);
+        assert_eq!(column.len(), CHUNK_SIZE as usize);
+        assert_eq!(column[7], Block(3));
+        assert!(column.iter().filter(|b| **b != Block(0)).count() == 1);
+
+        // leaves can be seen through, but are still visible
+        assert_eq!(chunk.opaque_column(4, 5), 0);
+        assert_eq!(chunk.visible_column(4, 5), 1 << 7);
+    }
+
+    #[test]
+    fn local_pos_constructors_check_bounds() {
+        assert!(LocalBlockPos::new(0, CHUNK_SIZE - 1, 3).is_ok());
+        assert_eq!(
+            LocalBlockPos::new(CHUNK_SIZE, 0, 0),
+            Err(ChunkError::OutOfBounds(LocalBlockPos(CHUNK_SIZE, 0, 0)))
+        );
+
+        assert_eq!(
+            LocalBlockPos::from_signed(1, 2, 3),
+            Some(LocalBlockPos(1, 2, 3))
+        );
+        assert_eq!(LocalBlockPos::from_signed(-1, 2, 3), None);
+        assert_eq!(LocalBlockPos::from_signed(1, CHUNK_SIZE as i32, 3), None);
+    }
+
+    #[test]
+    fn checked_access_rejects_out_of_bounds() {
+        let mut chunk = Chunk::default();
+        let outside = LocalBlockPos(CHUNK_SIZE + 8, 0, 0);
+
+        assert_eq!(
+            chunk.try_set_block(outside, Block(1)),
+            Err(ChunkError::OutOfBounds(outside))
+        );
+        assert_eq!(
+            chunk.try_get_block(&outside),
+            Err(ChunkError::OutOfBounds(outside))
+        );
 
         chunk
+            .try_set_block(LocalBlockPos(1, 1, 1), Block(1))
+            .unwrap();
+        assert_eq!(chunk.try_get_block(&LocalBlockPos(1, 1, 1)), Ok(Block(1)));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn unchecked_set_asserts_in_debug() {
+        // this would land inside the storage, so without the assertion it
+        // silently writes to the wrong block
+        let mut chunk = Chunk::default();
+        chunk.set_block(LocalBlockPos(0, CHUNK_SIZE + 8, 0), Block(1));
+    }
+
+    #[test]
+    fn uniform_chunks_use_less_memory() {
+        let full = Chunk::full();
+        let mut random = Chunk::random();
+
+        assert!(full.memory_usage() < random.memory_usage());
+
+        random.fill(Block(1));
+        random.compact();
+        assert_eq!(random.memory_usage(), full.memory_usage());
+    }
+
+    #[test]
+    fn iterators_visit_expected_blocks() {
+        let mut chunk = Chunk::default();
+        assert_eq!(chunk.iter_non_air().count(), 0);
+
+        chunk.set_block(LocalBlockPos(1, 2, 3), Block(2));
+        chunk.set_block(LocalBlockPos(CHUNK_SIZE - 1, 0, 5), Block(3));
+
+        let all: Vec<_> = chunk.iter().collect();
+        assert_eq!(all.len(), CHUNK_VOLUME);
+        assert!(all.iter().all(|(pos, b)| chunk.get_block(pos) == *b));
+
+        let solid: Vec<_> = chunk.iter_non_air().collect();
+        assert_eq!(
+            solid,
+            [
+                (LocalBlockPos(1, 2, 3), Block(2)),
+                (LocalBlockPos(CHUNK_SIZE - 1, 0, 5), Block(3))
+            ]
+        );
+
+        // the box is clamped to the chunk
+        let boxed: Vec<_> = chunk
+            .iter_box(LocalBlockPos(1, 1, 1), LocalBlockPos(3, 3, CHUNK_SIZE + 4))
+            .collect();
+        assert_eq!(boxed.len(), 2 * 2 * (CHUNK_SIZE as usize - 1));
+        assert_eq!(boxed.iter().filter(|(_, b)| *b == Block(2)).count(), 1);
+    }
+
+    #[test]
+    fn neighbors_stay_inside_the_chunk() {
+        let mut chunk = Chunk::default();
+        chunk.set_block(LocalBlockPos(1, 0, 0), Block(1));
+
+        let center = LocalBlockPos(5, 5, 5);
+        assert_eq!(center.face_neighbors().count(), 6);
+        assert_eq!(center.neighbors().count(), 26);
+
+        // a corner only has half of its neighbours in each direction
+        let corner = LocalBlockPos(0, 0, 0);
+        assert_eq!(corner.face_neighbors().count(), 3);
+        assert_eq!(corner.neighbors().count(), 7);
+
+        let solid: Vec<_> = chunk
+            .face_neighbors(&corner)
+            .filter(|(_, b)| *b == Block(1))
+            .collect();
+        assert_eq!(solid, [(LocalBlockPos(1, 0, 0), Block(1))]);
+        assert_eq!(
+            chunk
+                .neighbors(&LocalBlockPos(0, 1, 1))
+                .filter(|(_, b)| *b == Block(1))
+                .count(),
+            1
+        );
     }
 }
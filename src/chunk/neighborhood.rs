@@ -0,0 +1,127 @@
+use super::{
+    block::{Block, Facing},
+    Chunk, ChunkDimTy, LocalBlockPos, CHUNK_SIZE,
+};
+
+/// A chunk together with the chunks sharing a face with it, so blocks one past
+/// the edge of the chunk can be read without converting to world positions.
+/// Neighbours that aren't loaded are left out.
+#[derive(Clone, Copy)]
+pub struct ChunkNeighborhood<'a> {
+    center: &'a Chunk,
+    /// Indexed by `Facing`, starting at `Facing::North`.
+    neighbors: [Option<&'a Chunk>; 6],
+}
+
+impl<'a> ChunkNeighborhood<'a> {
+    /// A neighbourhood with none of the neighbours loaded.
+    pub fn new(center: &'a Chunk) -> Self {
+        Self {
+            center,
+            neighbors: [None; 6],
+        }
+    }
+
+    /// Sets the chunk next to the center in the `facing` direction.
+    pub fn with_neighbor(mut self, facing: Facing, chunk: &'a Chunk) -> Self {
+        self.neighbors[Self::slot(facing)] = Some(chunk);
+        self
+    }
+
+    pub fn center(&self) -> &'a Chunk {
+        self.center
+    }
+
+    pub fn neighbor(&self, facing: Facing) -> Option<&'a Chunk> {
+        self.neighbors[Self::slot(facing)]
+    }
+
+    /// Returns the block at a position relative to the center chunk. The
+    /// position can be past the edge of the center along one axis, reading
+    /// from that neighbour. Returns `None` if the neighbour isn't loaded, or
+    /// the position is past an edge or corner where no chunk is borrowed.
+    pub fn get_block(&self, x: i32, y: i32, z: i32) -> Option<Block> {
+        let size = CHUNK_SIZE as i32;
+        let outside = |v: i32| v.div_euclid(size);
+
+        let chunk = match (outside(x), outside(y), outside(z)) {
+            (0, 0, 0) => Some(self.center),
+            (-1, 0, 0) => self.neighbor(Facing::West),
+            (1, 0, 0) => self.neighbor(Facing::East),
+            (0, -1, 0) => self.neighbor(Facing::Down),
+            (0, 1, 0) => self.neighbor(Facing::Up),
+            (0, 0, -1) => self.neighbor(Facing::North),
+            (0, 0, 1) => self.neighbor(Facing::South),
+            _ => None,
+        }?;
+
+        let local = LocalBlockPos(
+            x.rem_euclid(size) as ChunkDimTy,
+            y.rem_euclid(size) as ChunkDimTy,
+            z.rem_euclid(size) as ChunkDimTy,
+        );
+        Some(chunk.get_block(&local))
+    }
+
+    /// The blocks sharing a face with a position in the center chunk, by the
+    /// direction they are in. Blocks in neighbours that aren't loaded are
+    /// `None`.
+    pub fn face_neighbors(
+        &self,
+        pos: &LocalBlockPos,
+    ) -> impl Iterator<Item = (Facing, Option<Block>)> + '_ {
+        let (x, y, z) = (pos.0 as i32, pos.1 as i32, pos.2 as i32);
+
+        Facing::ALL.into_iter().map(move |facing| {
+            let (dx, dy, dz) = facing.offset();
+            (facing, self.get_block(x + dx, y + dy, z + dz))
+        })
+    }
+
+    fn slot(facing: Facing) -> usize {
+        facing as usize - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_one_block_past_each_edge() {
+        let center = Chunk::default();
+        let mut east = Chunk::default();
+        east.set_block(LocalBlockPos(0, 3, 4), Block(2));
+        let mut down = Chunk::default();
+        down.set_block(LocalBlockPos(0, CHUNK_SIZE - 1, 0), Block(3));
+
+        let hood = ChunkNeighborhood::new(&center)
+            .with_neighbor(Facing::East, &east)
+            .with_neighbor(Facing::Down, &down);
+
+        let size = CHUNK_SIZE as i32;
+        assert_eq!(hood.get_block(size, 3, 4), Some(Block(2)));
+        assert_eq!(hood.get_block(0, -1, 0), Some(Block(3)));
+        assert_eq!(hood.get_block(0, 0, 0), Some(Block(0)));
+
+        // unloaded neighbours and diagonals can't be read
+        assert_eq!(hood.get_block(-1, 0, 0), None);
+        assert_eq!(hood.get_block(size, -1, 0), None);
+        assert_eq!(hood.get_block(2 * size, 0, 0), None);
+    }
+
+    #[test]
+    fn face_neighbors_cross_chunk_edges() {
+        let center = Chunk::default();
+        let mut north = Chunk::default();
+        north.set_block(LocalBlockPos(2, 2, CHUNK_SIZE - 1), Block(1));
+
+        let hood = ChunkNeighborhood::new(&center).with_neighbor(Facing::North, &north);
+        let neighbors: Vec<_> = hood.face_neighbors(&LocalBlockPos(2, 2, 0)).collect();
+
+        assert_eq!(neighbors.len(), 6);
+        assert!(neighbors.contains(&(Facing::North, Some(Block(1)))));
+        assert!(neighbors.contains(&(Facing::South, Some(Block(0)))));
+        assert!(neighbors.contains(&(Facing::West, Some(Block(0)))));
+    }
+}
@@ -0,0 +1,273 @@
+use super::block::Block;
+
+/// Index widths the storage can use. Widths divide 64 evenly so an index never
+/// straddles two words. A width of 0 means every entry is the single block in
+/// the palette, so no index data needs to be stored at all.
+const WIDTHS: [u32; 7] = [0, 1, 2, 4, 8, 16, 32];
+
+/// Palette compressed block storage.
+///
+/// Each entry is an index into a local palette of blocks, packed into `u64`s
+/// with as few bits as the palette length needs. Adding a new block type grows
+/// the index width, `compact` drops unused palette entries and shrinks it
+/// again.
+#[derive(Debug, Clone)]
+pub struct PalettedStorage {
+    len: usize,
+    palette: Vec<Block>,
+    bits: u32,
+    words: Vec<u64>,
+}
+
+impl PalettedStorage {
+    /// Creates storage for `len` entries, all set to `fill`.
+    pub fn new(len: usize, fill: Block) -> Self {
+        Self {
+            len,
+            palette: vec![fill],
+            bits: 0,
+            words: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> Block {
+        self.palette[self.get_index(i)]
+    }
+
+    pub fn set(&mut self, i: usize, b: Block) {
+        let index = match self.palette.iter().position(|p| *p == b) {
+            Some(index) => index,
+            None => {
+                // if the palette is full, the indices need to be wider
+                if self.bits < 32 && self.palette.len() >= 1 << self.bits {
+                    self.repack(Self::width_for(self.palette.len() + 1));
+                }
+                self.palette.push(b);
+                self.palette.len() - 1
+            }
+        };
+
+        self.set_index(i, index);
+    }
+
+    /// Sets every entry to `b`, resetting the palette.
+    pub fn fill(&mut self, b: Block) {
+        self.palette = vec![b];
+        self.bits = 0;
+        self.words = Vec::new();
+    }
+
+    /// Removes palette entries that are no longer used and shrinks the index
+    /// width to fit the remaining entries.
+    pub fn compact(&mut self) {
+        let mut counts = vec![0usize; self.palette.len()];
+        for i in 0..self.len {
+            counts[self.get_index(i)] += 1;
+        }
+
+        if counts.iter().all(|c| *c != 0) && self.bits == Self::width_for(self.palette.len()) {
+            return;
+        }
+
+        // map old palette indices to their new position
+        let mut remap = vec![0usize; self.palette.len()];
+        let mut palette = Vec::new();
+        for (old, count) in counts.iter().enumerate() {
+            if *count != 0 {
+                remap[old] = palette.len();
+                palette.push(self.palette[old]);
+            }
+        }
+
+        // an empty storage still needs something in the palette
+        if palette.is_empty() {
+            palette.push(self.palette[0]);
+        }
+
+        let bits = Self::width_for(palette.len());
+        let mut packed = Self {
+            len: self.len,
+            palette,
+            bits,
+            words: vec![0; Self::words_for(self.len, bits)],
+        };
+        if bits != 0 {
+            for i in 0..self.len {
+                packed.set_index(i, remap[self.get_index(i)]);
+            }
+        }
+
+        *self = packed;
+    }
+
+    /// The block every entry is set to, if the storage only holds one block.
+    /// Storage that has had other blocks in it is only known to be uniform
+    /// after it is compacted.
+    pub fn uniform(&self) -> Option<Block> {
+        if self.bits == 0 {
+            return Some(self.palette[0]);
+        }
+
+        None
+    }
+
+    /// The blocks that the indices refer to. May contain blocks that are no
+    /// longer used until the storage is compacted.
+    pub fn palette(&self) -> &[Block] {
+        &self.palette
+    }
+
+    /// Number of bits used to store each index.
+    pub fn bits_per_index(&self) -> u32 {
+        self.bits
+    }
+
+    /// Approximate number of bytes used by the storage, including the heap
+    /// allocations for the palette and the packed indices.
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.palette.capacity() * std::mem::size_of::<Block>()
+            + self.words.capacity() * std::mem::size_of::<u64>()
+    }
+
+    fn get_index(&self, i: usize) -> usize {
+        debug_assert!(i < self.len);
+        if self.bits == 0 {
+            return 0;
+        }
+
+        let per_word = 64 / self.bits as usize;
+        let shift = (i % per_word) * self.bits as usize;
+        ((self.words[i / per_word] >> shift) & self.mask()) as usize
+    }
+
+    fn set_index(&mut self, i: usize, index: usize) {
+        debug_assert!(i < self.len);
+        if self.bits == 0 {
+            return;
+        }
+
+        let per_word = 64 / self.bits as usize;
+        let shift = (i % per_word) * self.bits as usize;
+        let mask = self.mask();
+        let word = &mut self.words[i / per_word];
+        *word &= !(mask << shift);
+        *word |= (index as u64) << shift;
+    }
+
+    /// Rewrites the indices with a new width.
+    fn repack(&mut self, bits: u32) {
+        let indices: Vec<_> = (0..self.len).map(|i| self.get_index(i)).collect();
+
+        self.bits = bits;
+        self.words = vec![0; Self::words_for(self.len, bits)];
+        for (i, index) in indices.into_iter().enumerate() {
+            self.set_index(i, index);
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        (1u64 << self.bits) - 1
+    }
+
+    /// Smallest width that can index a palette of `len` entries.
+    fn width_for(len: usize) -> u32 {
+        *WIDTHS
+            .iter()
+            .find(|w| len <= 1 << **w)
+            .expect("Palette can't hold more than 2^32 entries")
+    }
+
+    fn words_for(len: usize, bits: u32) -> usize {
+        if bits == 0 {
+            return 0;
+        }
+
+        len.div_ceil(64 / bits as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uniform_storage_has_no_indices() {
+        let storage = PalettedStorage::new(4096, Block(5));
+
+        assert_eq!(storage.bits_per_index(), 0);
+        assert_eq!(storage.get(1234), Block(5));
+    }
+
+    #[test]
+    fn index_width_grows_with_palette() {
+        let mut storage = PalettedStorage::new(4096, Block(0));
+
+        storage.set(0, Block(1));
+        assert_eq!(storage.bits_per_index(), 1);
+
+        storage.set(1, Block(2));
+        assert_eq!(storage.bits_per_index(), 2);
+
+        for i in 3..20 {
+            storage.set(i as usize, Block(i));
+        }
+        assert_eq!(storage.bits_per_index(), 8);
+
+        // earlier values survive the repacking
+        assert_eq!(storage.get(0), Block(1));
+        assert_eq!(storage.get(1), Block(2));
+        assert_eq!(storage.get(2), Block(0));
+        assert_eq!(storage.get(19), Block(19));
+        assert_eq!(storage.get(20), Block(0));
+    }
+
+    #[test]
+    fn compact_shrinks_index_width() {
+        let mut storage = PalettedStorage::new(4096, Block(0));
+        for i in 0..10 {
+            storage.set(i, Block(i as u32 + 1));
+        }
+        assert_eq!(storage.bits_per_index(), 4);
+
+        // overwrite everything but two types
+        for i in 0..10 {
+            storage.set(i, Block(1));
+        }
+        storage.compact();
+
+        assert_eq!(storage.palette(), &[Block(0), Block(1)]);
+        assert_eq!(storage.bits_per_index(), 1);
+        assert_eq!(storage.get(9), Block(1));
+        assert_eq!(storage.get(10), Block(0));
+
+        for i in 0..10 {
+            storage.set(i, Block(0));
+        }
+        assert_eq!(storage.uniform(), None);
+        storage.compact();
+        assert_eq!(storage.bits_per_index(), 0);
+        assert_eq!(storage.uniform(), Some(Block(0)));
+        assert_eq!(storage.get(3), Block(0));
+    }
+
+    #[test]
+    fn memory_usage_follows_index_width() {
+        let mut storage = PalettedStorage::new(32768, Block(0));
+        let uniform = storage.memory_usage();
+
+        storage.set(0, Block(1));
+        let two_types = storage.memory_usage();
+
+        assert!(uniform < two_types);
+        assert!(two_types < 32768 * std::mem::size_of::<Block>());
+    }
+}
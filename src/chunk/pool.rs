@@ -2,16 +2,20 @@ use std::{borrow::Cow, collections::HashMap};
 
 use bytemuck::bytes_of;
 use wgpu::{
-    util::DrawIndirectArgs, BindGroup, BindGroupDescriptor, BindGroupEntry,
-    BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType,
-    BufferDescriptor, BufferUsages, Color, CommandEncoderDescriptor, Operations,
+    util::{BufferInitDescriptor, DeviceExt, DrawIndirectArgs},
+    BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayoutDescriptor,
+    BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType, BufferDescriptor, BufferUsages,
+    Color, CommandEncoderDescriptor, Operations, PipelineCompilationOptions,
     PipelineLayoutDescriptor, PolygonMode, RenderPassColorAttachment, RenderPassDescriptor,
     RenderPipeline, RenderPipelineDescriptor, ShaderStages, StoreOp,
 };
 
 use crate::{player::Player, util::allocator::Allocator, window_state::WindowState};
 
-use super::{mesher::mesh, traverse, visibility::VisibilityGraph, Chunk, ChunkPos, EncodedVertex};
+use super::{
+    mesher::mesh_with_neighbors, neighborhood::ChunkNeighborhood, registry::BlockRegistry,
+    traverse, visibility::VisibilityGraph, ChunkPos, EncodedVertex, CHUNK_SIZE, NUM_BITS_IN_POS,
+};
 
 pub struct ChunkDrawInfo {
     pub vertex_offset: u64,
@@ -22,6 +26,10 @@ pub struct ChunkDrawInfo {
 
     /// Used to traverse the world and identify which chunk/chunk faces need to be rendered
     pub vis_graph: VisibilityGraph,
+
+    /// Empty chunks have nothing to draw, so no buffer space is allocated for
+    /// them. They are still kept so that they can be traversed.
+    pub is_empty: bool,
 }
 
 /// Manages chunk vertex data. When we want to draw a chunk, we pass a list of
@@ -88,34 +96,65 @@ impl ChunkPool {
 
         let swapchain_format = state.surface.get_capabilities(&state.adapter).formats[0];
 
+        // the vertex encoding depends on the chunk size
+        let vertex_constants =
+            HashMap::from([("NUM_BITS_IN_POS".to_string(), NUM_BITS_IN_POS as f64)]);
+
         let vertex_buffer = Some(state.device.create_buffer(&desc_vertex));
         let uniform_buffer = Some(state.device.create_buffer(&desc_uniform));
         let storage_buffer = Some(state.device.create_buffer(&desc_storage));
         let indirect_buffer = Some(state.device.create_buffer(&desc_indirect));
 
+        // block colours, indexed by the block id encoded in each vertex. The
+        // bind group keeps the buffer alive
+        let colours = BlockRegistry::global().linear_colours();
+        let colour_buffer = state.device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Block colours"),
+            contents: bytemuck::cast_slice(colours.as_slice()),
+            usage: BufferUsages::STORAGE,
+        });
+
         let storage_bind_group_layout =
             state
                 .device
                 .create_bind_group_layout(&BindGroupLayoutDescriptor {
                     label: Some("Storage layout"),
-                    entries: &[BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: ShaderStages::VERTEX,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
+                    entries: &[
+                        BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: ShaderStages::VERTEX,
+                            ty: BindingType::Buffer {
+                                ty: BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
                         },
-                        count: None,
-                    }],
+                        BindGroupLayoutEntry {
+                            binding: 1,
+                            visibility: ShaderStages::FRAGMENT,
+                            ty: BindingType::Buffer {
+                                ty: BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                    ],
                 });
         let storage_bind_group = state.device.create_bind_group(&BindGroupDescriptor {
             label: Some("Storage bind group"),
             layout: &storage_bind_group_layout,
-            entries: &[BindGroupEntry {
-                binding: 0,
-                resource: storage_buffer.as_ref().unwrap().as_entire_binding(),
-            }],
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: storage_buffer.as_ref().unwrap().as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: colour_buffer.as_entire_binding(),
+                },
+            ],
         });
 
         let uniform_bind_group_layout =
@@ -171,7 +210,10 @@ impl ChunkPool {
                             format: wgpu::VertexFormat::Uint32,
                         }],
                     }],
-                    compilation_options: Default::default(),
+                    compilation_options: PipelineCompilationOptions {
+                        constants: &vertex_constants,
+                        ..Default::default()
+                    },
                 },
                 fragment: Some(wgpu::FragmentState {
                     module: &shader,
@@ -214,12 +256,41 @@ impl ChunkPool {
         ]
     }
 
-    /// Upload a chunk so that it can be rendered.
-    pub fn add_chunk(&mut self, state: &WindowState, chunk_pos: ChunkPos, chunk: Chunk) {
+    /// Upload the center chunk of `hood` so that it can be rendered. Its
+    /// faces against the loaded neighbours are culled.
+    pub fn add_chunk(
+        &mut self,
+        state: &WindowState,
+        chunk_pos: ChunkPos,
+        hood: &ChunkNeighborhood,
+    ) {
         log::debug!("ADDING CHUNK {:?}", chunk_pos);
         let vertex_size = std::mem::size_of::<EncodedVertex>() as u32;
+        let chunk = hood.center();
+
+        // chunks buried by their neighbours have nothing to draw either
+        let mesh = if chunk.is_empty() {
+            Default::default()
+        } else {
+            mesh_with_neighbors(hood)
+        };
+
+        if mesh.iter().all(|faces| faces.is_empty()) {
+            self.lookup.insert(
+                chunk_pos,
+                ChunkDrawInfo {
+                    vertex_offset: 0,
+                    storage_offset: 0,
+                    faces: [(0, 0); 6],
+                    vis_graph: VisibilityGraph::from_chunk(chunk),
+                    is_empty: true,
+                },
+            );
+
+            log::debug!("CHUNK IS EMPTY, NOTHING TO UPLOAD");
+            return;
+        }
 
-        let mesh = mesh(&chunk);
         let mesh_len = vertex_size
             * (mesh
                 .iter()
@@ -252,7 +323,13 @@ impl ChunkPool {
         );
 
         // We include an additional 0 so that we don't have to do any trickery trying to get the alignment correct
-        let pos = [32 * chunk_pos.0, 32 * chunk_pos.1, 32 * chunk_pos.2, 0];
+        let size = CHUNK_SIZE as i32;
+        let pos = [
+            size * chunk_pos.0,
+            size * chunk_pos.1,
+            size * chunk_pos.2,
+            0,
+        ];
         let pos_length = std::mem::size_of::<[i32; 4]>();
 
         let Some(storage_addr) = self.storage_allocator.alloc(pos_length as u64) else {
@@ -275,7 +352,7 @@ impl ChunkPool {
             bytemuck::bytes_of(&pos),
         );
 
-        let vis_graph = VisibilityGraph::from_chunk(&chunk);
+        let vis_graph = VisibilityGraph::from_chunk(chunk);
 
         // create the chunk info so that we can create indirect draw calls
         // from this
@@ -287,6 +364,7 @@ impl ChunkPool {
                 storage_offset: storage_addr / pos_length as u64,
                 faces,
                 vis_graph,
+                is_empty: false,
             },
         );
 
@@ -298,6 +376,10 @@ impl ChunkPool {
             return;
         };
 
+        if chunk_info.is_empty {
+            return;
+        }
+
         self.vertex_allocator.dealloc(chunk_info.vertex_offset);
         self.storage_allocator.dealloc(chunk_info.storage_offset);
     }
@@ -0,0 +1,476 @@
+//! Block definitions loaded from a plain-text file.
+//!
+//! Each non-empty line that isn't a `#` comment defines a block:
+//!
+//! ```text
+//! name    id  collidable  opaque  visible  colour   u     v    [tags...]
+//! dirt    1   true        true    true     #8b5a2b  0.0   0.0  natural
+//! leaves  3   true        false   true     #3a7d2c  0.25  0.0
+//! ```
+//!
+//! Opaque blocks hide the faces of their neighbours and block sight through
+//! the chunk, visible blocks have faces that get meshed, and collidable
+//! blocks can't be moved through.
+//!
+//! Ids have to start at 0 (air) and can't have gaps, as they are used to index
+//! the colour table in the shader.
+
+use std::{collections::HashMap, path::Path, sync::OnceLock};
+
+use super::{block::Block, MAX_BLOCK_ID};
+
+/// The definitions that ship with vvrs, used when no other registry has been
+/// installed.
+const DEFAULT_DEFINITIONS: &str = include_str!("../../assets/blocks.txt");
+
+static GLOBAL: OnceLock<BlockRegistry> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockDefinition {
+    pub name: String,
+    pub id: u32,
+    pub collidable: bool,
+    pub opaque: bool,
+    pub visible: bool,
+    /// sRGB colour with alpha.
+    pub colour: [u8; 4],
+    /// Offset into the texture atlas.
+    pub uv: (f32, f32),
+    /// Free-form tags used by simulation rules.
+    pub tags: Vec<String>,
+}
+
+impl BlockDefinition {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Io(std::io::Error),
+    /// A line is missing one of the required fields.
+    MissingField {
+        line: usize,
+        field: &'static str,
+    },
+    /// A field couldn't be parsed.
+    InvalidField {
+        line: usize,
+        field: &'static str,
+        value: String,
+    },
+    DuplicateId {
+        id: u32,
+        line: usize,
+    },
+    DuplicateName {
+        name: String,
+        line: usize,
+    },
+    /// No block is defined for this id, but a higher id is.
+    MissingId(u32),
+    /// The id doesn't fit in a block, or in the bits available in an encoded
+    /// vertex.
+    IdOutOfRange {
+        id: u32,
+        line: usize,
+    },
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::Io(e) => write!(f, "couldn't read block definitions: {}", e),
+            RegistryError::MissingField { line, field } => {
+                write!(f, "line {}: missing field `{}`", line, field)
+            }
+            RegistryError::InvalidField { line, field, value } => {
+                write!(f, "line {}: invalid {} `{}`", line, field, value)
+            }
+            RegistryError::DuplicateId { id, line } => {
+                write!(f, "line {}: block id {} is already defined", line, id)
+            }
+            RegistryError::DuplicateName { name, line } => {
+                write!(f, "line {}: block name `{}` is already defined", line, name)
+            }
+            RegistryError::MissingId(id) => write!(f, "no block is defined with id {}", id),
+            RegistryError::IdOutOfRange { id, line } => write!(
+                f,
+                "line {}: block id {} is too large (max {})",
+                line,
+                id,
+                MAX_BLOCK_ID - 1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl From<std::io::Error> for RegistryError {
+    fn from(e: std::io::Error) -> Self {
+        RegistryError::Io(e)
+    }
+}
+
+/// Lookup table for block properties, indexed by block id.
+#[derive(Debug, Clone)]
+pub struct BlockRegistry {
+    definitions: Vec<BlockDefinition>,
+    names: HashMap<String, u32>,
+}
+
+impl Default for BlockRegistry {
+    fn default() -> Self {
+        Self::parse(DEFAULT_DEFINITIONS).expect("Default block definitions should be valid")
+    }
+}
+
+impl BlockRegistry {
+    /// The registry used by `Block`, the default definitions unless another
+    /// registry was installed before the first use.
+    pub fn global() -> &'static BlockRegistry {
+        GLOBAL.get_or_init(BlockRegistry::default)
+    }
+
+    /// Makes this the global registry. Has to be called before any blocks are
+    /// used, otherwise the registry is handed back.
+    pub fn install(self) -> Result<(), BlockRegistry> {
+        GLOBAL.set(self)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RegistryError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, RegistryError> {
+        let mut by_id: HashMap<u32, BlockDefinition> = HashMap::new();
+        let mut names = HashMap::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let definition = parse_definition(line_number, line)?;
+
+            if definition.id >= MAX_BLOCK_ID {
+                return Err(RegistryError::IdOutOfRange {
+                    id: definition.id,
+                    line: line_number,
+                });
+            }
+            if by_id.contains_key(&definition.id) {
+                return Err(RegistryError::DuplicateId {
+                    id: definition.id,
+                    line: line_number,
+                });
+            }
+            if names.contains_key(&definition.name) {
+                return Err(RegistryError::DuplicateName {
+                    name: definition.name,
+                    line: line_number,
+                });
+            }
+
+            names.insert(definition.name.clone(), definition.id);
+            by_id.insert(definition.id, definition);
+        }
+
+        // ids have to be contiguous from 0
+        let mut definitions = Vec::with_capacity(by_id.len());
+        for id in 0..by_id.len() as u32 {
+            let Some(definition) = by_id.remove(&id) else {
+                return Err(RegistryError::MissingId(id));
+            };
+            definitions.push(definition);
+        }
+
+        Ok(Self { definitions, names })
+    }
+
+    pub fn get(&self, block: Block) -> Option<&BlockDefinition> {
+        self.definitions.get(block.id() as usize)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<Block> {
+        self.names.get(name).map(|id| Block::new(*id))
+    }
+
+    /// Unknown blocks aren't collidable.
+    pub fn is_collidable(&self, block: Block) -> bool {
+        self.get(block).is_some_and(|d| d.collidable)
+    }
+
+    /// Unknown blocks aren't opaque.
+    pub fn is_opaque(&self, block: Block) -> bool {
+        self.get(block).is_some_and(|d| d.opaque)
+    }
+
+    /// Unknown blocks aren't visible.
+    pub fn is_visible(&self, block: Block) -> bool {
+        self.get(block).is_some_and(|d| d.visible)
+    }
+
+    pub fn uv(&self, block: Block) -> (f32, f32) {
+        self.get(block).map_or((0.0, 0.0), |d| d.uv)
+    }
+
+    /// Blocks that have the tag.
+    pub fn with_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a BlockDefinition> {
+        self.definitions.iter().filter(move |d| d.has_tag(tag))
+    }
+
+    pub fn definitions(&self) -> &[BlockDefinition] {
+        &self.definitions
+    }
+
+    pub fn len(&self) -> usize {
+        self.definitions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.definitions.is_empty()
+    }
+
+    /// The definitions in the format `parse` reads, one line per block.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for d in &self.definitions {
+            let [r, g, b, a] = d.colour;
+            let colour = match a {
+                255 => format!("#{:02x}{:02x}{:02x}", r, g, b),
+                _ => format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a),
+            };
+
+            out.push_str(&format!(
+                "{} {} {} {} {} {} {} {}",
+                d.name, d.id, d.collidable, d.opaque, d.visible, colour, d.uv.0, d.uv.1
+            ));
+            for tag in &d.tags {
+                out.push(' ');
+                out.push_str(tag);
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Linear RGBA colours indexed by block id, for uploading to the shader.
+    pub fn linear_colours(&self) -> Vec<[f32; 4]> {
+        self.definitions
+            .iter()
+            .map(|d| {
+                let [r, g, b, a] = d.colour;
+                [
+                    srgb_to_linear(r),
+                    srgb_to_linear(g),
+                    srgb_to_linear(b),
+                    a as f32 / 255.0,
+                ]
+            })
+            .collect()
+    }
+}
+
+fn parse_definition(line: usize, source: &str) -> Result<BlockDefinition, RegistryError> {
+    let mut fields = source.split_whitespace();
+    let mut next = |field: &'static str| {
+        fields
+            .next()
+            .ok_or(RegistryError::MissingField { line, field })
+    };
+
+    let name = next("name")?.to_string();
+    let id = next("id")?;
+    let collidable = next("collidable")?;
+    let opaque = next("opaque")?;
+    let visible = next("visible")?;
+    let colour = next("colour")?;
+    let u = next("u")?;
+    let v = next("v")?;
+
+    let invalid = |field: &'static str, value: &str| RegistryError::InvalidField {
+        line,
+        field,
+        value: value.to_string(),
+    };
+
+    Ok(BlockDefinition {
+        name,
+        id: id.parse().map_err(|_| invalid("id", id))?,
+        collidable: collidable
+            .parse()
+            .map_err(|_| invalid("collidable", collidable))?,
+        opaque: opaque.parse().map_err(|_| invalid("opaque", opaque))?,
+        visible: visible.parse().map_err(|_| invalid("visible", visible))?,
+        colour: parse_colour(colour).ok_or_else(|| invalid("colour", colour))?,
+        uv: (
+            u.parse().map_err(|_| invalid("u", u))?,
+            v.parse().map_err(|_| invalid("v", v))?,
+        ),
+        tags: fields.map(str::to_string).collect(),
+    })
+}
+
+/// Parses `#rrggbb` or `#rrggbbaa`.
+fn parse_colour(s: &str) -> Option<[u8; 4]> {
+    let hex = s.strip_prefix('#')?;
+    if (hex.len() != 6 && hex.len() != 8) || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    let alpha = if hex.len() == 8 { channel(6)? } else { 255 };
+
+    Some([channel(0)?, channel(2)?, channel(4)?, alpha])
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFINITIONS: &str = "
+        # comment
+        air    0 false false false #000000 0.0 0.0
+        sand   1 true  true  true  #c2b280 0.5 0.25 granular falls
+
+        water  2 false false true  #1e90ff80 0.0 0.5 fluid
+    ";
+
+    #[test]
+    fn parses_definitions() {
+        let registry = BlockRegistry::parse(DEFINITIONS).unwrap();
+
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry.by_name("sand"), Some(Block(1)));
+        assert_eq!(registry.by_name("lava"), None);
+
+        let sand = registry.get(Block(1)).unwrap();
+        assert!(sand.collidable && sand.opaque && sand.visible);
+        assert_eq!(sand.colour, [0xc2, 0xb2, 0x80, 0xff]);
+        assert_eq!(sand.uv, (0.5, 0.25));
+        assert!(sand.has_tag("falls"));
+
+        let water = registry.get(Block(2)).unwrap();
+        assert_eq!(water.colour[3], 0x80);
+        assert!(!registry.is_collidable(Block(2)));
+        assert!(!registry.is_opaque(Block(2)));
+        assert!(registry.is_visible(Block(2)));
+
+        let fluids: Vec<_> = registry.with_tag("fluid").map(|d| d.id).collect();
+        assert_eq!(fluids, vec![2]);
+
+        // unknown blocks behave like air
+        assert!(!registry.is_visible(Block(40)));
+        assert_eq!(registry.uv(Block(40)), (0.0, 0.0));
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let registry = BlockRegistry::parse(DEFINITIONS).unwrap();
+        let copy = BlockRegistry::parse(&registry.to_text()).unwrap();
+        assert_eq!(copy.definitions(), registry.definitions());
+    }
+
+    #[test]
+    fn default_registry_matches_builtin_blocks() {
+        let registry = BlockRegistry::default();
+
+        assert_eq!(registry.by_name("air"), Some(Block(0)));
+        assert_eq!(registry.by_name("dirt"), Some(Block(1)));
+        assert!(registry.is_opaque(Block(2)));
+
+        // leaves can be seen through, but not walked through
+        assert!(!registry.is_opaque(Block(3)));
+        assert!(registry.is_visible(Block(3)));
+        assert!(registry.is_collidable(Block(3)));
+        assert_eq!(registry.uv(Block(3)), (0.25, 0.0));
+        assert_eq!(registry.linear_colours().len(), registry.len());
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        let result = BlockRegistry::parse(
+            "air 0 false false false #000000 0 0\n\
+             dirt 1 true true true #000000 0 0\n\
+             mud 1 true true true #000000 0 0",
+        );
+        assert!(matches!(
+            result,
+            Err(RegistryError::DuplicateId { id: 1, line: 3 })
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let result = BlockRegistry::parse(
+            "air 0 false false false #000000 0 0\n\
+             air 1 true true true #000000 0 0",
+        );
+        assert!(matches!(
+            result,
+            Err(RegistryError::DuplicateName { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_ids() {
+        let result = BlockRegistry::parse(
+            "air 0 false false false #000000 0 0\n\
+             stone 2 true true true #000000 0 0",
+        );
+        assert!(matches!(result, Err(RegistryError::MissingId(1))));
+
+        let result = BlockRegistry::parse("dirt 1 true true true #000000 0 0");
+        assert!(matches!(result, Err(RegistryError::MissingId(0))));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let result = BlockRegistry::parse("air 0 false false false #000000 0");
+        assert!(matches!(
+            result,
+            Err(RegistryError::MissingField {
+                line: 1,
+                field: "v"
+            })
+        ));
+
+        let result = BlockRegistry::parse("air 0 maybe false false #000000 0 0");
+        assert!(matches!(
+            result,
+            Err(RegistryError::InvalidField {
+                field: "collidable",
+                ..
+            })
+        ));
+
+        let result = BlockRegistry::parse("air 0 false false false 000000 0 0");
+        assert!(matches!(
+            result,
+            Err(RegistryError::InvalidField {
+                field: "colour",
+                ..
+            })
+        ));
+
+        let result = BlockRegistry::parse(&format!(
+            "air {} false false false #000000 0 0",
+            MAX_BLOCK_ID
+        ));
+        assert!(matches!(result, Err(RegistryError::IdOutOfRange { .. })));
+    }
+}
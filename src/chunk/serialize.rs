@@ -0,0 +1,378 @@
+//! Compact binary encoding of a single chunk.
+//!
+//! ```text
+//! version      u8
+//! chunk size   u8
+//! palette      varint length, then each block as a little-endian u32
+//! columns      for each (x, y) column in storage order:
+//!                varint run count, then (varint length, varint palette index)
+//!                for each run along z
+//!              a run count of 0 is followed by a varint n, and means this
+//!              column and the n - 1 after it are the same as the previous one
+//! ```
+//!
+//! Varints are unsigned LEB128. The blocks keep their state bits, so the
+//! palette holds whole blocks rather than just type ids.
+
+use std::collections::HashMap;
+
+use super::{block::Block, Chunk, ChunkDimTy, LocalBlockPos, CHUNK_SIZE, CHUNK_VOLUME};
+
+const VERSION: u8 = 1;
+
+/// The most bytes `Chunk::serialize` can write: every block in the palette,
+/// and every column with a run per block, with each varint at its longest.
+pub const MAX_SERIALIZED_LEN: usize =
+    2 + 5 + CHUNK_VOLUME * 4 + (CHUNK_SIZE * CHUNK_SIZE) as usize * 5 + CHUNK_VOLUME * 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeserializeError {
+    UnsupportedVersion(u8),
+    /// The data is for chunks of a different size.
+    ChunkSizeMismatch(u8),
+    /// The data ended before the chunk was complete.
+    UnexpectedEnd,
+    /// A varint doesn't fit in a u32.
+    InvalidVarint,
+    InvalidPaletteIndex(u32),
+    /// The runs of a column don't add up to the chunk size.
+    InvalidRuns {
+        column: usize,
+    },
+    /// The first column can't repeat the previous one, or the repeat goes past
+    /// the last column.
+    InvalidRepeat {
+        column: usize,
+    },
+    /// There is data left over after the last column.
+    TrailingData(usize),
+}
+
+impl std::fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeserializeError::UnsupportedVersion(v) => write!(
+                f,
+                "chunk format version {} isn't supported, only {} is",
+                v, VERSION
+            ),
+            DeserializeError::ChunkSizeMismatch(size) => write!(
+                f,
+                "chunk was saved with size {}, but chunks are size {}",
+                size, CHUNK_SIZE
+            ),
+            DeserializeError::UnexpectedEnd => write!(f, "chunk data ended early"),
+            DeserializeError::InvalidVarint => write!(f, "invalid varint in chunk data"),
+            DeserializeError::InvalidPaletteIndex(i) => {
+                write!(f, "palette index {} is out of range", i)
+            }
+            DeserializeError::InvalidRuns { column } => {
+                write!(f, "runs of column {} don't fill the column", column)
+            }
+            DeserializeError::InvalidRepeat { column } => {
+                write!(f, "invalid repeat at column {}", column)
+            }
+            DeserializeError::TrailingData(len) => {
+                write!(f, "{} bytes left over after the chunk data", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeserializeError {}
+
+impl Chunk {
+    /// Encodes the chunk, see the module docs for the format.
+    pub fn serialize(&self) -> Vec<u8> {
+        let columns = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+        let mut palette = Vec::<Block>::new();
+        let mut indices = HashMap::<Block, u32>::new();
+
+        // runs along z of each column, as (length, palette index)
+        let mut runs = Vec::with_capacity(columns);
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                let mut column_runs = Vec::<(u32, u32)>::new();
+                for b in self.column(x, y) {
+                    let index = *indices.entry(b).or_insert_with(|| {
+                        palette.push(b);
+                        palette.len() as u32 - 1
+                    });
+
+                    match column_runs.last_mut() {
+                        Some((len, i)) if *i == index => *len += 1,
+                        _ => column_runs.push((1, index)),
+                    }
+                }
+                runs.push(column_runs);
+            }
+        }
+
+        let mut out = vec![VERSION, CHUNK_SIZE as u8];
+        write_varint(&mut out, palette.len() as u32);
+        for b in &palette {
+            out.extend_from_slice(&b.0.to_le_bytes());
+        }
+
+        let mut column = 0;
+        while column < columns {
+            // count the columns repeating the one before
+            let repeats = match column.checked_sub(1) {
+                Some(previous) => runs[column..]
+                    .iter()
+                    .take_while(|r| **r == runs[previous])
+                    .count(),
+                None => 0,
+            };
+
+            if repeats > 0 {
+                write_varint(&mut out, 0);
+                write_varint(&mut out, repeats as u32);
+                column += repeats;
+                continue;
+            }
+
+            write_varint(&mut out, runs[column].len() as u32);
+            for (len, index) in &runs[column] {
+                write_varint(&mut out, *len);
+                write_varint(&mut out, *index);
+            }
+            column += 1;
+        }
+
+        out
+    }
+
+    /// Decodes a chunk written by `serialize`.
+    pub fn deserialize(data: &[u8]) -> Result<Chunk, DeserializeError> {
+        let mut reader = Reader { data, pos: 0 };
+
+        let version = reader.byte()?;
+        if version != VERSION {
+            return Err(DeserializeError::UnsupportedVersion(version));
+        }
+        let size = reader.byte()?;
+        if size as u32 != CHUNK_SIZE {
+            return Err(DeserializeError::ChunkSizeMismatch(size));
+        }
+
+        let palette_len = reader.varint()?;
+        let palette = (0..palette_len)
+            .map(|_| reader.u32().map(Block))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let columns = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+        let mut chunk = Chunk::default();
+        let mut previous = Vec::<(u32, Block)>::new();
+        let mut column = 0;
+
+        while column < columns {
+            let run_count = reader.varint()?;
+
+            let repeats = if run_count == 0 {
+                let repeats = reader.varint()? as usize;
+                if column == 0 || repeats == 0 || column + repeats > columns {
+                    return Err(DeserializeError::InvalidRepeat { column });
+                }
+                repeats
+            } else {
+                previous.clear();
+                let mut total = 0u32;
+                for _ in 0..run_count {
+                    let len = reader.varint()?;
+                    let index = reader.varint()?;
+                    let b = *palette
+                        .get(index as usize)
+                        .ok_or(DeserializeError::InvalidPaletteIndex(index))?;
+
+                    total = total.saturating_add(len);
+                    previous.push((len, b));
+                }
+                if total != CHUNK_SIZE || previous.iter().any(|(len, _)| *len == 0) {
+                    return Err(DeserializeError::InvalidRuns { column });
+                }
+                1
+            };
+
+            for c in column..column + repeats {
+                let x = (c / CHUNK_SIZE as usize) as ChunkDimTy;
+                let y = (c % CHUNK_SIZE as usize) as ChunkDimTy;
+
+                let mut z = 0;
+                for (len, b) in &previous {
+                    chunk.fill_region(
+                        LocalBlockPos(x, y, z),
+                        LocalBlockPos(x + 1, y + 1, z + len),
+                        *b,
+                    );
+                    z += len;
+                }
+            }
+            column += repeats;
+        }
+
+        if reader.pos != data.len() {
+            return Err(DeserializeError::TrailingData(data.len() - reader.pos));
+        }
+
+        chunk.compact();
+        Ok(chunk)
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u32) {
+    while v >= 0x80 {
+        out.push((v as u8 & 0x7f) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, DeserializeError> {
+        let b = *self
+            .data
+            .get(self.pos)
+            .ok_or(DeserializeError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn u32(&mut self) -> Result<u32, DeserializeError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + 4)
+            .ok_or(DeserializeError::UnexpectedEnd)?;
+        self.pos += 4;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn varint(&mut self) -> Result<u32, DeserializeError> {
+        let mut v = 0u32;
+        for shift in (0..35).step_by(7) {
+            let b = self.byte()?;
+            let bits = (b & 0x7f) as u32;
+            if shift == 28 && bits > 0xf {
+                return Err(DeserializeError::InvalidVarint);
+            }
+
+            v |= bits << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+
+        Err(DeserializeError::InvalidVarint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::block::Facing;
+
+    use super::*;
+
+    fn round_trip(chunk: &Chunk) -> Chunk {
+        let data = chunk.serialize();
+        let decoded = Chunk::deserialize(&data).unwrap();
+        assert!(decoded.blocks().eq(chunk.blocks()));
+        assert_eq!(decoded.opaque_columns(), chunk.opaque_columns());
+        assert_eq!(decoded.visible_columns(), chunk.visible_columns());
+        decoded
+    }
+
+    /// Stone at the bottom, a layer of dirt, then air.
+    fn terrain() -> Chunk {
+        let mut chunk = Chunk::default();
+        let size = CHUNK_SIZE;
+        chunk.fill_region(
+            LocalBlockPos(0, 0, 0),
+            LocalBlockPos(size, size, size / 2),
+            Block(2),
+        );
+        chunk.fill_region(
+            LocalBlockPos(0, 0, size / 2),
+            LocalBlockPos(size, size, size / 2 + 3),
+            Block(1),
+        );
+        chunk
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        round_trip(&Chunk::default());
+        round_trip(&Chunk::full());
+        round_trip(&Chunk::random());
+        round_trip(&terrain());
+
+        let mut chunk = terrain();
+        chunk.set_block(LocalBlockPos(0, 0, 0), Block(3));
+        chunk.set_block(
+            LocalBlockPos(CHUNK_SIZE - 1, CHUNK_SIZE - 1, CHUNK_SIZE - 1),
+            Block(3).with_facing(Some(Facing::Up)).with_level(5),
+        );
+        chunk.set_block(LocalBlockPos(4, 9, 1), Block(1000));
+        let decoded = round_trip(&chunk);
+        assert_eq!(decoded.palette().len(), 6);
+    }
+
+    #[test]
+    fn typical_chunks_are_small() {
+        assert!(Chunk::default().serialize().len() < 16);
+        assert!(terrain().serialize().len() < 32);
+
+        // columns that stick out only cost a few bytes each
+        let mut chunk = terrain();
+        for i in 0..CHUNK_SIZE {
+            chunk.set_block(LocalBlockPos(i, i, CHUNK_SIZE - 1), Block(3));
+        }
+        assert!(chunk.serialize().len() < 24 * CHUNK_SIZE as usize);
+    }
+
+    #[test]
+    fn rejects_bad_data() {
+        let data = terrain().serialize();
+
+        let mut wrong_version = data.clone();
+        wrong_version[0] = VERSION + 1;
+        assert_eq!(
+            Chunk::deserialize(&wrong_version).err(),
+            Some(DeserializeError::UnsupportedVersion(VERSION + 1))
+        );
+
+        let mut wrong_size = data.clone();
+        wrong_size[1] = 7;
+        assert_eq!(
+            Chunk::deserialize(&wrong_size).err(),
+            Some(DeserializeError::ChunkSizeMismatch(7))
+        );
+
+        assert_eq!(
+            Chunk::deserialize(&data[..data.len() - 1]).err(),
+            Some(DeserializeError::UnexpectedEnd)
+        );
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert_eq!(
+            Chunk::deserialize(&trailing).err(),
+            Some(DeserializeError::TrailingData(1))
+        );
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for v in [0, 1, 127, 128, 300, 1 << 21, u32::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, v);
+            let mut reader = Reader { data: &out, pos: 0 };
+            assert_eq!(reader.varint(), Ok(v));
+            assert_eq!(reader.pos, out.len());
+        }
+    }
+}
@@ -20,7 +20,7 @@ pub fn build_draw_list(
         let vertex_offset = x.vertex_offset;
         let storage_offset = x.storage_offset;
 
-        if is_chunk_inside_frustum(*pos, frustum_planes) {
+        if !x.is_empty && is_chunk_inside_frustum(*pos, frustum_planes) {
             // we are manually setting the all faces to be rendered
             for i in 0..6 {
                 let face_offset = x.faces[i].0;
@@ -65,8 +65,24 @@ impl VisibilityGraph {
         [false, false, false, false, false, true],
     ]);
 
-    // flood fill in this function
+    /// Every side can reach every other side.
+    pub const FULL_GRAPH: VisibilityGraph = VisibilityGraph([[true; 6]; 6]);
+
     pub fn from_chunk(chunk: &Chunk) -> Self {
+        // a chunk made of a single block can either be seen through from
+        // every side, or from none of them
+        if let Some(block) = chunk.uniform_block() {
+            if block.is_opaque() {
+                return VisibilityGraph::EMPTY_GRAPH;
+            }
+            return VisibilityGraph::FULL_GRAPH;
+        }
+
+        Self::flood_fill_chunk(chunk)
+    }
+
+    // flood fill in this function
+    fn flood_fill_chunk(chunk: &Chunk) -> Self {
         let mut connections = Vec::<(Side, Side)>::new();
         let depth = CHUNK_SIZE;
 
@@ -86,10 +102,8 @@ impl VisibilityGraph {
                         continue;
                     }
 
-                    let block = chunk.data.get(&LocalBlockPos(x, y, z));
-
                     // start at an empty block
-                    if block.is_none() || !block.expect("Block is not none!").is_solid() {
+                    if !chunk.is_opaque(&LocalBlockPos(x, y, z)) {
                         fill_seeds.insert(LocalBlockPos(x, y, z));
                     }
                 }
@@ -171,10 +185,9 @@ fn flood_fill(
             continue;
         }
 
-        // check if air
+        // check if it can be seen through
         // if not continue
-        let block = chunk.data.get(&pos);
-        if block.is_some() && block.expect("Block is some.").is_solid() {
+        if chunk.is_opaque(&pos) {
             continue;
         }
 
@@ -232,6 +245,18 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn uniform_graph_matches_flood_fill() {
+        for block in [Block(0), Block(1), Block(3)] {
+            let mut chunk = Chunk::default();
+            chunk.fill(block);
+
+            let fast = VisibilityGraph::from_chunk(&chunk);
+            let slow = VisibilityGraph::flood_fill_chunk(&chunk);
+            assert_eq!(fast.0, slow.0, "{:?}", block);
+        }
+    }
+
     #[test]
     fn visibility_graph_full_chunk() {
         let full_chunk = Chunk::full();
@@ -300,6 +325,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn visibility_graph_sees_through_leaves() {
+        let mut split_chunk = Chunk::default();
+
+        // a wall of leaves doesn't block sight
+        split_chunk.fill_region(
+            LocalBlockPos(3, 0, 0),
+            LocalBlockPos(4, CHUNK_SIZE, CHUNK_SIZE),
+            Block(3),
+        );
+
+        let vis_graph = VisibilityGraph::from_chunk(&split_chunk);
+        assert!(vis_graph.can_reach_from(Side::RIGHT, Side::LEFT));
+    }
+
     #[test]
     fn visibility_graph_split_y_chunk() {
         let mut split_chunk = Chunk::default();
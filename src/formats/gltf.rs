@@ -0,0 +1,472 @@
+//! Exporting chunk meshes to glTF 2.0, either as a `.gltf` with a separate
+//! `.bin` buffer or as a single `.glb`.
+//!
+//! Each chunk is a node translated to the chunk's position, with a mesh in
+//! chunk-local coordinates. The mesh has a primitive for each block type, so
+//! that each can use the material for its block. Vertices are only shared
+//! between faces pointing the same way, so every face keeps its own normal.
+
+use std::{collections::HashMap, path::Path};
+
+use serde_json::{json, Value};
+
+use crate::{
+    chunk::{
+        block::Block, mesher::mesh_with_neighbors, registry::BlockRegistry, ChunkPos, CHUNK_SIZE,
+    },
+    world::World,
+};
+
+use super::mesh::{decode_mesh, Triangle};
+
+const GLB_MAGIC: &[u8; 4] = b"glTF";
+const GLB_JSON: u32 = 0x4E4F534A;
+const GLB_BIN: u32 = 0x004E4942;
+
+// accessor component types and buffer view targets
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+/// A glTF document and its binary buffer.
+pub struct GltfExport {
+    json: Value,
+    bin: Vec<u8>,
+}
+
+impl GltfExport {
+    /// Meshes every loaded chunk of the world. Chunks with nothing to draw are
+    /// left out.
+    pub fn from_world(world: &World, registry: &BlockRegistry) -> Self {
+        let mut chunks: Vec<_> = world.chunk_positions().collect();
+        chunks.sort();
+
+        let mut builder = Builder::default();
+        for pos in chunks {
+            let Some(hood) = world.neighborhood(pos) else {
+                continue;
+            };
+            // decoded at the origin, the node moves it into place
+            let triangles = decode_mesh(ChunkPos(0, 0, 0), &mesh_with_neighbors(&hood));
+            if !triangles.is_empty() {
+                builder.add_chunk(*pos, &triangles);
+            }
+        }
+
+        builder.finish(registry)
+    }
+
+    /// The glTF JSON, with the buffer at `uri`, or without a uri for `.glb`.
+    pub fn document(&self, uri: Option<&str>) -> Value {
+        let mut json = self.json.clone();
+        if let Some(uri) = uri {
+            json["buffers"][0]["uri"] = json!(uri);
+        }
+        json
+    }
+
+    pub fn buffer(&self) -> &[u8] {
+        &self.bin
+    }
+
+    /// Writes `path` as a `.gltf` file, with the buffer in a `.bin` file next
+    /// to it.
+    pub fn save_gltf(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let bin_path = path.with_extension("bin");
+        let uri = bin_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("buffer.bin");
+
+        std::fs::write(path, self.document(Some(uri)).to_string())?;
+        std::fs::write(bin_path, &self.bin)
+    }
+
+    pub fn save_glb(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_glb())
+    }
+
+    /// Packs the document and buffer into a single binary file.
+    pub fn to_glb(&self) -> Vec<u8> {
+        // both chunks have to be padded to 4 bytes, json with spaces
+        let mut json = self.document(None).to_string().into_bytes();
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let mut bin = self.bin.clone();
+        bin.resize(bin.len().next_multiple_of(4), 0);
+
+        let total = 12 + 8 + json.len() + 8 + bin.len();
+        let mut out = Vec::with_capacity(total);
+        out.extend_from_slice(GLB_MAGIC);
+        out.extend_from_slice(&2u32.to_le_bytes());
+        out.extend_from_slice(&(total as u32).to_le_bytes());
+
+        out.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        out.extend_from_slice(&GLB_JSON.to_le_bytes());
+        out.extend_from_slice(&json);
+
+        out.extend_from_slice(&(bin.len() as u32).to_le_bytes());
+        out.extend_from_slice(&GLB_BIN.to_le_bytes());
+        out.extend_from_slice(&bin);
+
+        out
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    bin: Vec<u8>,
+    views: Vec<Value>,
+    accessors: Vec<Value>,
+    meshes: Vec<Value>,
+    nodes: Vec<Value>,
+    /// Material index of each block type, in the order they were first used.
+    materials: Vec<u32>,
+}
+
+impl Builder {
+    fn add_chunk(&mut self, pos: ChunkPos, triangles: &[Triangle]) {
+        let mut by_block = HashMap::<u32, Vec<&Triangle>>::new();
+        for triangle in triangles {
+            by_block
+                .entry(triangle.block_id)
+                .or_default()
+                .push(triangle);
+        }
+        let mut by_block: Vec<_> = by_block.into_iter().collect();
+        by_block.sort_by_key(|(id, _)| *id);
+
+        let mut primitives = Vec::new();
+        for (id, triangles) in by_block {
+            // vertices are shared between triangles facing the same way
+            let mut lookup = HashMap::<([i32; 3], usize), u32>::new();
+            let mut positions = Vec::<[f32; 3]>::new();
+            let mut normals = Vec::<[f32; 3]>::new();
+            let mut indices = Vec::<u32>::new();
+
+            for triangle in triangles {
+                for v in triangle.vertices {
+                    let index = *lookup.entry((v, triangle.axis)).or_insert_with(|| {
+                        positions.push(v.map(|c| c as f32));
+                        normals.push(triangle.normal());
+                        positions.len() as u32 - 1
+                    });
+                    indices.push(index);
+                }
+            }
+
+            let (min, max) = bounds(&positions);
+            let position = self.add_accessor(
+                bytemuck::cast_slice(&positions),
+                ARRAY_BUFFER,
+                json!({
+                    "componentType": FLOAT,
+                    "count": positions.len(),
+                    "type": "VEC3",
+                    "min": min,
+                    "max": max,
+                }),
+            );
+            let normal = self.add_accessor(
+                bytemuck::cast_slice(&normals),
+                ARRAY_BUFFER,
+                json!({
+                    "componentType": FLOAT,
+                    "count": normals.len(),
+                    "type": "VEC3",
+                }),
+            );
+            let index = self.add_accessor(
+                bytemuck::cast_slice(&indices),
+                ELEMENT_ARRAY_BUFFER,
+                json!({
+                    "componentType": UNSIGNED_INT,
+                    "count": indices.len(),
+                    "type": "SCALAR",
+                }),
+            );
+
+            primitives.push(json!({
+                "attributes": { "POSITION": position, "NORMAL": normal },
+                "indices": index,
+                "material": self.material(id),
+            }));
+        }
+
+        let size = CHUNK_SIZE as i32;
+        let name = format!("chunk {} {} {}", pos.0, pos.1, pos.2);
+        self.nodes.push(json!({
+            "name": name,
+            "mesh": self.meshes.len(),
+            "translation": [pos.0 * size, pos.1 * size, pos.2 * size],
+        }));
+        self.meshes
+            .push(json!({ "name": name, "primitives": primitives }));
+    }
+
+    /// Adds the data to the buffer with its own view, returning the index of
+    /// the accessor.
+    fn add_accessor(&mut self, data: &[u8], target: u32, mut accessor: Value) -> usize {
+        // every component is 4 bytes, so keep the views aligned to that
+        self.bin.resize(self.bin.len().next_multiple_of(4), 0);
+
+        accessor["bufferView"] = json!(self.views.len());
+        self.views.push(json!({
+            "buffer": 0,
+            "byteOffset": self.bin.len(),
+            "byteLength": data.len(),
+            "target": target,
+        }));
+        self.bin.extend_from_slice(data);
+
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    fn material(&mut self, id: u32) -> usize {
+        match self.materials.iter().position(|m| *m == id) {
+            Some(index) => index,
+            None => {
+                self.materials.push(id);
+                self.materials.len() - 1
+            }
+        }
+    }
+
+    fn finish(self, registry: &BlockRegistry) -> GltfExport {
+        let linear = registry.linear_colours();
+        let materials: Vec<_> = self
+            .materials
+            .iter()
+            .map(|id| {
+                let definition = registry.get(Block::new(*id));
+                let name = definition.map_or_else(|| format!("block_{}", id), |d| d.name.clone());
+                let colour = linear
+                    .get(*id as usize)
+                    .copied()
+                    .unwrap_or([1.0, 0.0, 1.0, 1.0]);
+                let blend = if colour[3] < 1.0 { "BLEND" } else { "OPAQUE" };
+
+                json!({
+                    "name": name,
+                    "pbrMetallicRoughness": {
+                        "baseColorFactor": colour,
+                        "metallicFactor": 0.0,
+                        "roughnessFactor": 1.0,
+                    },
+                    "alphaMode": blend,
+                })
+            })
+            .collect();
+
+        let json = json!({
+            "asset": { "version": "2.0", "generator": "vvrs" },
+            "scene": 0,
+            "scenes": [{ "nodes": (0..self.nodes.len()).collect::<Vec<_>>() }],
+            "nodes": self.nodes,
+            "meshes": self.meshes,
+            "materials": materials,
+            "accessors": self.accessors,
+            "bufferViews": self.views,
+            "buffers": [{ "byteLength": self.bin.len() }],
+        });
+
+        GltfExport {
+            json,
+            bin: self.bin,
+        }
+    }
+}
+
+fn bounds(positions: &[[f32; 3]]) -> ([f32; 3], [f32; 3]) {
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for p in positions {
+        for i in 0..3 {
+            min[i] = min[i].min(p[i]);
+            max[i] = max[i].max(p[i]);
+        }
+    }
+
+    (min, max)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::world::WorldBlockPos;
+
+    use super::*;
+
+    fn sample_world() -> World {
+        let mut world = World::default();
+        world.fill_box(WorldBlockPos(0, 0, 0), WorldBlockPos(3, 1, 1), Block(2));
+        world.set_block(WorldBlockPos(-1, 5, 0), Block(3));
+        // an empty chunk isn't exported
+        world.set_block(WorldBlockPos(100, 0, 0), Block(0));
+        world
+    }
+
+    fn floats(bin: &[u8], view: &Value) -> Vec<f32> {
+        let offset = view["byteOffset"].as_u64().unwrap() as usize;
+        let len = view["byteLength"].as_u64().unwrap() as usize;
+        bin[offset..offset + len]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    fn u32s(bin: &[u8], view: &Value) -> Vec<u32> {
+        let offset = view["byteOffset"].as_u64().unwrap() as usize;
+        let len = view["byteLength"].as_u64().unwrap() as usize;
+        bin[offset..offset + len]
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    /// Checks the document against its buffer, returning the world positions
+    /// of every triangle with its normal.
+    fn read_back(json: &Value, bin: &[u8]) -> Vec<([[f32; 3]; 3], [f32; 3])> {
+        assert_eq!(json["asset"]["version"], "2.0");
+        assert_eq!(
+            json["buffers"][0]["byteLength"].as_u64().unwrap() as usize,
+            bin.len()
+        );
+
+        let accessors = json["accessors"].as_array().unwrap();
+        let views = json["bufferViews"].as_array().unwrap();
+        let view_of = |accessor: &Value| &views[accessor["bufferView"].as_u64().unwrap() as usize];
+
+        let mut triangles = Vec::new();
+        for node in json["nodes"].as_array().unwrap() {
+            let t: Vec<f32> = node["translation"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v.as_f64().unwrap() as f32)
+                .collect();
+            let mesh = &json["meshes"][node["mesh"].as_u64().unwrap() as usize];
+
+            for primitive in mesh["primitives"].as_array().unwrap() {
+                let position =
+                    &accessors[primitive["attributes"]["POSITION"].as_u64().unwrap() as usize];
+                let normal =
+                    &accessors[primitive["attributes"]["NORMAL"].as_u64().unwrap() as usize];
+                let index = &accessors[primitive["indices"].as_u64().unwrap() as usize];
+                assert!(
+                    primitive["material"].as_u64().unwrap()
+                        < json["materials"].as_array().unwrap().len() as u64
+                );
+
+                let positions = floats(bin, view_of(position));
+                let normals = floats(bin, view_of(normal));
+                let indices = u32s(bin, view_of(index));
+                assert_eq!(
+                    positions.len(),
+                    position["count"].as_u64().unwrap() as usize * 3
+                );
+                assert_eq!(indices.len(), index["count"].as_u64().unwrap() as usize);
+
+                // the bounds have to match the data
+                for i in 0..3 {
+                    let axis = positions.iter().skip(i).step_by(3);
+                    let min = axis.clone().cloned().fold(f32::MAX, f32::min);
+                    let max = axis.cloned().fold(f32::MIN, f32::max);
+                    assert_eq!(position["min"][i].as_f64().unwrap() as f32, min);
+                    assert_eq!(position["max"][i].as_f64().unwrap() as f32, max);
+                }
+
+                for tri in indices.chunks_exact(3) {
+                    let vertex = |i: u32| {
+                        let i = i as usize * 3;
+                        [
+                            positions[i] + t[0],
+                            positions[i + 1] + t[1],
+                            positions[i + 2] + t[2],
+                        ]
+                    };
+                    let n = tri[0] as usize * 3;
+                    triangles.push((
+                        [vertex(tri[0]), vertex(tri[1]), vertex(tri[2])],
+                        [normals[n], normals[n + 1], normals[n + 2]],
+                    ));
+                }
+            }
+        }
+
+        triangles
+    }
+
+    #[test]
+    fn gltf_reads_back_with_world_positions() {
+        let world = sample_world();
+        let export = GltfExport::from_world(&world, BlockRegistry::global());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scene.gltf");
+        export.save_gltf(&path).unwrap();
+
+        let json: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["buffers"][0]["uri"], "scene.bin");
+        let bin = std::fs::read(dir.path().join("scene.bin")).unwrap();
+
+        // one node per chunk with something in it
+        let nodes = json["nodes"].as_array().unwrap();
+        assert_eq!(nodes.len(), 2);
+        let size = CHUNK_SIZE as i64;
+        assert_eq!(nodes[0]["translation"], json!([-size, 0, 0]));
+
+        let names: Vec<_> = json["materials"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["leaves", "stone"]);
+
+        let triangles = read_back(&json, &bin);
+        // the merged 3x1x1 box has 6 quads, the single block another 6
+        assert_eq!(triangles.len(), 24);
+
+        // every face is on the outside of its box, facing away from it
+        for (vertices, normal) in &triangles {
+            let inside_leaves = vertices
+                .iter()
+                .all(|v| v[0] >= -1.0 && v[0] <= 0.0 && v[1] >= 5.0);
+            let centre = if inside_leaves {
+                [-0.5, 5.5, 0.5]
+            } else {
+                [1.5, 0.5, 0.5]
+            };
+
+            let v = vertices[0];
+            let out: f32 = (0..3).map(|i| (v[i] - centre[i]) * normal[i]).sum();
+            assert!(out > 0.0, "{:?} {:?}", vertices, normal);
+        }
+    }
+
+    #[test]
+    fn glb_contains_the_same_document() {
+        let export = GltfExport::from_world(&sample_world(), BlockRegistry::global());
+        let glb = export.to_glb();
+
+        let word = |at: usize| u32::from_le_bytes([glb[at], glb[at + 1], glb[at + 2], glb[at + 3]]);
+        assert_eq!(&glb[0..4], GLB_MAGIC);
+        assert_eq!(word(4), 2);
+        assert_eq!(word(8) as usize, glb.len());
+
+        let json_len = word(12) as usize;
+        assert_eq!(word(16), GLB_JSON);
+        assert_eq!(json_len % 4, 0);
+        let json: Value = serde_json::from_slice(&glb[20..20 + json_len]).unwrap();
+        assert!(json["buffers"][0].get("uri").is_none());
+
+        let bin_start = 20 + json_len;
+        assert_eq!(word(bin_start + 4), GLB_BIN);
+        let bin_len = json["buffers"][0]["byteLength"].as_u64().unwrap() as usize;
+        let bin = &glb[bin_start + 8..bin_start + 8 + bin_len];
+
+        assert_eq!(read_back(&json, bin).len(), 24);
+    }
+}
@@ -0,0 +1,515 @@
+//! Terrain from grayscale heightmaps.
+//!
+//! Binary and plain PGM files and grayscale PNGs (1 to 16 bits per sample)
+//! are supported. Image x runs along world x and image rows run along world
+//! z, brighter samples are higher.
+
+use std::{fs, path::Path};
+
+use crate::{
+    chunk::{block::Block, Chunk, ChunkDimTy, ChunkPos, LocalBlockPos, CHUNK_SIZE},
+    world::{generator::ChunkGenerator, WorldBlockPos},
+};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(std::io::Error),
+    Png(png::DecodingError),
+    /// The data isn't a PGM or PNG image.
+    UnknownFormat,
+    /// The PGM header is missing a field or has an invalid one.
+    InvalidHeader,
+    /// The PNG has colour channels or a palette.
+    NotGrayscale,
+    /// The data ended before every sample was read.
+    UnexpectedEnd,
+    /// A PGM sample isn't a number, or is above the maximum value.
+    InvalidSample {
+        index: usize,
+    },
+}
+
+impl std::fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeightmapError::Io(e) => write!(f, "couldn't read heightmap: {}", e),
+            HeightmapError::Png(e) => write!(f, "couldn't decode PNG: {}", e),
+            HeightmapError::UnknownFormat => write!(f, "heightmap isn't a PGM or PNG image"),
+            HeightmapError::InvalidHeader => write!(f, "invalid PGM header"),
+            HeightmapError::NotGrayscale => write!(f, "heightmap isn't a grayscale image"),
+            HeightmapError::UnexpectedEnd => write!(f, "heightmap data ended early"),
+            HeightmapError::InvalidSample { index } => {
+                write!(f, "sample {} of the heightmap is invalid", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeightmapError {}
+
+impl From<std::io::Error> for HeightmapError {
+    fn from(e: std::io::Error) -> Self {
+        HeightmapError::Io(e)
+    }
+}
+
+impl From<png::DecodingError> for HeightmapError {
+    fn from(e: png::DecodingError) -> Self {
+        HeightmapError::Png(e)
+    }
+}
+
+/// Grayscale samples, row by row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heightmap {
+    width: u32,
+    depth: u32,
+    /// The value of a white sample.
+    max_value: u16,
+    samples: Vec<u16>,
+}
+
+impl Heightmap {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, HeightmapError> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// Reads a PGM or PNG image, depending on its signature.
+    pub fn parse(data: &[u8]) -> Result<Self, HeightmapError> {
+        if data.starts_with(PNG_SIGNATURE) {
+            Self::parse_png(data)
+        } else if data.starts_with(b"P5") || data.starts_with(b"P2") {
+            Self::parse_pgm(data)
+        } else {
+            Err(HeightmapError::UnknownFormat)
+        }
+    }
+
+    /// Reads a binary (`P5`) or plain (`P2`) PGM. Only the first image of a
+    /// file with several is read.
+    pub fn parse_pgm(data: &[u8]) -> Result<Self, HeightmapError> {
+        let mut pos = 0;
+        let magic = pgm_token(data, &mut pos).ok_or(HeightmapError::UnknownFormat)?;
+        let plain = match magic {
+            b"P5" => false,
+            b"P2" => true,
+            _ => return Err(HeightmapError::UnknownFormat),
+        };
+
+        let mut field = || {
+            pgm_token(data, &mut pos)
+                .and_then(parse_number)
+                .ok_or(HeightmapError::InvalidHeader)
+        };
+        let width = field()?;
+        let depth = field()?;
+        let max_value = field()?;
+        if width == 0 || depth == 0 || max_value == 0 || max_value > u16::MAX as u32 {
+            return Err(HeightmapError::InvalidHeader);
+        }
+        let max_value = max_value as u16;
+        let len = (width as usize)
+            .checked_mul(depth as usize)
+            .ok_or(HeightmapError::InvalidHeader)?;
+
+        let samples = if plain {
+            (0..len)
+                .map(|index| {
+                    let token = pgm_token(data, &mut pos).ok_or(HeightmapError::UnexpectedEnd)?;
+                    parse_number(token)
+                        .filter(|v| *v <= max_value as u32)
+                        .map(|v| v as u16)
+                        .ok_or(HeightmapError::InvalidSample { index })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            // a single whitespace byte separates the header from the samples
+            let start = pos + 1;
+            let bytes_per_sample = if max_value > u8::MAX as u16 { 2 } else { 1 };
+            let end = len
+                .checked_mul(bytes_per_sample)
+                .and_then(|n| n.checked_add(start))
+                .ok_or(HeightmapError::InvalidHeader)?;
+            let raw = data.get(start..end).ok_or(HeightmapError::UnexpectedEnd)?;
+
+            let samples: Vec<u16> = if bytes_per_sample == 2 {
+                raw.chunks_exact(2)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                    .collect()
+            } else {
+                raw.iter().map(|b| *b as u16).collect()
+            };
+            if let Some(index) = samples.iter().position(|v| *v > max_value) {
+                return Err(HeightmapError::InvalidSample { index });
+            }
+            samples
+        };
+
+        Ok(Self {
+            width,
+            depth,
+            max_value,
+            samples,
+        })
+    }
+
+    /// Reads a grayscale PNG, with or without alpha. The alpha channel is
+    /// ignored, and samples with fewer than 8 bits are scaled up to 8.
+    pub fn parse_png(data: &[u8]) -> Result<Self, HeightmapError> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info()?;
+
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf)?;
+
+        let channels = match info.color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            _ => return Err(HeightmapError::NotGrayscale),
+        };
+        let (bytes_per_sample, max_value) = match info.bit_depth {
+            png::BitDepth::Sixteen => (2, u16::MAX),
+            _ => (1, u8::MAX as u16),
+        };
+
+        let samples = buf[..info.buffer_size()]
+            .chunks_exact(info.line_size)
+            .flat_map(|line| line.chunks_exact(channels * bytes_per_sample))
+            .map(|pixel| match bytes_per_sample {
+                2 => u16::from_be_bytes([pixel[0], pixel[1]]),
+                _ => pixel[0] as u16,
+            })
+            .collect();
+
+        Ok(Self {
+            width: info.width,
+            depth: info.height,
+            max_value,
+            samples,
+        })
+    }
+
+    /// Number of samples along x.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Number of samples along z, the image height.
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn max_value(&self) -> u16 {
+        self.max_value
+    }
+
+    pub fn sample(&self, x: u32, z: u32) -> Option<u16> {
+        if x >= self.width || z >= self.depth {
+            return None;
+        }
+        Some(self.samples[z as usize * self.width as usize + x as usize])
+    }
+
+    /// The sample scaled to between 0 and 1.
+    pub fn normalized(&self, x: u32, z: u32) -> Option<f32> {
+        self.sample(x, z).map(|v| v as f32 / self.max_value as f32)
+    }
+}
+
+/// The blocks a column is made of, from the surface down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layers {
+    /// The highest block of each column.
+    pub top: Block,
+    /// The blocks under the top one.
+    pub sub_surface: Block,
+    /// How many sub-surface blocks there are under the top one.
+    pub sub_surface_depth: u32,
+    /// Everything below the sub-surface blocks.
+    pub deep: Block,
+}
+
+impl Default for Layers {
+    /// Dirt over stone.
+    fn default() -> Self {
+        Self {
+            top: Block(1),
+            sub_surface: Block(1),
+            sub_surface_depth: 3,
+            deep: Block(2),
+        }
+    }
+}
+
+/// Fills the columns of chunks up to the height of the heightmap. Chunks
+/// outside the heightmap are empty.
+#[derive(Debug, Clone)]
+pub struct HeightmapGenerator {
+    map: Heightmap,
+    /// Where the bottom of the first sample's column is.
+    origin: WorldBlockPos,
+    /// Height in blocks of a white sample above a black one.
+    scale: f32,
+    layers: Layers,
+}
+
+impl HeightmapGenerator {
+    pub fn new(map: Heightmap) -> Self {
+        Self {
+            map,
+            origin: WorldBlockPos(0, 0, 0),
+            scale: 64.0,
+            layers: Layers::default(),
+        }
+    }
+
+    pub fn with_origin(mut self, origin: WorldBlockPos) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_layers(mut self, layers: Layers) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    pub fn map(&self) -> &Heightmap {
+        &self.map
+    }
+
+    /// The y of the top block of the column at world `x` and `z`, if the
+    /// heightmap covers it. Black samples are one block thick.
+    pub fn surface(&self, x: i32, z: i32) -> Option<i32> {
+        let map_x = u32::try_from(x - self.origin.0).ok()?;
+        let map_z = u32::try_from(z - self.origin.2).ok()?;
+        let height = self.map.normalized(map_x, map_z)? * self.scale;
+        Some(self.origin.1 + height.round() as i32)
+    }
+}
+
+impl ChunkGenerator for HeightmapGenerator {
+    fn name(&self) -> &str {
+        "heightmap"
+    }
+
+    fn generate(&self, pos: ChunkPos) -> Chunk {
+        let size = CHUNK_SIZE as i32;
+        let chunk_min = WorldBlockPos::from_chunk_local(pos, LocalBlockPos(0, 0, 0));
+        let mut chunk = Chunk::default();
+
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let Some(surface) = self.surface(chunk_min.0 + x as i32, chunk_min.2 + z as i32)
+                else {
+                    continue;
+                };
+
+                // world y ranges of each layer, min inclusive and max exclusive
+                let sub_surface = surface - self.layers.sub_surface_depth as i32;
+                let layers = [
+                    (self.origin.1, sub_surface, self.layers.deep),
+                    (
+                        sub_surface.max(self.origin.1),
+                        surface,
+                        self.layers.sub_surface,
+                    ),
+                    (surface, surface + 1, self.layers.top),
+                ];
+
+                for (min, max, b) in layers {
+                    let min = (min - chunk_min.1).clamp(0, size);
+                    let max = (max - chunk_min.1).clamp(0, size);
+                    if min < max {
+                        chunk.fill_region(
+                            LocalBlockPos(x, min as ChunkDimTy, z),
+                            LocalBlockPos(x + 1, max as ChunkDimTy, z + 1),
+                            b,
+                        );
+                    }
+                }
+            }
+        }
+
+        chunk.compact();
+        chunk
+    }
+}
+
+/// The next whitespace separated token of a PGM header, skipping comments.
+fn pgm_token<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    loop {
+        match data.get(*pos)? {
+            b'#' => {
+                while data.get(*pos).is_some_and(|b| *b != b'\n') {
+                    *pos += 1;
+                }
+            }
+            b if b.is_ascii_whitespace() => *pos += 1,
+            _ => break,
+        }
+    }
+
+    let start = *pos;
+    while data.get(*pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+        *pos += 1;
+    }
+    Some(&data[start..*pos])
+}
+
+fn parse_number(token: &[u8]) -> Option<u32> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(bit_depth: png::BitDepth, width: u32, depth: u32, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, width, depth);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(bit_depth);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+        writer.finish().unwrap();
+        out
+    }
+
+    #[test]
+    fn pgm_and_png_give_the_same_samples() {
+        let plain = b"P2\n# a comment\n3 2\n255\n0 10 20\n30 40 255\n";
+        let mut binary = b"P5 3 2 255\n".to_vec();
+        binary.extend_from_slice(&[0, 10, 20, 30, 40, 255]);
+
+        let expected = Heightmap {
+            width: 3,
+            depth: 2,
+            max_value: 255,
+            samples: vec![0, 10, 20, 30, 40, 255],
+        };
+        assert_eq!(Heightmap::parse(plain).unwrap(), expected);
+        assert_eq!(Heightmap::parse(&binary).unwrap(), expected);
+        assert_eq!(
+            Heightmap::parse(&png(png::BitDepth::Eight, 3, 2, &[0, 10, 20, 30, 40, 255])).unwrap(),
+            expected
+        );
+        assert_eq!(expected.sample(2, 1), Some(255));
+        assert_eq!(expected.sample(3, 0), None);
+    }
+
+    #[test]
+    fn sixteen_bit_samples_keep_their_precision() {
+        let mut pgm = b"P5 2 1 65535\n".to_vec();
+        pgm.extend_from_slice(&[0x12, 0x34, 0xff, 0xff]);
+        let map = Heightmap::parse(&pgm).unwrap();
+        assert_eq!(map.max_value(), u16::MAX);
+        assert_eq!(
+            (map.sample(0, 0), map.sample(1, 0)),
+            (Some(0x1234), Some(0xffff))
+        );
+
+        let map = Heightmap::parse(&png(
+            png::BitDepth::Sixteen,
+            2,
+            1,
+            &[0x12, 0x34, 0xff, 0xff],
+        ))
+        .unwrap();
+        assert_eq!(map.max_value(), u16::MAX);
+        assert_eq!(
+            (map.sample(0, 0), map.sample(1, 0)),
+            (Some(0x1234), Some(0xffff))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_images() {
+        assert!(matches!(
+            Heightmap::parse(b"P6 1 1 255\n\0\0\0"),
+            Err(HeightmapError::UnknownFormat)
+        ));
+        assert!(matches!(
+            Heightmap::parse(b"P5 1 x 255\n\0"),
+            Err(HeightmapError::InvalidHeader)
+        ));
+        assert!(matches!(
+            Heightmap::parse(b"P5 2 2 255\n\0\0\0"),
+            Err(HeightmapError::UnexpectedEnd)
+        ));
+        // the samples would take more bytes than there are addresses
+        assert!(matches!(
+            Heightmap::parse(b"P5 4294967295 4294967295 65535\n\0\0"),
+            Err(HeightmapError::InvalidHeader)
+        ));
+        assert!(matches!(
+            Heightmap::parse(b"P2 2 1 100\n5 101"),
+            Err(HeightmapError::InvalidSample { index: 1 })
+        ));
+
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, 1, 1);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&[1, 2, 3])
+            .unwrap();
+        assert!(matches!(
+            Heightmap::parse(&out),
+            Err(HeightmapError::NotGrayscale)
+        ));
+    }
+
+    #[test]
+    fn columns_are_filled_up_to_the_surface() {
+        let map = Heightmap::parse(b"P2 2 1 10\n0 10").unwrap();
+        let layers = Layers {
+            top: Block(3),
+            sub_surface: Block(1),
+            sub_surface_depth: 2,
+            deep: Block(2),
+        };
+        let generator = HeightmapGenerator::new(map)
+            .with_origin(WorldBlockPos(1, 2, 3))
+            .with_scale(8.0)
+            .with_layers(layers);
+
+        assert_eq!(generator.surface(1, 3), Some(2));
+        assert_eq!(generator.surface(2, 3), Some(10));
+        assert_eq!(generator.surface(0, 3), None);
+        assert_eq!(generator.surface(1, 4), None);
+
+        let chunk = generator.generate(ChunkPos(0, 0, 0));
+        let column = |x, z| {
+            (0..CHUNK_SIZE)
+                .map(|y| chunk.get_block(&LocalBlockPos(x, y, z)))
+                .collect::<Vec<_>>()
+        };
+        let air = |n| vec![Block(0); n];
+
+        // a black sample is just the top block
+        let mut expected = air(2);
+        expected.push(Block(3));
+        expected.extend(air(CHUNK_SIZE as usize - 3));
+        assert_eq!(column(1, 3), expected);
+
+        let mut expected = air(2);
+        expected.extend([Block(2); 6]);
+        expected.extend([Block(1); 2]);
+        expected.push(Block(3));
+        expected.extend(air(CHUNK_SIZE as usize - 11));
+        assert_eq!(column(2, 3), expected);
+
+        assert_eq!(chunk.iter_non_air().count(), 1 + 9);
+        assert!(generator.generate(ChunkPos(0, 1, 0)).is_empty());
+        assert!(generator.generate(ChunkPos(-1, 0, 0)).is_empty());
+    }
+}
@@ -0,0 +1,403 @@
+//! Exporting chunk meshes to Wavefront OBJ and binary PLY.
+//!
+//! The encoded vertices from the mesher are decoded back to world positions,
+//! so the files line up with the world. Every triangle keeps the normal of the
+//! face it is part of and the colour of its block type.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    chunk::{
+        block::Block, mesher::mesh_with_neighbors, registry::BlockRegistry, ChunkPos,
+        EncodedVertex, CHUNK_SIZE,
+    },
+    world::World,
+};
+
+/// Outward normal of the faces on each mesher axis.
+pub const AXIS_NORMALS: [[i32; 3]; 6] = [
+    [0, 0, -1],
+    [0, 1, 0],
+    [1, 0, 0],
+    [0, 0, 1],
+    [0, -1, 0],
+    [-1, 0, 0],
+];
+
+/// Used for blocks that aren't in the registry.
+const UNKNOWN_COLOUR: [u8; 4] = [255, 0, 255, 255];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Triangle {
+    /// World positions, wound counter-clockwise when seen from outside.
+    pub vertices: [[i32; 3]; 3],
+    /// Mesher axis of the face, indexes `AXIS_NORMALS`.
+    pub axis: usize,
+    pub block_id: u32,
+}
+
+impl Triangle {
+    pub fn normal(&self) -> [f32; 3] {
+        AXIS_NORMALS[self.axis].map(|n| n as f32)
+    }
+}
+
+/// Decodes the mesh of the chunk at `pos` into triangles in world space.
+pub fn decode_mesh(pos: ChunkPos, mesh: &[Vec<EncodedVertex>; 6]) -> Vec<Triangle> {
+    let size = CHUNK_SIZE as i32;
+    let origin = [pos.0 * size, pos.1 * size, pos.2 * size];
+
+    let mut triangles = Vec::new();
+    for (axis, vertices) in mesh.iter().enumerate() {
+        for tri in vertices.chunks_exact(3) {
+            let mut vertices = [&tri[0], &tri[1], &tri[2]].map(|v| {
+                let (x, y, z) = v.position();
+                [
+                    origin[0] + x as i32,
+                    origin[1] + y as i32,
+                    origin[2] + z as i32,
+                ]
+            });
+
+            // the renderer doesn't cull back faces, so the mesher doesn't keep
+            // a consistent winding
+            if dot(cross(&vertices), AXIS_NORMALS[axis]) < 0 {
+                vertices.swap(1, 2);
+            }
+
+            triangles.push(Triangle {
+                vertices,
+                axis,
+                block_id: tri[0].block_id(),
+            });
+        }
+    }
+
+    triangles
+}
+
+fn cross([a, b, c]: &[[i32; 3]; 3]) -> [i32; 3] {
+    let u = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+    let v = [c[0] - a[0], c[1] - a[1], c[2] - a[2]];
+    [
+        u[1] * v[2] - u[2] * v[1],
+        u[2] * v[0] - u[0] * v[2],
+        u[0] * v[1] - u[1] * v[0],
+    ]
+}
+
+fn dot(a: [i32; 3], b: [i32; 3]) -> i32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Triangles from any number of chunks, merged into a single mesh when
+/// written.
+#[derive(Debug, Default, Clone)]
+pub struct MeshExport {
+    pub triangles: Vec<Triangle>,
+}
+
+impl MeshExport {
+    /// Meshes every loaded chunk of the world.
+    pub fn from_world(world: &World) -> Self {
+        let mut export = Self::default();
+        for pos in world.chunk_positions() {
+            if let Some(hood) = world.neighborhood(pos) {
+                export.add_mesh(*pos, &mesh_with_neighbors(&hood));
+            }
+        }
+
+        export
+    }
+
+    pub fn add_mesh(&mut self, pos: ChunkPos, mesh: &[Vec<EncodedVertex>; 6]) {
+        self.triangles.extend(decode_mesh(pos, mesh));
+    }
+
+    /// Writes `path` as an OBJ file, with a material for each block type in a
+    /// `.mtl` file next to it.
+    pub fn save_obj(
+        &self,
+        path: impl AsRef<Path>,
+        registry: &BlockRegistry,
+    ) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let mtl_path = path.with_extension("mtl");
+        let mtl_name = mtl_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("materials.mtl");
+
+        let mut obj = BufWriter::new(File::create(path)?);
+        self.write_obj(&mut obj, Some(mtl_name), registry)?;
+        obj.flush()?;
+
+        let mut mtl = BufWriter::new(File::create(&mtl_path)?);
+        self.write_mtl(&mut mtl, registry)?;
+        mtl.flush()
+    }
+
+    /// Writes the triangles as OBJ, sharing vertices between faces. Faces use
+    /// a material per block type, which are in the `mtl` file if one is given.
+    pub fn write_obj(
+        &self,
+        out: &mut impl Write,
+        mtl: Option<&str>,
+        registry: &BlockRegistry,
+    ) -> std::io::Result<()> {
+        writeln!(out, "# exported from vvrs")?;
+        if let Some(mtl) = mtl {
+            writeln!(out, "mtllib {}", mtl)?;
+        }
+
+        let (positions, indices) = self.shared_vertices();
+        for [x, y, z] in &positions {
+            writeln!(out, "v {} {} {}", x, y, z)?;
+        }
+        for [x, y, z] in AXIS_NORMALS {
+            writeln!(out, "vn {} {} {}", x, y, z)?;
+        }
+
+        // group the faces by block so each material is only switched to once
+        let mut order: Vec<_> = (0..self.triangles.len()).collect();
+        order.sort_by_key(|i| self.triangles[*i].block_id);
+
+        let mut material = None;
+        for i in order {
+            let triangle = &self.triangles[i];
+            if material != Some(triangle.block_id) {
+                material = Some(triangle.block_id);
+                writeln!(out, "usemtl {}", material_name(registry, triangle.block_id))?;
+            }
+
+            // obj indices start at 1
+            let [a, b, c] = indices[i].map(|v| v + 1);
+            let n = triangle.axis + 1;
+            writeln!(out, "f {a}//{n} {b}//{n} {c}//{n}")?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a material for each block type used, coloured like the block.
+    pub fn write_mtl(&self, out: &mut impl Write, registry: &BlockRegistry) -> std::io::Result<()> {
+        let mut ids: Vec<_> = self.triangles.iter().map(|t| t.block_id).collect();
+        ids.sort();
+        ids.dedup();
+
+        for id in ids {
+            let [r, g, b, a] = block_colour(registry, id);
+            writeln!(out, "newmtl {}", material_name(registry, id))?;
+            writeln!(
+                out,
+                "Kd {:.4} {:.4} {:.4}",
+                r as f32 / 255.0,
+                g as f32 / 255.0,
+                b as f32 / 255.0
+            )?;
+            writeln!(out, "d {:.4}", a as f32 / 255.0)?;
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn save_ply(
+        &self,
+        path: impl AsRef<Path>,
+        registry: &BlockRegistry,
+    ) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_ply(&mut out, registry)?;
+        out.flush()
+    }
+
+    /// Writes the triangles as binary PLY, sharing vertices between faces.
+    /// The normal and colour are properties of each face.
+    pub fn write_ply(&self, out: &mut impl Write, registry: &BlockRegistry) -> std::io::Result<()> {
+        let (positions, indices) = self.shared_vertices();
+
+        write!(
+            out,
+            "ply\n\
+             format binary_little_endian 1.0\n\
+             comment exported from vvrs\n\
+             element vertex {}\n\
+             property float x\n\
+             property float y\n\
+             property float z\n\
+             element face {}\n\
+             property list uchar int vertex_indices\n\
+             property float nx\n\
+             property float ny\n\
+             property float nz\n\
+             property uchar red\n\
+             property uchar green\n\
+             property uchar blue\n\
+             property uchar alpha\n\
+             end_header\n",
+            positions.len(),
+            self.triangles.len()
+        )?;
+
+        for position in &positions {
+            for v in position {
+                out.write_all(&(*v as f32).to_le_bytes())?;
+            }
+        }
+
+        for (triangle, indices) in self.triangles.iter().zip(&indices) {
+            out.write_all(&[3])?;
+            for i in indices {
+                out.write_all(&(*i as i32).to_le_bytes())?;
+            }
+            for n in triangle.normal() {
+                out.write_all(&n.to_le_bytes())?;
+            }
+            out.write_all(&block_colour(registry, triangle.block_id))?;
+        }
+
+        Ok(())
+    }
+
+    /// The distinct vertex positions, and the index of each triangle's
+    /// vertices into them.
+    fn shared_vertices(&self) -> (Vec<[i32; 3]>, Vec<[usize; 3]>) {
+        let mut positions = Vec::new();
+        let mut lookup = HashMap::new();
+
+        let indices = self
+            .triangles
+            .iter()
+            .map(|t| {
+                t.vertices.map(|v| {
+                    *lookup.entry(v).or_insert_with(|| {
+                        positions.push(v);
+                        positions.len() - 1
+                    })
+                })
+            })
+            .collect();
+
+        (positions, indices)
+    }
+}
+
+fn block_colour(registry: &BlockRegistry, id: u32) -> [u8; 4] {
+    registry
+        .get(Block::new(id))
+        .map_or(UNKNOWN_COLOUR, |d| d.colour)
+}
+
+fn material_name(registry: &BlockRegistry, id: u32) -> String {
+    registry
+        .get(Block::new(id))
+        .map_or_else(|| format!("block_{}", id), |d| d.name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        chunk::{mesher::mesh, Chunk, LocalBlockPos},
+        world::WorldBlockPos,
+    };
+
+    use super::*;
+
+    fn single_block() -> World {
+        let mut world = World::default();
+        world.set_block(WorldBlockPos(-1, 2, 3), Block(2));
+        world
+    }
+
+    #[test]
+    fn triangles_face_outwards_in_world_space() {
+        let export = MeshExport::from_world(&single_block());
+        assert_eq!(export.triangles.len(), 12);
+
+        for triangle in &export.triangles {
+            let normal = AXIS_NORMALS[triangle.axis];
+            assert!(dot(cross(&triangle.vertices), normal) > 0);
+
+            // every vertex is a corner of the block
+            for [x, y, z] in triangle.vertices {
+                assert!((-1..=0).contains(&x) && (2..=3).contains(&y) && (3..=4).contains(&z));
+            }
+
+            // and the face is on the side the normal points to
+            let centre = [-0.5, 2.5, 3.5];
+            let v = triangle.vertices[0];
+            let offset: f32 = (0..3)
+                .map(|i| (v[i] as f32 - centre[i]) * normal[i] as f32)
+                .sum();
+            assert_eq!(offset, 0.5);
+            assert_eq!(triangle.block_id, 2);
+        }
+    }
+
+    #[test]
+    fn obj_shares_vertices_and_uses_materials() {
+        let mut world = single_block();
+        world.set_block(WorldBlockPos(10, 0, 0), Block(3));
+        let export = MeshExport::from_world(&world);
+
+        let mut obj = Vec::new();
+        export
+            .write_obj(&mut obj, Some("scene.mtl"), BlockRegistry::global())
+            .unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+
+        let count = |prefix: &str| obj.lines().filter(|l| l.starts_with(prefix)).count();
+        assert_eq!(count("v "), 16);
+        assert_eq!(count("vn "), 6);
+        assert_eq!(count("f "), 24);
+        assert!(obj.contains("mtllib scene.mtl"));
+        assert!(obj.contains("usemtl stone"));
+        assert!(obj.contains("usemtl leaves"));
+
+        let mut mtl = Vec::new();
+        export.write_mtl(&mut mtl, BlockRegistry::global()).unwrap();
+        let mtl = String::from_utf8(mtl).unwrap();
+        assert!(mtl.contains("newmtl stone\nKd 0.4980 0.4980 0.4980"));
+    }
+
+    #[test]
+    fn ply_has_a_record_for_each_face() {
+        let export = MeshExport::from_world(&single_block());
+
+        let mut ply = Vec::new();
+        export.write_ply(&mut ply, BlockRegistry::global()).unwrap();
+
+        let header_end = ply.windows(11).position(|w| w == b"end_header\n").unwrap() + 11;
+        let header = std::str::from_utf8(&ply[..header_end]).unwrap();
+        assert!(header.contains("element vertex 8\n"));
+        assert!(header.contains("element face 12\n"));
+
+        // 3 floats per vertex, and a count, 3 indices, a normal and a colour
+        // per face
+        assert_eq!(ply.len() - header_end, 8 * 12 + 12 * (1 + 12 + 12 + 4));
+
+        // the first face's colour is stone's
+        let face = header_end + 8 * 12;
+        assert_eq!(ply[face], 3);
+        assert_eq!(&ply[face + 25..face + 29], &[0x7f, 0x7f, 0x7f, 0xff]);
+    }
+
+    #[test]
+    fn chunk_position_translates_the_mesh() {
+        let mut chunk = Chunk::default();
+        chunk.set_block(LocalBlockPos(0, 0, 0), Block(1));
+
+        let triangles = decode_mesh(ChunkPos(1, -1, 0), &mesh(&chunk));
+        let size = CHUNK_SIZE as i32;
+        assert!(triangles
+            .iter()
+            .flat_map(|t| t.vertices)
+            .all(|[x, y, _]| (size..=size + 1).contains(&x) && (-size..=-size + 1).contains(&y)));
+    }
+}
@@ -0,0 +1,7 @@
+//! Importing and exporting voxel data in formats used by other tools.
+
+pub mod gltf;
+pub mod heightmap;
+pub mod mesh;
+pub mod volume;
+pub mod vox;
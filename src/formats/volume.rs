@@ -0,0 +1,1236 @@
+//! Dense scalar volumes, as raw samples, NRRD files or legacy VTK structured
+//! points.
+//!
+//! Samples are kept with x varying fastest, then y, then z, which is the order
+//! all three formats use, and the volume axes map directly onto the world
+//! axes. Spacing and origin fields are ignored, every sample is one block.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    path::{Component, Path},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use crate::{
+    chunk::{block::Block, MAX_BLOCK_ID},
+    world::{World, WorldBlockPos},
+};
+
+#[derive(Debug)]
+pub enum VolumeError {
+    Io(std::io::Error),
+    /// The data ended before every sample was read.
+    UnexpectedEnd,
+    /// A raw volume has more data than its dimensions need.
+    TrailingData(usize),
+    /// A NRRD or VTK header is missing a field or has an invalid one.
+    InvalidHeader {
+        reason: String,
+    },
+    UnsupportedType(String),
+    UnsupportedEncoding(String),
+    /// The data isn't a 3D grid of single scalars.
+    UnsupportedLayout(String),
+    /// A text sample isn't a number.
+    InvalidSample {
+        index: usize,
+    },
+    /// A sample mapped with `ScalarMapping::Ids` isn't a block id.
+    InvalidBlockId {
+        index: usize,
+        value: f64,
+    },
+    /// The number of samples doesn't match the dimensions.
+    SizeMismatch {
+        expected: usize,
+        found: usize,
+    },
+    /// The NRRD header refers to a separate data file, so it has to be
+    /// loaded from a path.
+    DetachedData,
+}
+
+impl std::fmt::Display for VolumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VolumeError::Io(e) => write!(f, "couldn't read volume: {}", e),
+            VolumeError::UnexpectedEnd => write!(f, "volume data ended early"),
+            VolumeError::TrailingData(len) => {
+                write!(f, "{} bytes left over after the volume data", len)
+            }
+            VolumeError::InvalidHeader { reason } => write!(f, "invalid header: {}", reason),
+            VolumeError::UnsupportedType(name) => write!(f, "sample type {} isn't supported", name),
+            VolumeError::UnsupportedEncoding(name) => {
+                write!(f, "encoding {} isn't supported", name)
+            }
+            VolumeError::UnsupportedLayout(reason) => {
+                write!(f, "volume layout isn't supported: {}", reason)
+            }
+            VolumeError::InvalidSample { index } => write!(f, "sample {} isn't a number", index),
+            VolumeError::InvalidBlockId { index, value } => {
+                write!(f, "sample {} is {}, which isn't a block id", index, value)
+            }
+            VolumeError::SizeMismatch { expected, found } => write!(
+                f,
+                "volume has {} samples, but its dimensions need {}",
+                found, expected
+            ),
+            VolumeError::DetachedData => {
+                write!(f, "volume data is in a separate file, load it from a path")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VolumeError {}
+
+impl From<std::io::Error> for VolumeError {
+    fn from(e: std::io::Error) -> Self {
+        VolumeError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElementType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    F64,
+}
+
+impl ElementType {
+    /// Bytes per sample.
+    pub fn size(self) -> usize {
+        match self {
+            ElementType::U8 | ElementType::I8 => 1,
+            ElementType::U16 | ElementType::I16 => 2,
+            ElementType::U32 | ElementType::I32 | ElementType::F32 => 4,
+            ElementType::F64 => 8,
+        }
+    }
+
+    fn nrrd_name(self) -> &'static str {
+        match self {
+            ElementType::U8 => "uint8",
+            ElementType::I8 => "int8",
+            ElementType::U16 => "uint16",
+            ElementType::I16 => "int16",
+            ElementType::U32 => "uint32",
+            ElementType::I32 => "int32",
+            ElementType::F32 => "float",
+            ElementType::F64 => "double",
+        }
+    }
+
+    /// Any of the NRRD spellings of a type.
+    fn from_nrrd(name: &str) -> Option<Self> {
+        Some(match name {
+            "uchar" | "unsigned char" | "uint8" | "uint8_t" => ElementType::U8,
+            "signed char" | "int8" | "int8_t" => ElementType::I8,
+            "ushort" | "unsigned short" | "unsigned short int" | "uint16" | "uint16_t" => {
+                ElementType::U16
+            }
+            "short" | "short int" | "signed short" | "signed short int" | "int16" | "int16_t" => {
+                ElementType::I16
+            }
+            "uint" | "unsigned int" | "uint32" | "uint32_t" => ElementType::U32,
+            "int" | "signed int" | "int32" | "int32_t" => ElementType::I32,
+            "float" => ElementType::F32,
+            "double" => ElementType::F64,
+            _ => return None,
+        })
+    }
+
+    fn vtk_name(self) -> &'static str {
+        match self {
+            ElementType::U8 => "unsigned_char",
+            ElementType::I8 => "char",
+            ElementType::U16 => "unsigned_short",
+            ElementType::I16 => "short",
+            ElementType::U32 => "unsigned_int",
+            ElementType::I32 => "int",
+            ElementType::F32 => "float",
+            ElementType::F64 => "double",
+        }
+    }
+
+    fn from_vtk(name: &str) -> Option<Self> {
+        Some(match name {
+            "unsigned_char" => ElementType::U8,
+            "char" => ElementType::I8,
+            "unsigned_short" => ElementType::U16,
+            "short" => ElementType::I16,
+            "unsigned_int" => ElementType::U32,
+            "int" => ElementType::I32,
+            "float" => ElementType::F32,
+            "double" => ElementType::F64,
+            _ => return None,
+        })
+    }
+
+    /// Reads one sample, `bytes` is `size()` long.
+    fn decode(self, bytes: &[u8], endian: Endian) -> f64 {
+        let mut le = [0u8; 8];
+        le[..bytes.len()].copy_from_slice(bytes);
+        if endian == Endian::Big {
+            le[..bytes.len()].reverse();
+        }
+
+        match self {
+            ElementType::U8 => le[0] as f64,
+            ElementType::I8 => le[0] as i8 as f64,
+            ElementType::U16 => u16::from_le_bytes([le[0], le[1]]) as f64,
+            ElementType::I16 => i16::from_le_bytes([le[0], le[1]]) as f64,
+            ElementType::U32 => u32::from_le_bytes([le[0], le[1], le[2], le[3]]) as f64,
+            ElementType::I32 => i32::from_le_bytes([le[0], le[1], le[2], le[3]]) as f64,
+            ElementType::F32 => f32::from_le_bytes([le[0], le[1], le[2], le[3]]) as f64,
+            ElementType::F64 => f64::from_le_bytes(le),
+        }
+    }
+
+    /// Writes one sample, rounding and saturating it for integer types.
+    fn encode(self, v: f64, endian: Endian, out: &mut Vec<u8>) {
+        let start = out.len();
+        match self {
+            ElementType::U8 => out.extend((v.round() as u8).to_le_bytes()),
+            ElementType::I8 => out.extend((v.round() as i8).to_le_bytes()),
+            ElementType::U16 => out.extend((v.round() as u16).to_le_bytes()),
+            ElementType::I16 => out.extend((v.round() as i16).to_le_bytes()),
+            ElementType::U32 => out.extend((v.round() as u32).to_le_bytes()),
+            ElementType::I32 => out.extend((v.round() as i32).to_le_bytes()),
+            ElementType::F32 => out.extend((v as f32).to_le_bytes()),
+            ElementType::F64 => out.extend(v.to_le_bytes()),
+        }
+        if endian == Endian::Big {
+            out[start..].reverse();
+        }
+    }
+
+    fn format(self, v: f64) -> String {
+        match self {
+            ElementType::F32 => (v as f32).to_string(),
+            ElementType::F64 => v.to_string(),
+            _ => (v.round() as i64).to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// What a raw volume file holds, as it has no header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawLayout {
+    pub dims: [u32; 3],
+    pub element: ElementType,
+    pub endian: Endian,
+}
+
+/// How the samples of a NRRD file are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Raw,
+    /// Whitespace separated numbers.
+    Ascii,
+    Gzip,
+}
+
+/// Turns samples into blocks on import.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScalarMapping {
+    /// Samples at or above `level` become `block`, the rest are air.
+    Threshold { level: f64, block: Block },
+    /// Steps sorted by value. Samples get the block of the highest step at or
+    /// below them, samples below the first step are air.
+    Transfer(Vec<(f64, Block)>),
+    /// Samples are block ids, e.g. for volumes made by `Volume::from_world`.
+    /// They're rounded to the nearest id, and have to be below
+    /// `MAX_BLOCK_ID`.
+    Ids,
+}
+
+impl ScalarMapping {
+    pub fn threshold(level: f64, block: Block) -> Self {
+        ScalarMapping::Threshold { level, block }
+    }
+
+    /// A transfer mapping from steps in any order.
+    pub fn transfer(mut steps: Vec<(f64, Block)>) -> Self {
+        steps.sort_by(|a, b| a.0.total_cmp(&b.0));
+        ScalarMapping::Transfer(steps)
+    }
+
+    /// The block for a sample, or `None` if the sample isn't a valid block
+    /// id under `Ids`.
+    pub fn get(&self, v: f64) -> Option<Block> {
+        match self {
+            ScalarMapping::Threshold { level, block } if v >= *level => Some(*block),
+            ScalarMapping::Threshold { .. } => Some(Block::new(0)),
+            ScalarMapping::Transfer(steps) => Some(
+                steps
+                    .iter()
+                    .take_while(|(step, _)| *step <= v)
+                    .last()
+                    .map_or(Block::new(0), |(_, b)| *b),
+            ),
+            ScalarMapping::Ids => {
+                // NaN isn't in any range either
+                let id = v.round();
+                (0.0..MAX_BLOCK_ID as f64)
+                    .contains(&id)
+                    .then(|| Block::new(id as u32))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    dims: [u32; 3],
+    /// The type the samples were read as, and are written as.
+    element: ElementType,
+    values: Vec<f64>,
+}
+
+impl Volume {
+    pub fn new(
+        dims: [u32; 3],
+        element: ElementType,
+        values: Vec<f64>,
+    ) -> Result<Self, VolumeError> {
+        let expected = sample_count(dims)?;
+        if values.len() != expected {
+            return Err(VolumeError::SizeMismatch {
+                expected,
+                found: values.len(),
+            });
+        }
+
+        Ok(Self {
+            dims,
+            element,
+            values,
+        })
+    }
+
+    pub fn dims(&self) -> [u32; 3] {
+        self.dims
+    }
+
+    pub fn element(&self) -> ElementType {
+        self.element
+    }
+
+    pub fn values(&self) -> &[f64] {
+        &self.values
+    }
+
+    pub fn get(&self, x: u32, y: u32, z: u32) -> Option<f64> {
+        let [nx, ny, nz] = self.dims;
+        if x >= nx || y >= ny || z >= nz {
+            return None;
+        }
+        let index = (z as usize * ny as usize + y as usize) * nx as usize + x as usize;
+        Some(self.values[index])
+    }
+
+    /// Sets the blocks the samples map to, with the first sample at
+    /// `offset`. Samples that map to air are skipped. Returns the number of
+    /// blocks set, or the first sample that isn't a block id, in which case
+    /// the world is left untouched.
+    pub fn place(
+        &self,
+        world: &mut World,
+        offset: WorldBlockPos,
+        mapping: &ScalarMapping,
+    ) -> Result<usize, VolumeError> {
+        // check every sample before setting any, without keeping the blocks
+        // around as volumes can be large
+        if let Some((index, &value)) = self
+            .values
+            .iter()
+            .enumerate()
+            .find(|(_, &value)| mapping.get(value).is_none())
+        {
+            return Err(VolumeError::InvalidBlockId { index, value });
+        }
+
+        let [nx, ny, _] = self.dims.map(|d| d as usize);
+        let mut placed = 0;
+
+        for (i, &value) in self.values.iter().enumerate() {
+            let Some(block) = mapping.get(value).filter(|b| b.id() != 0) else {
+                continue;
+            };
+
+            let (x, y, z) = (i % nx, i / nx % ny, i / (nx * ny));
+            world.set_block(
+                WorldBlockPos(
+                    offset.0 + x as i32,
+                    offset.1 + y as i32,
+                    offset.2 + z as i32,
+                ),
+                block,
+            );
+            placed += 1;
+        }
+
+        Ok(placed)
+    }
+
+    /// A volume of the block ids between `min` (inclusive) and `max`
+    /// (exclusive), in the smallest unsigned type that holds them. Block state
+    /// is left out.
+    pub fn from_world(world: &World, min: WorldBlockPos, max: WorldBlockPos) -> Self {
+        let dims = [max.0 - min.0, max.1 - min.1, max.2 - min.2].map(|d| d.max(0) as u32);
+
+        let mut values = Vec::with_capacity(dims.iter().map(|d| *d as usize).product());
+        let mut max_id = 0;
+        for z in min.2..max.2 {
+            for y in min.1..max.1 {
+                for x in min.0..max.0 {
+                    let id = world.get_block(&WorldBlockPos(x, y, z)).id();
+                    max_id = max_id.max(id);
+                    values.push(id as f64);
+                }
+            }
+        }
+
+        let element = if max_id <= u8::MAX as u32 {
+            ElementType::U8
+        } else if max_id <= u16::MAX as u32 {
+            ElementType::U16
+        } else {
+            ElementType::U32
+        };
+
+        Self {
+            dims,
+            element,
+            values,
+        }
+    }
+}
+
+/// Raw volumes, just the samples without a header.
+impl Volume {
+    pub fn load_raw<P: AsRef<Path>>(path: P, layout: RawLayout) -> Result<Self, VolumeError> {
+        Self::from_raw(&fs::read(path)?, layout)
+    }
+
+    pub fn from_raw(data: &[u8], layout: RawLayout) -> Result<Self, VolumeError> {
+        let count = sample_count(layout.dims)?;
+        let len = data_len(count, layout.element)?;
+        if data.len() > len {
+            return Err(VolumeError::TrailingData(data.len() - len));
+        }
+
+        let values = decode_samples(data, layout.element, layout.endian, count)?;
+        Self::new(layout.dims, layout.element, values)
+    }
+
+    pub fn save_raw<P: AsRef<Path>>(&self, path: P, endian: Endian) -> Result<(), VolumeError> {
+        Ok(fs::write(path, self.to_raw(endian))?)
+    }
+
+    pub fn to_raw(&self, endian: Endian) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.values.len() * self.element.size());
+        for v in &self.values {
+            self.element.encode(*v, endian, &mut out);
+        }
+        out
+    }
+
+    /// The layout to read the output of `to_raw` back with.
+    pub fn raw_layout(&self, endian: Endian) -> RawLayout {
+        RawLayout {
+            dims: self.dims,
+            element: self.element,
+            endian,
+        }
+    }
+}
+
+/// NRRD files with attached or detached data. Volumes with fewer than three
+/// axes are padded to three.
+impl Volume {
+    /// Reads a NRRD file, with the data file of a detached header relative
+    /// to the header.
+    pub fn load_nrrd<P: AsRef<Path>>(path: P) -> Result<Self, VolumeError> {
+        let path = path.as_ref();
+        Self::parse_nrrd_in(&fs::read(path)?, path.parent())
+    }
+
+    /// Reads a NRRD file with the data after the header.
+    pub fn parse_nrrd(data: &[u8]) -> Result<Self, VolumeError> {
+        Self::parse_nrrd_in(data, None)
+    }
+
+    fn parse_nrrd_in(data: &[u8], dir: Option<&Path>) -> Result<Self, VolumeError> {
+        let mut pos = 0;
+        if !header_line(data, &mut pos)?.starts_with("NRRD000") {
+            return Err(invalid_header("missing NRRD magic"));
+        }
+
+        // the header ends at the first empty line, or the end of a detached
+        // header file
+        let mut fields = HashMap::<String, String>::new();
+        while pos < data.len() {
+            let line = header_line(data, &mut pos)?;
+            if line.is_empty() {
+                break;
+            }
+            // comments and key/value pairs
+            if line.starts_with('#') || line.contains(":=") {
+                continue;
+            }
+
+            let (field, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid_header(format!("invalid line {:?}", line)))?;
+            fields.insert(field.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+
+        let field = |name: &str| fields.get(name).map(String::as_str);
+        let required = |name: &str| {
+            field(name).ok_or_else(|| invalid_header(format!("missing {} field", name)))
+        };
+
+        let type_name = required("type")?;
+        let element = ElementType::from_nrrd(type_name)
+            .ok_or_else(|| VolumeError::UnsupportedType(type_name.to_string()))?;
+
+        let dimension = required("dimension")?
+            .parse::<usize>()
+            .map_err(|_| invalid_header("invalid dimension"))?;
+        if !(1..=3).contains(&dimension) {
+            return Err(VolumeError::UnsupportedLayout(format!(
+                "{} dimensions",
+                dimension
+            )));
+        }
+        let sizes = required("sizes")?
+            .split_whitespace()
+            .map(|s| s.parse::<u32>().ok().filter(|s| *s > 0))
+            .collect::<Option<Vec<_>>>()
+            .filter(|s| s.len() == dimension)
+            .ok_or_else(|| invalid_header("invalid sizes"))?;
+        let mut dims = [1; 3];
+        dims[..dimension].copy_from_slice(&sizes);
+
+        let encoding = match required("encoding")? {
+            "raw" => Encoding::Raw,
+            "txt" | "text" | "ascii" => Encoding::Ascii,
+            "gzip" | "gz" => Encoding::Gzip,
+            other => return Err(VolumeError::UnsupportedEncoding(other.to_string())),
+        };
+        let endian = match field("endian") {
+            Some("little") => Endian::Little,
+            Some("big") => Endian::Big,
+            Some(other) => return Err(invalid_header(format!("invalid endian {}", other))),
+            None if element.size() == 1 || encoding == Encoding::Ascii => Endian::Little,
+            None => return Err(invalid_header("missing endian field")),
+        };
+
+        let skip = |name: &str| {
+            field(name).map_or(Ok(0), |v| {
+                v.parse::<i64>()
+                    .map_err(|_| invalid_header(format!("invalid {}", name)))
+            })
+        };
+        let line_skip = skip("line skip")?;
+        let byte_skip = skip("byte skip")?;
+
+        let detached;
+        let mut payload = match field("data file").or(field("datafile")) {
+            Some(name) => {
+                // only files beside the header, not anywhere the header names
+                let name = Path::new(name);
+                let inside = name
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+                if !inside {
+                    return Err(invalid_header(format!(
+                        "data file {} isn't beside the header",
+                        name.display()
+                    )));
+                }
+                detached = fs::read(dir.ok_or(VolumeError::DetachedData)?.join(name))?;
+                &detached[..]
+            }
+            None => &data[pos..],
+        };
+
+        for _ in 0..line_skip {
+            let end = payload
+                .iter()
+                .position(|b| *b == b'\n')
+                .ok_or(VolumeError::UnexpectedEnd)?;
+            payload = &payload[end + 1..];
+        }
+
+        let count = sample_count(dims)?;
+        let len = data_len(count, element)?;
+        let bytes = match encoding {
+            Encoding::Gzip => {
+                // inflate no more than the sizes need, so a small file can't
+                // fill up memory
+                let skip =
+                    u64::try_from(byte_skip).map_err(|_| invalid_header("invalid byte skip"))?;
+                let limit = skip.saturating_add(len as u64);
+                let mut out = Vec::new();
+                GzDecoder::new(payload)
+                    .take(limit.saturating_add(1))
+                    .read_to_end(&mut out)?;
+                if out.len() as u64 > limit {
+                    return Err(invalid_header(format!(
+                        "gzip data is longer than the {} bytes the sizes need",
+                        limit
+                    )));
+                }
+                Cow::Owned(out)
+            }
+            _ => Cow::Borrowed(payload),
+        };
+
+        let bytes = match byte_skip {
+            // the data is at the end of the file
+            -1 if encoding == Encoding::Raw => {
+                let start = bytes
+                    .len()
+                    .checked_sub(len)
+                    .ok_or(VolumeError::UnexpectedEnd)?;
+                &bytes[start..]
+            }
+            skip if skip >= 0 => bytes
+                .get(skip as usize..)
+                .ok_or(VolumeError::UnexpectedEnd)?,
+            _ => return Err(invalid_header("invalid byte skip")),
+        };
+
+        let values = match encoding {
+            Encoding::Ascii => parse_samples(bytes, count)?,
+            _ => decode_samples(bytes, element, endian, count)?,
+        };
+        Self::new(dims, element, values)
+    }
+
+    pub fn save_nrrd<P: AsRef<Path>>(
+        &self,
+        path: P,
+        encoding: Encoding,
+    ) -> Result<(), VolumeError> {
+        Ok(fs::write(path, self.to_nrrd(encoding)?)?)
+    }
+
+    /// A NRRD file with attached data, little-endian if it's binary.
+    pub fn to_nrrd(&self, encoding: Encoding) -> Result<Vec<u8>, VolumeError> {
+        let [nx, ny, nz] = self.dims;
+        let mut out = format!(
+            "NRRD0004\ntype: {}\ndimension: 3\nsizes: {} {} {}\n",
+            self.element.nrrd_name(),
+            nx,
+            ny,
+            nz
+        );
+        if encoding != Encoding::Ascii && self.element.size() > 1 {
+            out.push_str("endian: little\n");
+        }
+        out.push_str(match encoding {
+            Encoding::Raw => "encoding: raw\n\n",
+            Encoding::Ascii => "encoding: ascii\n\n",
+            Encoding::Gzip => "encoding: gzip\n\n",
+        });
+
+        let mut out = out.into_bytes();
+        match encoding {
+            Encoding::Raw => out.extend(self.to_raw(Endian::Little)),
+            Encoding::Ascii => out.extend(self.ascii_rows().into_bytes()),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(out, Compression::default());
+                encoder.write_all(&self.to_raw(Endian::Little))?;
+                out = encoder.finish()?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// The samples as text, one row along x per line.
+    fn ascii_rows(&self) -> String {
+        let mut out = String::new();
+        for row in self.values.chunks(self.dims[0].max(1) as usize) {
+            let row: Vec<_> = row.iter().map(|v| self.element.format(*v)).collect();
+            out.push_str(&row.join(" "));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Legacy VTK files with a structured points dataset and a single scalar per
+/// point.
+impl Volume {
+    pub fn load_vtk<P: AsRef<Path>>(path: P) -> Result<Self, VolumeError> {
+        Self::parse_vtk(&fs::read(path)?)
+    }
+
+    pub fn parse_vtk(data: &[u8]) -> Result<Self, VolumeError> {
+        let mut pos = 0;
+        if !header_line(data, &mut pos)?.starts_with("# vtk DataFile Version") {
+            return Err(invalid_header("missing VTK version line"));
+        }
+        let _title = header_line(data, &mut pos)?;
+        let binary = match header_line(data, &mut pos)?
+            .trim()
+            .to_ascii_uppercase()
+            .as_str()
+        {
+            "ASCII" => false,
+            "BINARY" => true,
+            other => return Err(invalid_header(format!("invalid file type {}", other))),
+        };
+
+        let mut dims = None;
+        let mut point_data = None;
+        let element = loop {
+            let keyword = header_token(data, &mut pos)?.to_ascii_uppercase();
+            match keyword.as_str() {
+                "DATASET" => {
+                    let kind = header_token(data, &mut pos)?;
+                    if !kind.eq_ignore_ascii_case("STRUCTURED_POINTS") {
+                        return Err(VolumeError::UnsupportedLayout(format!("{} dataset", kind)));
+                    }
+                }
+                "DIMENSIONS" => {
+                    let mut d = [0; 3];
+                    for d in &mut d {
+                        *d = header_token(data, &mut pos)?
+                            .parse()
+                            .ok()
+                            .filter(|d| *d > 0)
+                            .ok_or_else(|| invalid_header("invalid dimensions"))?;
+                    }
+                    dims = Some(d);
+                }
+                "SPACING" | "ASPECT_RATIO" | "ORIGIN" => {
+                    for _ in 0..3 {
+                        header_token(data, &mut pos)?;
+                    }
+                }
+                "POINT_DATA" => {
+                    let n = header_token(data, &mut pos)?
+                        .parse::<usize>()
+                        .map_err(|_| invalid_header("invalid point count"))?;
+                    point_data = Some(n);
+                }
+                "SCALARS" => {
+                    // name, type and an optional number of components
+                    let line = header_line(data, &mut pos)?;
+                    let mut fields = line.split_whitespace().skip(1);
+                    let type_name = fields
+                        .next()
+                        .ok_or_else(|| invalid_header("missing scalar type"))?;
+                    let element = ElementType::from_vtk(type_name)
+                        .ok_or_else(|| VolumeError::UnsupportedType(type_name.to_string()))?;
+                    if fields.next().is_some_and(|n| n != "1") {
+                        return Err(VolumeError::UnsupportedLayout(
+                            "more than one component".to_string(),
+                        ));
+                    }
+
+                    // the lookup table line is optional
+                    let after_scalars = pos;
+                    match header_token(data, &mut pos) {
+                        Ok(t) if t.eq_ignore_ascii_case("LOOKUP_TABLE") => {
+                            header_line(data, &mut pos)?;
+                        }
+                        _ => pos = after_scalars,
+                    }
+                    break element;
+                }
+                "CELL_DATA" => return Err(VolumeError::UnsupportedLayout("cell data".to_string())),
+                other => return Err(invalid_header(format!("unexpected {}", other))),
+            }
+        };
+
+        let dims = dims.ok_or_else(|| invalid_header("missing DIMENSIONS"))?;
+        let count = sample_count(dims)?;
+        match point_data {
+            Some(n) if n == count => {}
+            Some(n) => {
+                return Err(VolumeError::SizeMismatch {
+                    expected: count,
+                    found: n,
+                })
+            }
+            None => return Err(invalid_header("missing POINT_DATA")),
+        }
+
+        let values = if binary {
+            decode_samples(&data[pos..], element, Endian::Big, count)?
+        } else {
+            parse_samples(&data[pos..], count)?
+        };
+        Self::new(dims, element, values)
+    }
+
+    pub fn save_vtk<P: AsRef<Path>>(&self, path: P, binary: bool) -> Result<(), VolumeError> {
+        Ok(fs::write(path, self.to_vtk(binary))?)
+    }
+
+    /// A legacy VTK file, binary data is big-endian as the format requires.
+    pub fn to_vtk(&self, binary: bool) -> Vec<u8> {
+        let [nx, ny, nz] = self.dims;
+        let mut out = format!(
+            "# vtk DataFile Version 3.0\nvvrs volume\n{}\nDATASET STRUCTURED_POINTS\n\
+             DIMENSIONS {} {} {}\nORIGIN 0 0 0\nSPACING 1 1 1\nPOINT_DATA {}\n\
+             SCALARS blocks {} 1\nLOOKUP_TABLE default\n",
+            if binary { "BINARY" } else { "ASCII" },
+            nx,
+            ny,
+            nz,
+            self.values.len(),
+            self.element.vtk_name()
+        )
+        .into_bytes();
+
+        if binary {
+            out.extend(self.to_raw(Endian::Big));
+            out.push(b'\n');
+        } else {
+            out.extend(self.ascii_rows().into_bytes());
+        }
+        out
+    }
+}
+
+fn invalid_header(reason: impl Into<String>) -> VolumeError {
+    VolumeError::InvalidHeader {
+        reason: reason.into(),
+    }
+}
+
+fn sample_count(dims: [u32; 3]) -> Result<usize, VolumeError> {
+    dims.iter()
+        .try_fold(1usize, |n, d| n.checked_mul(*d as usize))
+        .ok_or_else(|| invalid_header("volume is too large"))
+}
+
+/// The number of bytes `count` binary samples take.
+fn data_len(count: usize, element: ElementType) -> Result<usize, VolumeError> {
+    count
+        .checked_mul(element.size())
+        .ok_or_else(|| invalid_header("volume is too large"))
+}
+
+fn decode_samples(
+    data: &[u8],
+    element: ElementType,
+    endian: Endian,
+    count: usize,
+) -> Result<Vec<f64>, VolumeError> {
+    let data = data
+        .get(..data_len(count, element)?)
+        .ok_or(VolumeError::UnexpectedEnd)?;
+    Ok(data
+        .chunks_exact(element.size())
+        .map(|b| element.decode(b, endian))
+        .collect())
+}
+
+/// Reads `count` whitespace separated numbers.
+fn parse_samples(data: &[u8], count: usize) -> Result<Vec<f64>, VolumeError> {
+    let mut tokens = data
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|t| !t.is_empty());
+
+    (0..count)
+        .map(|index| {
+            let token = tokens.next().ok_or(VolumeError::UnexpectedEnd)?;
+            std::str::from_utf8(token)
+                .ok()
+                .and_then(|t| t.parse::<f64>().ok())
+                .ok_or(VolumeError::InvalidSample { index })
+        })
+        .collect()
+}
+
+/// The rest of the current line, without the line ending.
+fn header_line<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a str, VolumeError> {
+    if *pos >= data.len() {
+        return Err(VolumeError::UnexpectedEnd);
+    }
+
+    let rest = &data[*pos..];
+    let len = rest.iter().position(|b| *b == b'\n').unwrap_or(rest.len());
+    *pos += skip_line_end(rest);
+
+    let line = std::str::from_utf8(&rest[..len]).map_err(|_| invalid_header("not text"))?;
+    Ok(line.strip_suffix('\r').unwrap_or(line))
+}
+
+/// The next whitespace separated word of a header.
+fn header_token<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a str, VolumeError> {
+    while data.get(*pos).is_some_and(|b| b.is_ascii_whitespace()) {
+        *pos += 1;
+    }
+    let start = *pos;
+    while data.get(*pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+        *pos += 1;
+    }
+
+    if start == *pos {
+        return Err(VolumeError::UnexpectedEnd);
+    }
+    std::str::from_utf8(&data[start..*pos]).map_err(|_| invalid_header("not text"))
+}
+
+/// Number of bytes up to and including the next line ending.
+fn skip_line_end(data: &[u8]) -> usize {
+    data.iter()
+        .position(|b| *b == b'\n')
+        .map_or(data.len(), |i| i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 3x2x2 volume with a different value in every sample, some of them
+    /// negative if the type is signed.
+    fn ramp(element: ElementType) -> Volume {
+        let start = match element {
+            ElementType::U8 | ElementType::U16 | ElementType::U32 => 0.0,
+            _ => -20.0,
+        };
+        let values = (0..12).map(|v| start + v as f64 * 10.0).collect();
+        Volume::new([3, 2, 2], element, values).unwrap()
+    }
+
+    const ELEMENTS: [ElementType; 8] = [
+        ElementType::U8,
+        ElementType::I8,
+        ElementType::U16,
+        ElementType::I16,
+        ElementType::U32,
+        ElementType::I32,
+        ElementType::F32,
+        ElementType::F64,
+    ];
+
+    #[test]
+    fn raw_volumes_round_trip() {
+        for element in ELEMENTS {
+            for endian in [Endian::Little, Endian::Big] {
+                let volume = ramp(element);
+                let data = volume.to_raw(endian);
+                assert_eq!(data.len(), 12 * element.size());
+
+                let layout = volume.raw_layout(endian);
+                assert_eq!(Volume::from_raw(&data, layout).unwrap(), volume);
+
+                assert!(matches!(
+                    Volume::from_raw(&data[1..], layout),
+                    Err(VolumeError::UnexpectedEnd)
+                ));
+                let mut long = data.clone();
+                long.push(0);
+                assert!(matches!(
+                    Volume::from_raw(&long, layout),
+                    Err(VolumeError::TrailingData(1))
+                ));
+            }
+        }
+
+        let layout = RawLayout {
+            dims: [2, 1, 1],
+            element: ElementType::I16,
+            endian: Endian::Big,
+        };
+        let volume = Volume::from_raw(&[0xff, 0xfe, 0x01, 0x00], layout).unwrap();
+        assert_eq!(volume.values(), [-2.0, 256.0]);
+    }
+
+    #[test]
+    fn sizes_past_usize_are_refused() {
+        // the sample count fits, but not its size in bytes
+        let layout = RawLayout {
+            dims: [u32::MAX, u32::MAX, 1],
+            element: ElementType::I32,
+            endian: Endian::Little,
+        };
+        assert!(matches!(
+            Volume::from_raw(&[0; 8], layout),
+            Err(VolumeError::InvalidHeader { .. })
+        ));
+
+        for skip in [0, -1] {
+            let nrrd = format!(
+                "NRRD0004\ntype: int\ndimension: 3\nsizes: {0} {0} 1\nendian: little\n\
+                encoding: raw\nbyte skip: {1}\n\n\0\0\0\0",
+                u32::MAX,
+                skip
+            );
+            assert!(matches!(
+                Volume::parse_nrrd(nrrd.as_bytes()),
+                Err(VolumeError::InvalidHeader { .. })
+            ));
+        }
+
+        let vtk = format!(
+            "# vtk DataFile Version 2.0\nt\nBINARY\nDATASET STRUCTURED_POINTS\n\
+            DIMENSIONS {0} {0} 1\nPOINT_DATA {1}\nSCALARS v int 1\nLOOKUP_TABLE default\n\0",
+            u32::MAX,
+            u32::MAX as u64 * u32::MAX as u64
+        );
+        assert!(matches!(
+            Volume::parse_vtk(vtk.as_bytes()),
+            Err(VolumeError::InvalidHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn nrrd_volumes_round_trip() {
+        for element in ELEMENTS {
+            for encoding in [Encoding::Raw, Encoding::Ascii, Encoding::Gzip] {
+                let volume = ramp(element);
+                let data = volume.to_nrrd(encoding).unwrap();
+                assert_eq!(Volume::parse_nrrd(&data).unwrap(), volume);
+            }
+        }
+    }
+
+    #[test]
+    fn nrrd_headers_are_read() {
+        let mut data = b"NRRD0005\n\
+            # written by hand\n\
+            type: short\n\
+            dimension: 2\n\
+            space: left-posterior-superior\n\
+            sizes: 2 2\n\
+            endian: big\n\
+            encoding: raw\n\
+            byte skip: 1\n\
+            note:=ignored\n\n"
+            .to_vec();
+        data.extend_from_slice(&[0xaa, 0, 1, 0, 2, 0xff, 0xff, 0, 4]);
+
+        let volume = Volume::parse_nrrd(&data).unwrap();
+        assert_eq!(volume.dims(), [2, 2, 1]);
+        assert_eq!(volume.element(), ElementType::I16);
+        assert_eq!(volume.values(), [1.0, 2.0, -1.0, 4.0]);
+
+        let missing_endian =
+            b"NRRD0004\ntype: short\ndimension: 1\nsizes: 1\nencoding: raw\n\n\0\0";
+        assert!(matches!(
+            Volume::parse_nrrd(missing_endian),
+            Err(VolumeError::InvalidHeader { .. })
+        ));
+        let bzip = b"NRRD0004\ntype: uchar\ndimension: 1\nsizes: 1\nencoding: bzip2\n\n";
+        assert!(matches!(
+            Volume::parse_nrrd(bzip),
+            Err(VolumeError::UnsupportedEncoding(_))
+        ));
+        let four_d = b"NRRD0004\ntype: uchar\ndimension: 4\nsizes: 1 1 1 1\nencoding: raw\n\n\0";
+        assert!(matches!(
+            Volume::parse_nrrd(four_d),
+            Err(VolumeError::UnsupportedLayout(_))
+        ));
+    }
+
+    #[test]
+    fn detached_nrrd_data_is_read_from_beside_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = b"NRRD0004\ntype: uint8\ndimension: 3\nsizes: 2 1 1\n\
+            encoding: raw\ndata file: volume.raw\n";
+        fs::write(dir.path().join("volume.nhdr"), header).unwrap();
+        fs::write(dir.path().join("volume.raw"), [7, 9]).unwrap();
+
+        let volume = Volume::load_nrrd(dir.path().join("volume.nhdr")).unwrap();
+        assert_eq!(volume.values(), [7.0, 9.0]);
+        assert!(matches!(
+            Volume::parse_nrrd(header),
+            Err(VolumeError::DetachedData)
+        ));
+
+        // the data has to be beside the header, even if it exists elsewhere
+        let inner = dir.path().join("inner");
+        fs::create_dir(&inner).unwrap();
+        let outside = dir.path().join("volume.raw");
+        for name in ["../volume.raw", outside.to_str().unwrap()] {
+            let header = format!(
+                "NRRD0004\ntype: uint8\ndimension: 3\nsizes: 2 1 1\n\
+                encoding: raw\ndata file: {}\n",
+                name
+            );
+            fs::write(inner.join("volume.nhdr"), header).unwrap();
+            assert!(
+                matches!(
+                    Volume::load_nrrd(inner.join("volume.nhdr")),
+                    Err(VolumeError::InvalidHeader { .. })
+                ),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn gzip_data_is_inflated_no_further_than_needed() {
+        let header = b"NRRD0004\ntype: uint8\ndimension: 3\nsizes: 2 1 1\nencoding: gzip\n\n";
+        let nrrd = |samples: &[u8]| {
+            let mut encoder = GzEncoder::new(header.to_vec(), Compression::default());
+            encoder.write_all(samples).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        assert_eq!(
+            Volume::parse_nrrd(&nrrd(&[7, 9])).unwrap().values(),
+            [7.0, 9.0]
+        );
+        assert!(matches!(
+            Volume::parse_nrrd(&nrrd(&[7])),
+            Err(VolumeError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            Volume::parse_nrrd(&nrrd(&vec![0; 1 << 24])),
+            Err(VolumeError::InvalidHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn vtk_volumes_round_trip() {
+        for element in ELEMENTS {
+            for binary in [false, true] {
+                let volume = ramp(element);
+                let data = volume.to_vtk(binary);
+                assert_eq!(Volume::parse_vtk(&data).unwrap(), volume);
+            }
+        }
+    }
+
+    #[test]
+    fn vtk_files_are_read() {
+        let data = b"# vtk DataFile Version 2.0\n\
+            a title\n\
+            ASCII\n\
+            DATASET STRUCTURED_POINTS\n\
+            DIMENSIONS 2 1 2\n\
+            SPACING 0.5 0.5 2\n\
+            ORIGIN -1 0 1\n\
+            POINT_DATA 4\n\
+            SCALARS density float\n\
+            0.25 1.5\n-3 4\n";
+        let volume = Volume::parse_vtk(data).unwrap();
+        assert_eq!(volume.dims(), [2, 1, 2]);
+        assert_eq!(volume.get(0, 0, 1), Some(-3.0));
+        assert_eq!(volume.values(), [0.25, 1.5, -3.0, 4.0]);
+
+        let grid = b"# vtk DataFile Version 2.0\nt\nASCII\nDATASET RECTILINEAR_GRID\n";
+        assert!(matches!(
+            Volume::parse_vtk(grid),
+            Err(VolumeError::UnsupportedLayout(_))
+        ));
+        let vectors = b"# vtk DataFile Version 2.0\nt\nASCII\nDATASET STRUCTURED_POINTS\n\
+            DIMENSIONS 1 1 1\nPOINT_DATA 1\nSCALARS v float 3\n1 2 3\n";
+        assert!(matches!(
+            Volume::parse_vtk(vectors),
+            Err(VolumeError::UnsupportedLayout(_))
+        ));
+    }
+
+    #[test]
+    fn samples_map_to_blocks() {
+        let threshold = ScalarMapping::threshold(0.5, Block::new(2));
+        assert_eq!(threshold.get(0.4), Some(Block::new(0)));
+        assert_eq!(threshold.get(0.5), Some(Block::new(2)));
+
+        let transfer = ScalarMapping::transfer(vec![
+            (100.0, Block::new(2)),
+            (10.0, Block::new(1)),
+            (500.0, Block::new(0)),
+        ]);
+        assert_eq!(transfer.get(5.0), Some(Block::new(0)));
+        assert_eq!(transfer.get(10.0), Some(Block::new(1)));
+        assert_eq!(transfer.get(499.0), Some(Block::new(2)));
+        assert_eq!(transfer.get(1000.0), Some(Block::new(0)));
+
+        let ids = ScalarMapping::Ids;
+        assert_eq!(ids.get(2.9), Some(Block::new(3)));
+        assert_eq!(ids.get(-0.2), Some(Block::new(0)));
+        assert_eq!(
+            ids.get((MAX_BLOCK_ID - 1) as f64),
+            Some(Block::new(MAX_BLOCK_ID - 1))
+        );
+        for v in [-1.0, 5000.0, MAX_BLOCK_ID as f64, f64::NAN, f64::INFINITY] {
+            assert_eq!(ids.get(v), None, "{}", v);
+        }
+    }
+
+    #[test]
+    fn samples_that_arent_block_ids_are_refused() {
+        let volume = Volume::new([2, 1, 1], ElementType::U16, vec![1.0, 5000.0]).unwrap();
+        let mut world = World::default();
+
+        assert!(matches!(
+            volume.place(&mut world, WorldBlockPos(0, 0, 0), &ScalarMapping::Ids),
+            Err(VolumeError::InvalidBlockId { index: 1, value }) if value == 5000.0
+        ));
+        assert_eq!(world.get_block(&WorldBlockPos(0, 0, 0)), Block::new(0));
+    }
+
+    #[test]
+    fn volumes_are_placed_in_the_world() {
+        let volume = Volume::new([2, 2, 1], ElementType::F32, vec![0.0, 0.7, 0.2, 0.9]).unwrap();
+        let mut world = World::default();
+        let placed = volume
+            .place(
+                &mut world,
+                WorldBlockPos(-1, 5, 3),
+                &ScalarMapping::threshold(0.5, Block::new(2)),
+            )
+            .unwrap();
+
+        assert_eq!(placed, 2);
+        assert_eq!(world.get_block(&WorldBlockPos(0, 5, 3)), Block::new(2));
+        assert_eq!(world.get_block(&WorldBlockPos(0, 6, 3)), Block::new(2));
+        assert_eq!(world.get_block(&WorldBlockPos(-1, 5, 3)), Block::new(0));
+        assert_eq!(world.get_block(&WorldBlockPos(-1, 6, 3)), Block::new(0));
+    }
+
+    #[test]
+    fn exported_worlds_come_back() {
+        let mut world = World::default();
+        world.set_block(WorldBlockPos(-1, 0, 2), Block::new(1));
+        world.set_block(WorldBlockPos(1, 1, 2), Block::new(300));
+
+        let min = WorldBlockPos(-2, 0, 1);
+        let max = WorldBlockPos(3, 2, 3);
+        let volume = Volume::from_world(&world, min, max);
+        assert_eq!(volume.dims(), [5, 2, 2]);
+        assert_eq!(volume.element(), ElementType::U16);
+
+        for data in [volume.to_nrrd(Encoding::Gzip).unwrap(), volume.to_vtk(true)] {
+            let read = Volume::parse_nrrd(&data).or_else(|_| Volume::parse_vtk(&data));
+            let mut copy = World::default();
+            assert_eq!(
+                read.unwrap()
+                    .place(&mut copy, min, &ScalarMapping::Ids)
+                    .unwrap(),
+                2
+            );
+            assert!(copy.iter_box(min, max).eq(world.iter_box(min, max)));
+        }
+    }
+}
@@ -0,0 +1,1301 @@
+//! MagicaVoxel `.vox` files.
+//!
+//! A file is a tree of chunks inside a `MAIN` chunk. Models are a `SIZE`
+//! chunk followed by an `XYZI` chunk with the voxels, `RGBA` holds the
+//! palette, and `nTRN`/`nGRP`/`nSHP` nodes place the models in the scene.
+//! Other chunks (materials, layers, cameras...) are skipped.
+//!
+//! MagicaVoxel has z up, so scenes are turned a quarter turn about x when
+//! placed in the world: `(x, y, z)` in the scene is `(x, z, -y)` in the world.
+//! Just swapping y and z would mirror them. Models placed by the scene graph
+//! are centred on their translation like they are in MagicaVoxel; files
+//! without a scene graph have every model at the origin.
+//!
+//! Exported regions are split into models of at most `MAX_MODEL_SIZE` along
+//! each axis, with a palette entry for each block type.
+
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    chunk::{block::Block, registry::BlockRegistry},
+    world::{World, WorldBlockPos},
+};
+
+const MAGIC: &[u8; 4] = b"VOX ";
+const VERSION: u32 = 200;
+
+/// Models can't be bigger than this along any axis.
+pub const MAX_MODEL_SIZE: u32 = 256;
+
+/// Colour of each palette index, index 0 is unused as it means no voxel.
+pub type VoxPalette = [[u8; 4]; 256];
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(std::io::Error),
+    /// The file doesn't start with the `.vox` magic bytes.
+    NotAVox,
+    UnsupportedVersion(u32),
+    /// The file ended in the middle of a chunk.
+    UnexpectedEnd {
+        chunk: String,
+    },
+    MissingMain,
+    /// An `XYZI` chunk without a `SIZE` chunk before it.
+    MissingSize,
+    /// A chunk has contents that don't make sense.
+    InvalidChunk {
+        chunk: String,
+        reason: String,
+    },
+    /// A voxel is outside of its model's size.
+    VoxelOutOfBounds {
+        model: usize,
+        pos: (u8, u8, u8),
+    },
+    /// A scene node refers to a model or node that doesn't exist.
+    InvalidReference {
+        node: i32,
+        reason: String,
+    },
+    /// A model is bigger than `MAX_MODEL_SIZE`.
+    ModelTooLarge {
+        model: usize,
+        size: (u32, u32, u32),
+    },
+    /// The palette only has room for 255 block types.
+    TooManyBlockTypes(usize),
+}
+
+impl std::fmt::Display for VoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VoxError::Io(e) => write!(f, "couldn't read vox file: {}", e),
+            VoxError::NotAVox => write!(f, "not a MagicaVoxel file"),
+            VoxError::UnsupportedVersion(v) => write!(f, "unsupported vox version {}", v),
+            VoxError::UnexpectedEnd { chunk } => {
+                write!(f, "file ended in the middle of a `{}` chunk", chunk)
+            }
+            VoxError::MissingMain => write!(f, "file has no `MAIN` chunk"),
+            VoxError::MissingSize => write!(f, "`XYZI` chunk without a `SIZE` chunk"),
+            VoxError::InvalidChunk { chunk, reason } => {
+                write!(f, "invalid `{}` chunk: {}", chunk, reason)
+            }
+            VoxError::VoxelOutOfBounds { model, pos } => {
+                write!(f, "voxel {:?} is outside of model {}", pos, model)
+            }
+            VoxError::InvalidReference { node, reason } => {
+                write!(f, "scene node {}: {}", node, reason)
+            }
+            VoxError::ModelTooLarge { model, size } => write!(
+                f,
+                "model {} is {:?}, models can be at most {} along each axis",
+                model, size, MAX_MODEL_SIZE
+            ),
+            VoxError::TooManyBlockTypes(count) => write!(
+                f,
+                "{} block types don't fit in a vox palette, at most 255 do",
+                count
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VoxError {}
+
+impl From<std::io::Error> for VoxError {
+    fn from(e: std::io::Error) -> Self {
+        VoxError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoxModel {
+    pub size: (u32, u32, u32),
+    /// (x, y, z, palette index) of each voxel, in MagicaVoxel coordinates.
+    pub voxels: Vec<(u8, u8, u8, u8)>,
+}
+
+/// A model placed in the scene, in MagicaVoxel coordinates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoxInstance {
+    pub model: usize,
+    /// Signed permutation matrix, rows by columns.
+    pub rotation: [[i32; 3]; 3],
+    pub translation: (i32, i32, i32),
+    /// Whether the model is centred on the translation, as models placed by
+    /// the scene graph are.
+    pub centred: bool,
+}
+
+impl VoxInstance {
+    /// Position of a voxel of the model in the scene, `None` if it doesn't
+    /// fit in an `i32`.
+    pub fn transform(&self, model: &VoxModel, (x, y, z): (u8, u8, u8)) -> Option<(i32, i32, i32)> {
+        let v = if self.centred {
+            [
+                x as i32 - (model.size.0 / 2) as i32,
+                y as i32 - (model.size.1 / 2) as i32,
+                z as i32 - (model.size.2 / 2) as i32,
+            ]
+        } else {
+            [x as i32, y as i32, z as i32]
+        };
+
+        offset(self.translation, mul_vec(&self.rotation, v)?)
+    }
+}
+
+const IDENTITY: [[i32; 3]; 3] = [[1, 0, 0], [0, 1, 0], [0, 0, 1]];
+
+/// The models, palette and placements read from a `.vox` file.
+#[derive(Debug, Clone)]
+pub struct VoxScene {
+    pub models: Vec<VoxModel>,
+    /// `None` if the file has no `RGBA` chunk, in which case MagicaVoxel uses
+    /// its default palette.
+    pub palette: Option<Box<VoxPalette>>,
+    pub instances: Vec<VoxInstance>,
+}
+
+/// Which block each palette index becomes. Voxels with unmapped indices are
+/// skipped.
+#[derive(Debug, Clone)]
+pub struct PaletteMapping {
+    blocks: [Option<Block>; 256],
+}
+
+impl Default for PaletteMapping {
+    fn default() -> Self {
+        Self {
+            blocks: [None; 256],
+        }
+    }
+}
+
+impl PaletteMapping {
+    /// Maps every palette index to the same block.
+    pub fn uniform(block: Block) -> Self {
+        let mut mapping = Self {
+            blocks: [Some(block); 256],
+        };
+        mapping.blocks[0] = None;
+        mapping
+    }
+
+    /// Maps each palette index to the visible block with the closest colour.
+    pub fn by_colour(palette: &VoxPalette, registry: &BlockRegistry) -> Self {
+        let candidates: Vec<_> = registry
+            .definitions()
+            .iter()
+            .filter(|d| d.visible)
+            .collect();
+
+        let mut mapping = Self::default();
+        for (index, colour) in palette.iter().enumerate().skip(1) {
+            mapping.blocks[index] = candidates
+                .iter()
+                .min_by_key(|d| colour_distance(&d.colour, colour))
+                .map(|d| Block::new(d.id));
+        }
+
+        mapping
+    }
+
+    pub fn with(mut self, index: u8, block: Block) -> Self {
+        self.set(index, Some(block));
+        self
+    }
+
+    pub fn set(&mut self, index: u8, block: Option<Block>) {
+        self.blocks[index as usize] = block;
+    }
+
+    pub fn get(&self, index: u8) -> Option<Block> {
+        self.blocks[index as usize]
+    }
+}
+
+fn colour_distance(a: &[u8; 4], b: &[u8; 4]) -> u32 {
+    (0..3)
+        .map(|i| (a[i] as i32 - b[i] as i32).pow(2) as u32)
+        .sum()
+}
+
+impl VoxScene {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, VoxError> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, VoxError> {
+        let mut reader = Reader::new(data, "header");
+        if reader.bytes(4)? != MAGIC {
+            return Err(VoxError::NotAVox);
+        }
+        let version = reader.u32()?;
+        if version != 150 && version != 200 {
+            return Err(VoxError::UnsupportedVersion(version));
+        }
+
+        if reader.is_empty() {
+            return Err(VoxError::MissingMain);
+        }
+        let main = reader.chunk()?;
+        if main.id != *b"MAIN" {
+            return Err(VoxError::MissingMain);
+        }
+
+        let mut models = Vec::new();
+        let mut palette = None;
+        let mut nodes = HashMap::new();
+        let mut size = None;
+
+        let mut children = Reader::new(main.children, "MAIN");
+        while !children.is_empty() {
+            let chunk = children.chunk()?;
+            let mut content = Reader::new(chunk.content, chunk.name());
+
+            match &chunk.id {
+                b"SIZE" => {
+                    size = Some((content.u32()?, content.u32()?, content.u32()?));
+                }
+                b"XYZI" => {
+                    let size = size.take().ok_or(VoxError::MissingSize)?;
+                    let model = models.len();
+                    let count = content.u32()? as usize;
+
+                    let mut voxels = Vec::with_capacity(count.min(content.remaining() / 4));
+                    for _ in 0..count {
+                        let v = content.bytes(4)?;
+                        let (x, y, z) = (v[0], v[1], v[2]);
+                        if x as u32 >= size.0 || y as u32 >= size.1 || z as u32 >= size.2 {
+                            return Err(VoxError::VoxelOutOfBounds {
+                                model,
+                                pos: (x, y, z),
+                            });
+                        }
+                        voxels.push((x, y, z, v[3]));
+                    }
+
+                    models.push(VoxModel { size, voxels });
+                }
+                b"RGBA" => {
+                    // the colours are stored from index 1 up, the last one is
+                    // unused
+                    let mut colours = Box::new([[0; 4]; 256]);
+                    for colour in colours.iter_mut().skip(1) {
+                        let c = content.bytes(4)?;
+                        *colour = [c[0], c[1], c[2], c[3]];
+                    }
+                    palette = Some(colours);
+                }
+                b"nTRN" | b"nGRP" | b"nSHP" => {
+                    let (id, node) = Node::parse(&chunk.id, &mut content)?;
+                    nodes.insert(id, node);
+                }
+                _ => {}
+            }
+        }
+
+        let instances = if nodes.is_empty() {
+            (0..models.len())
+                .map(|model| VoxInstance {
+                    model,
+                    rotation: IDENTITY,
+                    translation: (0, 0, 0),
+                    centred: false,
+                })
+                .collect()
+        } else {
+            let mut walk = SceneWalk {
+                nodes: &nodes,
+                model_count: models.len(),
+                path: Vec::new(),
+                visited: 0,
+                instances: Vec::new(),
+            };
+            walk.walk(0, IDENTITY, (0, 0, 0))?;
+            walk.instances
+        };
+
+        Ok(Self {
+            models,
+            palette,
+            instances,
+        })
+    }
+
+    /// Sets the blocks of every placed model in the world, with the scene
+    /// origin at `offset`. Returns the number of blocks set. Nothing is set
+    /// if any voxel would end up outside of the world's coordinates.
+    pub fn place(
+        &self,
+        world: &mut World,
+        offset: WorldBlockPos,
+        mapping: &PaletteMapping,
+    ) -> Result<usize, VoxError> {
+        let voxels = || {
+            self.instances.iter().flat_map(|instance| {
+                let model = &self.models[instance.model];
+                model.voxels.iter().filter_map(move |&(x, y, z, index)| {
+                    let block = mapping.get(index)?;
+                    let pos = instance
+                        .transform(model, (x, y, z))
+                        .and_then(|(vx, vy, vz)| {
+                            Some(WorldBlockPos(
+                                offset.0.checked_add(vx)?,
+                                offset.1.checked_add(vz)?,
+                                offset.2.checked_sub(vy)?,
+                            ))
+                        });
+                    Some((pos, block))
+                })
+            })
+        };
+
+        if voxels().any(|(pos, _)| pos.is_none()) {
+            return Err(out_of_range());
+        }
+
+        let mut placed = 0;
+        for (pos, block) in voxels() {
+            world.set_block(pos.unwrap(), block);
+            placed += 1;
+        }
+
+        Ok(placed)
+    }
+}
+
+impl VoxScene {
+    /// Builds a scene from the blocks between `min` (inclusive) and `max`
+    /// (exclusive), with the scene origin at `min`. Air is left out, every
+    /// other block type gets its own palette entry coloured from the registry.
+    /// The returned mapping turns the palette back into blocks, without their
+    /// state.
+    pub fn from_world(
+        world: &World,
+        min: WorldBlockPos,
+        max: WorldBlockPos,
+        registry: &BlockRegistry,
+    ) -> Result<(Self, PaletteMapping), VoxError> {
+        let mut indices = HashMap::<u32, u8>::new();
+        let mut mapping = PaletteMapping::default();
+        let mut palette = Box::new([[0; 4]; 256]);
+        // voxels of each model, by the model's position in the grid of models
+        let mut grid = HashMap::<(u32, u32, u32), Vec<(u8, u8, u8, u8)>>::new();
+
+        for (pos, block) in world.iter_box(min, max) {
+            if block.id() == 0 {
+                continue;
+            }
+
+            let index = match indices.get(&block.id()) {
+                Some(index) => *index,
+                None => {
+                    let count = indices.len() + 1;
+                    if count > 255 {
+                        let mut types: Vec<_> = world
+                            .iter_box(min, max)
+                            .map(|(_, b)| b.id())
+                            .filter(|id| *id != 0)
+                            .collect();
+                        types.sort();
+                        types.dedup();
+                        return Err(VoxError::TooManyBlockTypes(types.len()));
+                    }
+
+                    let index = count as u8;
+                    let colour = registry.get(block).map_or([255, 0, 255, 255], |d| d.colour);
+                    palette[index as usize] = colour;
+                    mapping.set(index, Some(Block::new(block.id())));
+                    indices.insert(block.id(), index);
+                    index
+                }
+            };
+
+            // the inverse of `place`, world z is -y in MagicaVoxel. Counting y
+            // from the far end keeps it positive, the translation undoes it.
+            let (x, y, z) = (
+                (pos.0 - min.0) as u32,
+                (max.2 - 1 - pos.2) as u32,
+                (pos.1 - min.1) as u32,
+            );
+            grid.entry((x / MAX_MODEL_SIZE, y / MAX_MODEL_SIZE, z / MAX_MODEL_SIZE))
+                .or_default()
+                .push((
+                    (x % MAX_MODEL_SIZE) as u8,
+                    (y % MAX_MODEL_SIZE) as u8,
+                    (z % MAX_MODEL_SIZE) as u8,
+                    index,
+                ));
+        }
+
+        let extent = (
+            (max.0 - min.0).max(0) as u32,
+            (max.2 - min.2).max(0) as u32,
+            (max.1 - min.1).max(0) as u32,
+        );
+        let mut cells: Vec<_> = grid.into_iter().collect();
+        cells.sort_by_key(|(cell, _)| *cell);
+
+        let mut models = Vec::new();
+        let mut instances = Vec::new();
+        for ((cx, cy, cz), voxels) in cells {
+            let origin = (
+                cx * MAX_MODEL_SIZE,
+                cy * MAX_MODEL_SIZE,
+                cz * MAX_MODEL_SIZE,
+            );
+            let size = (
+                (extent.0 - origin.0).min(MAX_MODEL_SIZE),
+                (extent.1 - origin.1).min(MAX_MODEL_SIZE),
+                (extent.2 - origin.2).min(MAX_MODEL_SIZE),
+            );
+
+            instances.push(VoxInstance {
+                model: models.len(),
+                rotation: IDENTITY,
+                translation: (
+                    origin.0 as i32,
+                    origin.1 as i32 - (extent.1 as i32 - 1),
+                    origin.2 as i32,
+                ),
+                centred: false,
+            });
+            models.push(VoxModel { size, voxels });
+        }
+
+        let scene = Self {
+            models,
+            palette: Some(palette),
+            instances,
+        };
+        Ok((scene, mapping))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), VoxError> {
+        std::fs::write(path, self.to_bytes()?)?;
+        Ok(())
+    }
+
+    /// Encodes the scene as a `.vox` file, with a scene graph placing every
+    /// instance.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, VoxError> {
+        let mut children = Vec::new();
+
+        for (i, model) in self.models.iter().enumerate() {
+            let (x, y, z) = model.size;
+            if x > MAX_MODEL_SIZE || y > MAX_MODEL_SIZE || z > MAX_MODEL_SIZE {
+                return Err(VoxError::ModelTooLarge {
+                    model: i,
+                    size: model.size,
+                });
+            }
+
+            write_chunk(&mut children, b"SIZE", &u32s(&[x, y, z]), &[]);
+
+            let mut xyzi = u32s(&[model.voxels.len() as u32]);
+            for (x, y, z, index) in &model.voxels {
+                xyzi.extend_from_slice(&[*x, *y, *z, *index]);
+            }
+            write_chunk(&mut children, b"XYZI", &xyzi, &[]);
+        }
+
+        // root transform -> group -> a transform and shape for each instance
+        let mut root = u32s(&[0]);
+        write_dict(&mut root, &[]);
+        root.extend(u32s(&[1, u32::MAX, u32::MAX, 1]));
+        write_dict(&mut root, &[]);
+        write_chunk(&mut children, b"nTRN", &root, &[]);
+
+        let count = self.instances.len() as u32;
+        let mut group = u32s(&[1]);
+        write_dict(&mut group, &[]);
+        group.extend(u32s(&[count]));
+        group.extend(u32s(&(0..count).map(|i| 2 + 2 * i).collect::<Vec<_>>()));
+        write_chunk(&mut children, b"nGRP", &group, &[]);
+
+        for (i, instance) in self.instances.iter().enumerate() {
+            let model =
+                self.models
+                    .get(instance.model)
+                    .ok_or_else(|| VoxError::InvalidReference {
+                        node: 2 + 2 * i as i32,
+                        reason: format!("model {} doesn't exist", instance.model),
+                    })?;
+
+            // MagicaVoxel always centres models on their translation
+            let mut t = instance.translation;
+            if !instance.centred {
+                t = mul_vec(
+                    &instance.rotation,
+                    [
+                        (model.size.0 / 2) as i32,
+                        (model.size.1 / 2) as i32,
+                        (model.size.2 / 2) as i32,
+                    ],
+                )
+                .and_then(|pivot| offset(t, pivot))
+                .ok_or_else(out_of_range)?;
+            }
+
+            let id = 2 + 2 * i as u32;
+            let mut transform = u32s(&[id]);
+            write_dict(&mut transform, &[]);
+            transform.extend(u32s(&[id + 1, u32::MAX, 0, 1]));
+            write_dict(
+                &mut transform,
+                &[
+                    ("_r", &rotation_bits(&instance.rotation).to_string()),
+                    ("_t", &format!("{} {} {}", t.0, t.1, t.2)),
+                ],
+            );
+            write_chunk(&mut children, b"nTRN", &transform, &[]);
+
+            let mut shape = u32s(&[id + 1]);
+            write_dict(&mut shape, &[]);
+            shape.extend(u32s(&[1, instance.model as u32]));
+            write_dict(&mut shape, &[]);
+            write_chunk(&mut children, b"nSHP", &shape, &[]);
+        }
+
+        if let Some(palette) = &self.palette {
+            let mut rgba: Vec<u8> = palette[1..].iter().flatten().copied().collect();
+            rgba.extend_from_slice(&[0; 4]);
+            write_chunk(&mut children, b"RGBA", &rgba, &[]);
+        }
+
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        write_chunk(&mut out, b"MAIN", &[], &children);
+        Ok(out)
+    }
+}
+
+fn u32s(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(content.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(children.len() as u32).to_le_bytes());
+    out.extend_from_slice(content);
+    out.extend_from_slice(children);
+}
+
+fn write_dict(out: &mut Vec<u8>, pairs: &[(&str, &str)]) {
+    out.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+    for (key, value) in pairs {
+        for s in [key, value] {
+            out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+    }
+}
+
+enum Node {
+    Transform {
+        child: i32,
+        rotation: [[i32; 3]; 3],
+        translation: (i32, i32, i32),
+    },
+    Group {
+        children: Vec<i32>,
+    },
+    Shape {
+        models: Vec<i32>,
+    },
+}
+
+impl Node {
+    fn parse(id: &[u8; 4], content: &mut Reader) -> Result<(i32, Node), VoxError> {
+        let node_id = content.i32()?;
+        content.dict()?;
+
+        let node = match id {
+            b"nTRN" => {
+                let child = content.i32()?;
+                let _reserved = content.i32()?;
+                let _layer = content.i32()?;
+                let frames = content.i32()?;
+
+                // only the first frame is used, animations aren't supported
+                let mut rotation = IDENTITY;
+                let mut translation = (0, 0, 0);
+                if frames > 0 {
+                    let frame = content.dict()?;
+                    if let Some(r) = frame.get("_r") {
+                        rotation =
+                            parse_rotation(r).ok_or_else(|| content.invalid("bad rotation"))?;
+                    }
+                    if let Some(t) = frame.get("_t") {
+                        translation = parse_translation(t)
+                            .ok_or_else(|| content.invalid("bad translation"))?;
+                    }
+                }
+
+                Node::Transform {
+                    child,
+                    rotation,
+                    translation,
+                }
+            }
+            b"nGRP" => {
+                let count = content.u32()?;
+                let children = (0..count)
+                    .map(|_| content.i32())
+                    .collect::<Result<_, _>>()?;
+                Node::Group { children }
+            }
+            _ => {
+                let count = content.u32()?;
+                let mut models = Vec::new();
+                for _ in 0..count {
+                    models.push(content.i32()?);
+                    content.dict()?;
+                }
+                Node::Shape { models }
+            }
+        };
+
+        Ok((node_id, node))
+    }
+}
+
+/// Nodes can't nest deeper than this.
+const MAX_SCENE_DEPTH: usize = 64;
+/// Nodes can be shared, so a small file can list a huge number of them.
+/// Walking the scene stops after visiting this many.
+const MAX_SCENE_NODES: usize = 1 << 20;
+/// The most models a scene can place.
+const MAX_SCENE_INSTANCES: usize = 1 << 16;
+
+/// Flattens the scene graph into instances.
+struct SceneWalk<'a> {
+    nodes: &'a HashMap<i32, Node>,
+    model_count: usize,
+    /// Nodes from the root to the current one, to find cycles.
+    path: Vec<i32>,
+    visited: usize,
+    instances: Vec<VoxInstance>,
+}
+
+impl SceneWalk<'_> {
+    fn walk(
+        &mut self,
+        id: i32,
+        rotation: [[i32; 3]; 3],
+        translation: (i32, i32, i32),
+    ) -> Result<(), VoxError> {
+        let invalid = |reason: &str| VoxError::InvalidReference {
+            node: id,
+            reason: reason.to_string(),
+        };
+        if self.path.contains(&id) {
+            return Err(invalid("scene graph has a cycle"));
+        }
+        if self.path.len() >= MAX_SCENE_DEPTH {
+            return Err(invalid("scene graph is too deep"));
+        }
+        self.visited += 1;
+        if self.visited > MAX_SCENE_NODES {
+            return Err(invalid("scene graph has too many nodes"));
+        }
+        let node = self
+            .nodes
+            .get(&id)
+            .ok_or_else(|| invalid("node doesn't exist"))?;
+
+        self.path.push(id);
+        match node {
+            Node::Transform {
+                child,
+                rotation: r,
+                translation: t,
+            } => {
+                // the child's transform is applied first, then the parent's
+                let translation = mul_vec(&rotation, [t.0, t.1, t.2])
+                    .and_then(|rt| offset(translation, rt))
+                    .ok_or_else(out_of_range)?;
+                self.walk(*child, mul(&rotation, r), translation)?;
+            }
+            Node::Group { children } => {
+                for child in children {
+                    self.walk(*child, rotation, translation)?;
+                }
+            }
+            Node::Shape { models } => {
+                for model in models {
+                    let model = usize::try_from(*model)
+                        .ok()
+                        .filter(|&model| model < self.model_count)
+                        .ok_or_else(|| VoxError::InvalidReference {
+                            node: id,
+                            reason: format!("model {} doesn't exist", model),
+                        })?;
+                    if self.instances.len() == MAX_SCENE_INSTANCES {
+                        return Err(VoxError::InvalidReference {
+                            node: id,
+                            reason: format!(
+                                "scene places more than {} models",
+                                MAX_SCENE_INSTANCES
+                            ),
+                        });
+                    }
+                    self.instances.push(VoxInstance {
+                        model,
+                        rotation,
+                        translation,
+                        centred: true,
+                    });
+                }
+            }
+        }
+        self.path.pop();
+        Ok(())
+    }
+}
+
+fn mul(a: &[[i32; 3]; 3], b: &[[i32; 3]; 3]) -> [[i32; 3]; 3] {
+    std::array::from_fn(|i| std::array::from_fn(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+/// `None` if the result doesn't fit in an `i32`.
+fn mul_vec(a: &[[i32; 3]; 3], v: [i32; 3]) -> Option<[i32; 3]> {
+    let row =
+        |i: usize| (0..3).try_fold(0i32, |sum, k| sum.checked_add(a[i][k].checked_mul(v[k])?));
+    Some([row(0)?, row(1)?, row(2)?])
+}
+
+fn offset(t: (i32, i32, i32), v: [i32; 3]) -> Option<(i32, i32, i32)> {
+    Some((
+        t.0.checked_add(v[0])?,
+        t.1.checked_add(v[1])?,
+        t.2.checked_add(v[2])?,
+    ))
+}
+
+fn out_of_range() -> VoxError {
+    VoxError::InvalidChunk {
+        chunk: "nTRN".to_string(),
+        reason: "translation is out of range".to_string(),
+    }
+}
+
+/// Rotations are stored as a byte: bits 0-1 and 2-3 are the column of the
+/// non-zero entry in the first and second rows, bits 4-6 are set if the entry
+/// in each row is negative.
+fn parse_rotation(value: &str) -> Option<[[i32; 3]; 3]> {
+    let bits: u8 = value.trim().parse().ok()?;
+    let first = (bits & 3) as usize;
+    let second = ((bits >> 2) & 3) as usize;
+    if first > 2 || second > 2 || first == second {
+        return None;
+    }
+    let columns = [first, second, 3 - first - second];
+
+    let mut rotation = [[0; 3]; 3];
+    for (row, column) in columns.iter().enumerate() {
+        rotation[row][*column] = if bits & (1 << (4 + row)) != 0 { -1 } else { 1 };
+    }
+
+    Some(rotation)
+}
+
+/// Inverse of `parse_rotation`.
+fn rotation_bits(rotation: &[[i32; 3]; 3]) -> u8 {
+    let column = |row: usize| (0..3).find(|c| rotation[row][*c] != 0).unwrap_or(row);
+
+    let mut bits = column(0) as u8 | (column(1) as u8) << 2;
+    for (row, entries) in rotation.iter().enumerate() {
+        if entries.iter().any(|e| *e < 0) {
+            bits |= 1 << (4 + row);
+        }
+    }
+
+    bits
+}
+
+fn parse_translation(value: &str) -> Option<(i32, i32, i32)> {
+    let mut parts = value.split_whitespace().map(|p| p.parse::<i32>());
+    let t = (
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+    );
+    parts.next().is_none().then_some(t)
+}
+
+struct RawChunk<'a> {
+    id: [u8; 4],
+    content: &'a [u8],
+    children: &'a [u8],
+}
+
+impl RawChunk<'_> {
+    fn name(&self) -> &'static str {
+        match &self.id {
+            b"SIZE" => "SIZE",
+            b"XYZI" => "XYZI",
+            b"RGBA" => "RGBA",
+            b"nTRN" => "nTRN",
+            b"nGRP" => "nGRP",
+            b"nSHP" => "nSHP",
+            _ => "unknown",
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Name of the chunk being read, for errors.
+    chunk: &'static str,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], chunk: &'static str) -> Self {
+        Self {
+            data,
+            pos: 0,
+            chunk,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], VoxError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| VoxError::UnexpectedEnd {
+                chunk: self.chunk.to_string(),
+            })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, VoxError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, VoxError> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn string(&mut self) -> Result<String, VoxError> {
+        let len = self.u32()? as usize;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| self.invalid("string isn't utf-8"))
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+        let count = self.u32()?;
+        (0..count)
+            .map(|_| Ok((self.string()?, self.string()?)))
+            .collect()
+    }
+
+    fn chunk(&mut self) -> Result<RawChunk<'a>, VoxError> {
+        let id = self.bytes(4)?;
+        let id = [id[0], id[1], id[2], id[3]];
+        let content_len = self.u32()? as usize;
+        let children_len = self.u32()? as usize;
+
+        Ok(RawChunk {
+            id,
+            content: self.bytes(content_len)?,
+            children: self.bytes(children_len)?,
+        })
+    }
+
+    fn invalid(&self, reason: &str) -> VoxError {
+        VoxError::InvalidChunk {
+            chunk: self.chunk.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], content: &[u8], children: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_chunk(&mut out, id, content, children);
+        out
+    }
+
+    fn ints(values: &[i32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn dict(pairs: &[(&str, &str)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_dict(&mut out, pairs);
+        out
+    }
+
+    fn model(size: (i32, i32, i32), voxels: &[[u8; 4]]) -> Vec<u8> {
+        let mut out = chunk(b"SIZE", &ints(&[size.0, size.1, size.2]), &[]);
+        let mut xyzi = ints(&[voxels.len() as i32]);
+        xyzi.extend(voxels.iter().flatten());
+        out.extend(chunk(b"XYZI", &xyzi, &[]));
+        out
+    }
+
+    fn file(children: &[u8]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&ints(&[150]));
+        out.extend(chunk(b"MAIN", &[], children));
+        out
+    }
+
+    #[test]
+    fn models_without_a_scene_are_at_the_origin() {
+        let mut children = model((2, 3, 4), &[[0, 0, 0, 1], [1, 2, 3, 2]]);
+        let mut rgba = vec![0; 256 * 4];
+        rgba[4..8].copy_from_slice(&[0x7f, 0x7f, 0x7f, 0xff]);
+        children.extend(chunk(b"RGBA", &rgba, &[]));
+
+        let scene = VoxScene::parse(&file(&children)).unwrap();
+        assert_eq!(scene.models.len(), 1);
+        assert_eq!(scene.palette.as_ref().unwrap()[2], [0x7f, 0x7f, 0x7f, 0xff]);
+
+        // index 2 is grey like stone, the others are unmapped
+        let mapping = PaletteMapping::default().with(1, Block(1));
+        let mut world = World::default();
+        let placed = scene
+            .place(&mut world, WorldBlockPos(10, 0, -5), &mapping)
+            .unwrap();
+
+        assert_eq!(placed, 1);
+        assert_eq!(world.get_block(&WorldBlockPos(10, 0, -5)), Block(1));
+        // (1, 2, 3) is at (1, 3, -2) from the offset
+        assert_eq!(world.get_block(&WorldBlockPos(11, 3, -7)), Block(0));
+
+        let by_colour =
+            PaletteMapping::by_colour(scene.palette.as_ref().unwrap(), BlockRegistry::global());
+        assert_eq!(by_colour.get(2), Some(Block(2)));
+        scene
+            .place(&mut world, WorldBlockPos(10, 0, -5), &by_colour)
+            .unwrap();
+        assert_eq!(world.get_block(&WorldBlockPos(11, 3, -7)), Block(2));
+    }
+
+    #[test]
+    fn handedness_is_kept() {
+        // an L with a different block on each arm, and a post going up
+        let voxels = [[0, 0, 0, 1], [1, 0, 0, 2], [0, 1, 0, 3], [0, 0, 1, 4]];
+        let scene = VoxScene::parse(&file(&model((2, 2, 2), &voxels))).unwrap();
+        let mapping = (1..=4).fold(PaletteMapping::default(), |m, i| m.with(i, Block(i as u32)));
+
+        let mut world = World::default();
+        assert_eq!(
+            scene
+                .place(&mut world, WorldBlockPos(0, 0, 0), &mapping)
+                .unwrap(),
+            4
+        );
+        let find = |block| {
+            let pos = world
+                .iter_box(WorldBlockPos(-2, -2, -2), WorldBlockPos(3, 3, 3))
+                .find(|(_, b)| *b == block)
+                .unwrap()
+                .0;
+            [pos.0, pos.1, pos.2]
+        };
+        let [x, y, up] = [2, 3, 4].map(|i| find(Block(i)));
+
+        // up is up, and x cross y is still up, which a mirror would flip
+        assert_eq!(up, [0, 1, 0]);
+        let cross = [
+            x[1] * y[2] - x[2] * y[1],
+            x[2] * y[0] - x[0] * y[2],
+            x[0] * y[1] - x[1] * y[0],
+        ];
+        assert_eq!(cross, up);
+
+        // and exporting turns it back
+        let (exported, mapping) = VoxScene::from_world(
+            &world,
+            WorldBlockPos(0, 0, -1),
+            WorldBlockPos(2, 2, 1),
+            BlockRegistry::global(),
+        )
+        .unwrap();
+        let exported = VoxScene::parse(&exported.to_bytes().unwrap()).unwrap();
+        let instance = &exported.instances[0];
+        let model = &exported.models[instance.model];
+        let positions: HashMap<_, _> = model
+            .voxels
+            .iter()
+            .map(|&(x, y, z, i)| {
+                (
+                    mapping.get(i).unwrap(),
+                    instance.transform(model, (x, y, z)).unwrap(),
+                )
+            })
+            .collect();
+        let corner = positions[&Block(1)];
+        for (block, offset) in [(2, (1, 0, 0)), (3, (0, 1, 0)), (4, (0, 0, 1))] {
+            let pos = positions[&Block(block)];
+            assert_eq!(
+                (pos.0 - corner.0, pos.1 - corner.1, pos.2 - corner.2),
+                offset
+            );
+        }
+    }
+
+    #[test]
+    fn scene_graph_moves_and_rotates_models() {
+        let mut children = model((3, 1, 1), &[[0, 0, 0, 1], [2, 0, 0, 2]]);
+
+        // root transform -> group -> transform -> shape
+        let mut root = ints(&[0]);
+        root.extend(dict(&[]));
+        root.extend(ints(&[1, -1, -1, 1]));
+        root.extend(dict(&[("_t", "100 0 0")]));
+        children.extend(chunk(b"nTRN", &root, &[]));
+
+        let mut group = ints(&[1]);
+        group.extend(dict(&[]));
+        group.extend(ints(&[1, 2]));
+        children.extend(chunk(b"nGRP", &group, &[]));
+
+        // rotate 90 degrees about z, x becomes y
+        let mut inner = ints(&[2]);
+        inner.extend(dict(&[]));
+        inner.extend(ints(&[3, -1, 0, 1]));
+        inner.extend(dict(&[("_r", "17"), ("_t", "0 5 0")]));
+        children.extend(chunk(b"nTRN", &inner, &[]));
+
+        let mut shape = ints(&[3]);
+        shape.extend(dict(&[]));
+        shape.extend(ints(&[1, 0]));
+        shape.extend(dict(&[]));
+        children.extend(chunk(b"nSHP", &shape, &[]));
+
+        let scene = VoxScene::parse(&file(&children)).unwrap();
+        assert_eq!(scene.instances.len(), 1);
+
+        // rows (0, -1, 0), (1, 0, 0), (0, 0, 1): x -> y, y -> -x
+        let instance = &scene.instances[0];
+        assert_eq!(instance.rotation, [[0, -1, 0], [1, 0, 0], [0, 0, 1]]);
+        assert_eq!(instance.translation, (100, 5, 0));
+
+        // the model is centred, so voxel 0 is at -1 and voxel 2 at 1
+        let model = &scene.models[0];
+        assert_eq!(instance.transform(model, (0, 0, 0)), Some((100, 4, 0)));
+        assert_eq!(instance.transform(model, (2, 0, 0)), Some((100, 6, 0)));
+    }
+
+    #[test]
+    fn shared_children_dont_blow_up_the_scene() {
+        // each group lists the next one twice, so the shape is reached 2^40 times
+        let mut children = model((1, 1, 1), &[[0, 0, 0, 1]]);
+        for id in 0..40 {
+            let mut group = ints(&[id]);
+            group.extend(dict(&[]));
+            group.extend(ints(&[2, id + 1, id + 1]));
+            children.extend(chunk(b"nGRP", &group, &[]));
+        }
+        let mut shape = ints(&[40]);
+        shape.extend(dict(&[]));
+        shape.extend(ints(&[1, 0]));
+        shape.extend(dict(&[]));
+        children.extend(chunk(b"nSHP", &shape, &[]));
+
+        assert!(matches!(
+            VoxScene::parse(&file(&children)),
+            Err(VoxError::InvalidReference { .. })
+        ));
+    }
+
+    #[test]
+    fn translations_out_of_range_are_rejected() {
+        // two transforms that each move by nearly i32::MAX
+        let mut children = model((1, 1, 1), &[[0, 0, 0, 1]]);
+        for id in 0..2 {
+            let mut transform = ints(&[id]);
+            transform.extend(dict(&[]));
+            transform.extend(ints(&[id + 1, -1, -1, 1]));
+            transform.extend(dict(&[("_t", "2000000000 0 0")]));
+            children.extend(chunk(b"nTRN", &transform, &[]));
+        }
+        let mut shape = ints(&[2]);
+        shape.extend(dict(&[]));
+        shape.extend(ints(&[1, 0]));
+        shape.extend(dict(&[]));
+        children.extend(chunk(b"nSHP", &shape, &[]));
+        assert!(matches!(
+            VoxScene::parse(&file(&children)),
+            Err(VoxError::InvalidChunk { .. })
+        ));
+
+        // the placed voxel would be past the end of the world's coordinates
+        let mut children = model((1, 1, 1), &[[0, 0, 0, 1]]);
+        let mut transform = ints(&[0]);
+        transform.extend(dict(&[]));
+        transform.extend(ints(&[1, -1, -1, 1]));
+        transform.extend(dict(&[("_t", "2000000000 0 0")]));
+        children.extend(chunk(b"nTRN", &transform, &[]));
+        let mut shape = ints(&[1]);
+        shape.extend(dict(&[]));
+        shape.extend(ints(&[1, 0]));
+        shape.extend(dict(&[]));
+        children.extend(chunk(b"nSHP", &shape, &[]));
+        let far = VoxScene::parse(&file(&children)).unwrap();
+
+        let mut world = World::default();
+        let mapping = PaletteMapping::uniform(Block(1));
+        assert!(matches!(
+            far.place(&mut world, WorldBlockPos(i32::MAX - 10, 0, 0), &mapping),
+            Err(VoxError::InvalidChunk { .. })
+        ));
+        assert!(far
+            .place(&mut world, WorldBlockPos(-2_000_000_000, 0, 0), &mapping)
+            .is_ok());
+        assert_eq!(world.get_block(&WorldBlockPos(0, 0, 0)), Block(1));
+
+        // the shape that refers to a missing model is the one reported
+        let mut children = model((1, 1, 1), &[[0, 0, 0, 1]]);
+        let mut group = ints(&[0]);
+        group.extend(dict(&[]));
+        group.extend(ints(&[2, 1, 2]));
+        children.extend(chunk(b"nGRP", &group, &[]));
+        for (id, model_id) in [(1, 0), (2, 3)] {
+            let mut shape = ints(&[id]);
+            shape.extend(dict(&[]));
+            shape.extend(ints(&[1, model_id]));
+            shape.extend(dict(&[]));
+            children.extend(chunk(b"nSHP", &shape, &[]));
+        }
+        assert!(matches!(
+            VoxScene::parse(&file(&children)),
+            Err(VoxError::InvalidReference { node: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        assert!(matches!(
+            VoxScene::parse(b"PNG?...."),
+            Err(VoxError::NotAVox)
+        ));
+
+        let mut wrong_version = file(&[]);
+        wrong_version[4] = 3;
+        assert!(matches!(
+            VoxScene::parse(&wrong_version),
+            Err(VoxError::UnsupportedVersion(3))
+        ));
+
+        let xyzi_only = chunk(b"XYZI", &ints(&[0]), &[]);
+        assert!(matches!(
+            VoxScene::parse(&file(&xyzi_only)),
+            Err(VoxError::MissingSize)
+        ));
+
+        let outside = model((2, 2, 2), &[[2, 0, 0, 1]]);
+        assert!(matches!(
+            VoxScene::parse(&file(&outside)),
+            Err(VoxError::VoxelOutOfBounds { model: 0, .. })
+        ));
+
+        let valid = file(&model((2, 2, 2), &[[1, 1, 1, 1]]));
+        assert!(matches!(
+            VoxScene::parse(&valid[..valid.len() - 2]),
+            Err(VoxError::UnexpectedEnd { .. })
+        ));
+
+        // a transform pointing at itself
+        let mut looping = ints(&[0]);
+        looping.extend(dict(&[]));
+        looping.extend(ints(&[0, -1, -1, 0]));
+        assert!(matches!(
+            VoxScene::parse(&file(&chunk(b"nTRN", &looping, &[]))),
+            Err(VoxError::InvalidReference { .. })
+        ));
+    }
+
+    #[test]
+    fn exported_regions_import_back() {
+        let mut world = World::default();
+        let min = WorldBlockPos(-20, -3, 5);
+        let max = WorldBlockPos(280, 4, 9);
+
+        world.fill_box(
+            WorldBlockPos(-20, -3, 5),
+            WorldBlockPos(-10, -2, 9),
+            Block(2),
+        );
+        world.set_block(WorldBlockPos(279, 3, 8), Block(3).with_level(2));
+        world.set_block(WorldBlockPos(100, 0, 6), Block(1));
+        // outside of the region
+        world.set_block(WorldBlockPos(280, 0, 6), Block(1));
+
+        let (scene, mapping) =
+            VoxScene::from_world(&world, min, max, BlockRegistry::global()).unwrap();
+
+        // 300 blocks wide, so it takes two models
+        assert_eq!(scene.models.len(), 2);
+        assert_eq!(scene.models[0].size, (256, 4, 7));
+        assert_eq!(scene.models[1].size, (44, 4, 7));
+        let palette = scene.palette.as_ref().unwrap();
+        assert_eq!(palette[1], [0x7f, 0x7f, 0x7f, 0xff]);
+
+        let parsed = VoxScene::parse(&scene.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.models, scene.models);
+
+        let mut imported = World::default();
+        parsed.place(&mut imported, min, &mapping).unwrap();
+        for (pos, block) in world.iter_box(min, max) {
+            let expected = Block::new(block.id());
+            assert_eq!(imported.get_block(&pos), expected, "{:?}", pos);
+        }
+        assert_eq!(imported.get_block(&WorldBlockPos(280, 0, 6)), Block(0));
+    }
+
+    #[test]
+    fn rotations_survive_writing() {
+        for bits in [0b0000100, 17, 0b1101001, 0b0100110] {
+            let rotation = parse_rotation(&bits.to_string()).unwrap();
+            assert_eq!(rotation_bits(&rotation), bits);
+        }
+
+        let model = VoxModel {
+            size: (3, 2, 1),
+            voxels: vec![(0, 0, 0, 1), (2, 1, 0, 1)],
+        };
+        let instance = VoxInstance {
+            model: 0,
+            rotation: parse_rotation("17").unwrap(),
+            translation: (4, -2, 7),
+            centred: false,
+        };
+        let scene = VoxScene {
+            models: vec![model.clone()],
+            palette: None,
+            instances: vec![instance.clone()],
+        };
+
+        let parsed = VoxScene::parse(&scene.to_bytes().unwrap()).unwrap();
+        for (x, y, z, _) in &model.voxels {
+            assert_eq!(
+                parsed.instances[0].transform(&model, (*x, *y, *z)),
+                instance.transform(&model, (*x, *y, *z))
+            );
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::{sync::Arc, time::Instant};
+use std::{path::Path, sync::Arc, time::Instant};
 
 use winit::{
     application::ApplicationHandler,
@@ -9,13 +9,18 @@ use winit::{
     window::{Window, WindowId},
 };
 
-use crate::{chunk::manager::ChunkManager, input::Input, player::Player};
+use crate::{
+    chunk::{manager::ChunkManager, registry::BlockRegistry},
+    formats::heightmap::{Heightmap, HeightmapGenerator},
+    input::Input,
+    player::Player,
+    world::WorldBlockPos,
+};
 
 use super::window_state::WindowState;
 
 /// TODO:
 /// - Visibility graphs?
-/// - Chunk padding?
 /// - Real terrain
 /// - SSAO
 /// - Block textures/colors?
@@ -53,10 +58,44 @@ impl ApplicationHandler for Game {
 
         let w = pollster::block_on(WindowState::new(window));
 
+        // the block definitions have to be installed before any chunks are
+        // created, otherwise the defaults are used
+        match BlockRegistry::load("./assets/blocks.txt") {
+            Ok(registry) => {
+                if registry.install().is_err() {
+                    log::warn!("Block registry was already in use, keeping the current one.");
+                }
+            }
+            Err(e) => log::warn!("Using default block definitions: {}", e),
+        }
+
         // set up game objects, player is set up by Default
 
         self.chunk_m.init(&w);
 
+        // terrain comes from a heightmap if there is one, centred on the
+        // origin, otherwise it is random
+        for path in ["./assets/heightmap.png", "./assets/heightmap.pgm"] {
+            if !Path::new(path).exists() {
+                continue;
+            }
+            match Heightmap::load(path) {
+                Ok(map) => {
+                    let origin =
+                        WorldBlockPos(-(map.width() as i32) / 2, 0, -(map.depth() as i32) / 2);
+                    self.chunk_m
+                        .set_generator(HeightmapGenerator::new(map).with_origin(origin));
+                    break;
+                }
+                Err(e) => log::warn!("Couldn't load heightmap {}: {}", path, e),
+            }
+        }
+
+        // changed chunks are kept between runs
+        if let Err(e) = self.chunk_m.open_world("./world") {
+            log::warn!("Chunks won't be saved: {}", e);
+        }
+
         self.chunk_m.load_chunks(&w, &self.player);
 
         self.window = Some(w);
@@ -146,4 +185,8 @@ impl ApplicationHandler for Game {
             }
         }
     }
+
+    fn exiting(&mut self, _event_loop: &ActiveEventLoop) {
+        self.chunk_m.save_dirty();
+    }
 }
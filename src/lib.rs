@@ -5,11 +5,13 @@ use winit::{
 };
 
 pub mod chunk;
+pub mod formats;
 pub mod game;
 pub mod input;
 pub mod player;
 pub mod util;
 pub mod window_state;
+pub mod world;
 
 pub fn run() -> Result<(), EventLoopError> {
     let event_loop = EventLoop::new().unwrap();
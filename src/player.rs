@@ -3,11 +3,7 @@ use core::f32;
 use cgmath::{InnerSpace, Matrix4, SquareMatrix};
 use winit::keyboard::KeyCode;
 
-use crate::{
-    chunk::{ChunkPos, CHUNK_SIZE},
-    input::Input,
-    window_state::WindowState,
-};
+use crate::{chunk::ChunkPos, input::Input, window_state::WindowState, world::WorldBlockPos};
 
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
@@ -53,22 +49,7 @@ impl Player {
     }
 
     pub fn get_chunk_pos(&self) -> ChunkPos {
-        let x = (self.position.x / CHUNK_SIZE as f32).floor() as i32;
-        // if self.position.x < 0.0 {
-        //     x -= 1;
-        // }
-
-        let y = (self.position.y / CHUNK_SIZE as f32).floor() as i32;
-        // if self.position.y < 0.0 {
-        //     y -= 1;
-        // }
-
-        let z = (self.position.z / CHUNK_SIZE as f32).floor() as i32;
-        // if self.position.z < 0.0 {
-        //     z -= 1;
-        // }
-
-        ChunkPos(x, y, z)
+        WorldBlockPos::from_point(self.position).chunk_pos()
     }
 
     pub fn resize(&mut self, aspect: f32) {
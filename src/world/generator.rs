@@ -0,0 +1,85 @@
+//! Creating chunks that haven't been saved before.
+
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::chunk::{Chunk, ChunkPos};
+
+/// Makes the contents of a chunk the first time it is loaded.
+pub trait ChunkGenerator {
+    /// Short name of the generator, saved with the world.
+    fn name(&self) -> &str;
+
+    fn generate(&self, pos: ChunkPos) -> Chunk;
+
+    /// Seed saved with the world, so its chunks are generated the same way
+    /// when it's opened again. Generators that don't use one return 0.
+    fn seed(&self) -> u64 {
+        0
+    }
+
+    /// Restores the seed of a saved world.
+    fn set_seed(&mut self, _seed: u64) {}
+}
+
+/// Fills chunks with dirt, leaving a few random blocks empty. Each chunk only
+/// depends on the seed and its position.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomGenerator {
+    seed: u64,
+}
+
+impl RandomGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+}
+
+impl Default for RandomGenerator {
+    /// A generator with a random seed.
+    fn default() -> Self {
+        Self::new(rand::random())
+    }
+}
+
+impl ChunkGenerator for RandomGenerator {
+    fn name(&self) -> &str {
+        "random"
+    }
+
+    fn generate(&self, pos: ChunkPos) -> Chunk {
+        // mix the position into the seed so neighbouring chunks differ
+        let mut seed = self.seed;
+        for v in [pos.0, pos.1, pos.2] {
+            seed = (seed ^ v as u32 as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+
+        let mut chunk = Chunk::random_from(&mut StdRng::seed_from_u64(seed));
+        chunk.compact();
+        chunk
+    }
+
+    fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_only_depend_on_the_seed_and_position() {
+        let generator = RandomGenerator::new(7);
+        let chunk = generator.generate(ChunkPos(1, -2, 3));
+
+        let again = RandomGenerator::new(7).generate(ChunkPos(1, -2, 3));
+        assert!(chunk.blocks().eq(again.blocks()));
+
+        let other = generator.generate(ChunkPos(1, -2, 4));
+        assert!(!chunk.blocks().eq(other.blocks()));
+    }
+}
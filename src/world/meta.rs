@@ -0,0 +1,270 @@
+//! The `world.meta` file, describing how a saved world was made.
+//!
+//! It is JSON, so it can be read without vvrs:
+//!
+//! ```text
+//! {
+//!   "format_version": 3,
+//!   "chunk_size": 32,
+//!   "seed": 1234,
+//!   "generator": "random",
+//!   "created": 1700000000,
+//!   "registry": "air 0 false false false #000000 0 0\n..."
+//! }
+//! ```
+//!
+//! `created` is in seconds since the Unix epoch, and `registry` holds the
+//! block definitions in the format of `assets/blocks.txt`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::chunk::{registry::BlockRegistry, CHUNK_SIZE};
+
+use super::{generator::ChunkGenerator, migrate, region::RegionError};
+
+/// Version of the saved world layout, upgraded by `migrate`.
+///
+/// 1. Region payloads hold every block of the chunk.
+/// 2. Region payloads hold the chunk from `Chunk::serialize`.
+/// 3. Region payloads start with a checksum.
+pub const FORMAT_VERSION: u32 = 3;
+
+pub const META_FILE: &str = "world.meta";
+
+#[derive(Debug)]
+pub enum WorldError {
+    Io(std::io::Error),
+    Region(RegionError),
+    /// `world.meta` isn't valid JSON or is missing a field.
+    InvalidMeta(String),
+    /// The world was saved by a newer version of vvrs.
+    UnsupportedVersion(u32),
+    /// The world was saved with a different chunk size.
+    ChunkSizeMismatch(u32),
+}
+
+impl std::fmt::Display for WorldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorldError::Io(e) => write!(f, "world io error: {}", e),
+            WorldError::Region(e) => write!(f, "{}", e),
+            WorldError::InvalidMeta(reason) => write!(f, "invalid {}: {}", META_FILE, reason),
+            WorldError::UnsupportedVersion(v) => write!(
+                f,
+                "world format version {} is newer than the supported version {}",
+                v, FORMAT_VERSION
+            ),
+            WorldError::ChunkSizeMismatch(size) => write!(
+                f,
+                "world was saved with {} block chunks, but this build uses {} block chunks",
+                size, CHUNK_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WorldError {}
+
+impl From<std::io::Error> for WorldError {
+    fn from(e: std::io::Error) -> Self {
+        WorldError::Io(e)
+    }
+}
+
+impl From<RegionError> for WorldError {
+    fn from(e: RegionError) -> Self {
+        WorldError::Region(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorldMeta {
+    pub format_version: u32,
+    pub chunk_size: u32,
+    pub seed: u64,
+    /// Name of the generator that made the chunks.
+    pub generator: String,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /// Block definitions the world was saved with.
+    pub registry: String,
+}
+
+impl WorldMeta {
+    /// Metadata for a world made now with this build.
+    pub fn new(generator: &dyn ChunkGenerator, registry: &BlockRegistry) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        Self {
+            format_version: FORMAT_VERSION,
+            chunk_size: CHUNK_SIZE,
+            seed: generator.seed(),
+            generator: generator.name().to_string(),
+            created,
+            registry: registry.to_text(),
+        }
+    }
+
+    pub fn path(dir: impl AsRef<Path>) -> PathBuf {
+        dir.as_ref().join(META_FILE)
+    }
+
+    /// Reads the metadata of the world in `dir`, `None` if it has none.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Option<Self>, WorldError> {
+        let path = Self::path(dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Self::parse(&fs::read_to_string(path)?).map(Some)
+    }
+
+    pub fn save(&self, dir: impl AsRef<Path>) -> Result<(), WorldError> {
+        Ok(fs::write(Self::path(dir), self.to_json())?)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, WorldError> {
+        let value: Value =
+            serde_json::from_str(source).map_err(|e| WorldError::InvalidMeta(e.to_string()))?;
+
+        let missing = |field: &str| WorldError::InvalidMeta(format!("missing {}", field));
+        let number = |field: &str| value[field].as_u64().ok_or_else(|| missing(field));
+        let small_number = |field: &str| {
+            u32::try_from(number(field)?)
+                .map_err(|_| WorldError::InvalidMeta(format!("{} is out of range", field)))
+        };
+        let string = |field: &str| {
+            value[field]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| missing(field))
+        };
+
+        Ok(Self {
+            format_version: small_number("format_version")?,
+            chunk_size: small_number("chunk_size")?,
+            seed: number("seed")?,
+            generator: string("generator")?,
+            created: number("created")?,
+            registry: string("registry")?,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        let value = json!({
+            "format_version": self.format_version,
+            "chunk_size": self.chunk_size,
+            "seed": self.seed,
+            "generator": self.generator,
+            "created": self.created,
+            "registry": self.registry,
+        });
+        serde_json::to_string_pretty(&value).expect("Metadata is always valid JSON")
+    }
+
+    /// Opens the world in `dir`, upgrading it to the current format if it was
+    /// saved by an older version, or starts a new one made by `generator`.
+    /// Worlds saved before there was a metadata file get one.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        generator: &dyn ChunkGenerator,
+        registry: &BlockRegistry,
+    ) -> Result<Self, WorldError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let mut meta = match Self::load(dir)? {
+            Some(meta) => meta,
+            None => {
+                let mut meta = Self::new(generator, registry);
+                if let Some((version, chunk_size)) = migrate::legacy_format(dir)? {
+                    meta.format_version = version;
+                    meta.chunk_size = chunk_size;
+                }
+                meta
+            }
+        };
+
+        if meta.chunk_size != CHUNK_SIZE {
+            return Err(WorldError::ChunkSizeMismatch(meta.chunk_size));
+        }
+        if meta.format_version > FORMAT_VERSION {
+            return Err(WorldError::UnsupportedVersion(meta.format_version));
+        }
+
+        migrate::migrate(dir, &mut meta)?;
+        meta.save(dir)?;
+        Ok(meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::world::generator::RandomGenerator;
+
+    use super::*;
+
+    #[test]
+    fn new_worlds_keep_their_seed() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = BlockRegistry::default();
+
+        let meta = WorldMeta::open(dir.path(), &RandomGenerator::new(5), &registry).unwrap();
+        assert_eq!(meta.format_version, FORMAT_VERSION);
+        assert_eq!(meta.chunk_size, CHUNK_SIZE);
+        assert_eq!(meta.seed, 5);
+        assert_eq!(meta.generator, "random");
+        assert_eq!(meta.registry, registry.to_text());
+        assert_eq!(WorldMeta::load(dir.path()).unwrap(), Some(meta.clone()));
+
+        let reopened = WorldMeta::open(dir.path(), &RandomGenerator::new(6), &registry).unwrap();
+        assert_eq!(reopened, meta);
+    }
+
+    #[test]
+    fn refuses_other_chunk_sizes_and_newer_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = BlockRegistry::default();
+        let generator = RandomGenerator::new(0);
+
+        let mut meta = WorldMeta::new(&generator, &registry);
+        meta.chunk_size = CHUNK_SIZE * 2;
+        meta.save(dir.path()).unwrap();
+        let error = WorldMeta::open(dir.path(), &generator, &registry).unwrap_err();
+        assert!(matches!(error, WorldError::ChunkSizeMismatch(size) if size == CHUNK_SIZE * 2));
+        assert!(error.to_string().contains(&CHUNK_SIZE.to_string()));
+
+        let mut meta = WorldMeta::new(&generator, &registry);
+        meta.format_version = FORMAT_VERSION + 1;
+        meta.save(dir.path()).unwrap();
+        assert!(matches!(
+            WorldMeta::open(dir.path(), &generator, &registry),
+            Err(WorldError::UnsupportedVersion(_))
+        ));
+
+        fs::write(WorldMeta::path(dir.path()), "{\"format_version\": 2}").unwrap();
+        assert!(matches!(
+            WorldMeta::load(dir.path()),
+            Err(WorldError::InvalidMeta(_))
+        ));
+
+        // 2^32 + 3 would wrap to version 3
+        let mut source = WorldMeta::new(&generator, &registry).to_json();
+        source = source.replace(
+            &format!("\"format_version\": {}", FORMAT_VERSION),
+            "\"format_version\": 4294967299",
+        );
+        assert!(source.contains("4294967299"));
+        assert!(matches!(
+            WorldMeta::parse(&source),
+            Err(WorldError::InvalidMeta(e)) if e.contains("format_version")
+        ));
+    }
+}
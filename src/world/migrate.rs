@@ -0,0 +1,352 @@
+//! Upgrades worlds saved by older versions of vvrs to `FORMAT_VERSION`.
+//!
+//! Each migration upgrades the files of a world by one format version, in
+//! place. The metadata is saved after each one, so an interrupted upgrade
+//! carries on from where it stopped. Region files are always rewritten in the
+//! current format, so later migrations skip files an earlier one wrote.
+
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use flate2::read::ZlibDecoder;
+
+use crate::chunk::{block::Block, Chunk, LocalBlockPos, CHUNK_SIZE, CHUNK_VOLUME};
+
+use super::{
+    meta::{WorldError, WorldMeta, FORMAT_VERSION},
+    region::{self, RegionError, HEADER_LEN, MAGIC, REGION_CHUNKS},
+};
+
+type Migration = fn(&Path) -> Result<(), WorldError>;
+
+/// `MIGRATIONS[i]` upgrades a world from format version `i + 1`.
+const MIGRATIONS: [Migration; FORMAT_VERSION as usize - 1] =
+    [serialized_payloads, checksummed_payloads];
+
+/// Runs the migrations from the world's format version up to the current
+/// one.
+pub fn migrate(dir: &Path, meta: &mut WorldMeta) -> Result<(), WorldError> {
+    while meta.format_version < FORMAT_VERSION {
+        let migration = (meta.format_version as usize)
+            .checked_sub(1)
+            .and_then(|i| MIGRATIONS.get(i))
+            .ok_or_else(|| {
+                WorldError::InvalidMeta(format!("unknown format version {}", meta.format_version))
+            })?;
+
+        log::info!(
+            "Upgrading world in {} from format version {}",
+            dir.display(),
+            meta.format_version
+        );
+        migration(dir)?;
+        meta.format_version += 1;
+        meta.save(dir)?;
+    }
+
+    Ok(())
+}
+
+/// The oldest format version and the chunk size of the region files of a
+/// world saved before it had metadata, `None` if there are no region files.
+pub fn legacy_format(dir: &Path) -> Result<Option<(u32, u32)>, WorldError> {
+    let mut format = None;
+
+    for path in region_files(dir)? {
+        let mut header = [0; 8];
+        fs::File::open(&path)?
+            .read_exact(&mut header)
+            .map_err(|_| RegionError::NotARegion(path.clone()))?;
+        if &header[0..4] != MAGIC {
+            return Err(RegionError::NotARegion(path).into());
+        }
+
+        let version = u16::from_le_bytes([header[4], header[5]]) as u32;
+        let chunk_size = u16::from_le_bytes([header[6], header[7]]) as u32;
+        format = match format {
+            Some((oldest, size)) if oldest <= version => Some((oldest, size)),
+            _ => Some((version, chunk_size)),
+        };
+    }
+
+    Ok(format)
+}
+
+fn region_files(dir: &Path) -> Result<Vec<PathBuf>, WorldError> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "vvr") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Version 1 to 2: region payloads go from every block of the chunk to
+/// `Chunk::serialize`. Chunks that can't be decoded are dropped, so they are
+/// generated again.
+fn serialized_payloads(dir: &Path) -> Result<(), WorldError> {
+    rewrite_regions(dir, 1, |payload| {
+        let chunk = decode_v1_payload(payload)?;
+        region::encode_payload(&chunk).map_err(|e| e.to_string())
+    })
+}
+
+/// Version 2 to 3: region payloads get a checksum.
+fn checksummed_payloads(dir: &Path) -> Result<(), WorldError> {
+    rewrite_regions(dir, 2, |payload| Ok(region::with_checksum(payload)))
+}
+
+/// Rewrites the region files of `version` with each payload converted to the
+/// current format. Each file is written next to the old one and then moved
+/// over it.
+fn rewrite_regions(
+    dir: &Path,
+    version: u16,
+    convert: impl Fn(&[u8]) -> Result<Vec<u8>, String>,
+) -> Result<(), WorldError> {
+    for path in region_files(dir)? {
+        let data = fs::read(&path)?;
+        if data.len() < HEADER_LEN as usize || &data[0..4] != MAGIC {
+            return Err(RegionError::NotARegion(path).into());
+        }
+        if u16::from_le_bytes([data[4], data[5]]) != version {
+            continue;
+        }
+        let chunk_size = u16::from_le_bytes([data[6], data[7]]);
+        if chunk_size as u32 != CHUNK_SIZE {
+            return Err(WorldError::ChunkSizeMismatch(chunk_size as u32));
+        }
+
+        let mut entries = vec![(0u32, 0u32); REGION_CHUNKS];
+        let mut payloads = Vec::new();
+        for (slot, entry) in data[8..HEADER_LEN as usize].chunks_exact(8).enumerate() {
+            let offset = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as usize;
+            let len = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]) as usize;
+            if offset == 0 {
+                continue;
+            }
+
+            let payload = data
+                .get(offset..offset + len)
+                .ok_or_else(|| "payload is past the end of the file".to_string())
+                .and_then(&convert);
+            match payload {
+                Ok(payload) => {
+                    let offset = HEADER_LEN as usize + payloads.len();
+                    entries[slot] = (offset as u32, payload.len() as u32);
+                    payloads.extend(payload);
+                }
+                Err(reason) => log::warn!(
+                    "Dropping chunk {} of {}, it will be regenerated: {}",
+                    slot,
+                    path.display(),
+                    reason
+                ),
+            }
+        }
+
+        let mut upgraded = Vec::with_capacity(HEADER_LEN as usize + payloads.len());
+        upgraded.extend_from_slice(MAGIC);
+        upgraded.extend_from_slice(&region::VERSION.to_le_bytes());
+        upgraded.extend_from_slice(&chunk_size.to_le_bytes());
+        for (offset, len) in entries {
+            upgraded.extend_from_slice(&offset.to_le_bytes());
+            upgraded.extend_from_slice(&len.to_le_bytes());
+        }
+        upgraded.extend(payloads);
+
+        let temp = path.with_extension("vvr.tmp");
+        fs::write(&temp, upgraded)?;
+        fs::rename(&temp, &path)?;
+    }
+
+    Ok(())
+}
+
+/// A zlib compressed array of every block, ordered by x, then y, then z.
+fn decode_v1_payload(payload: &[u8]) -> Result<Chunk, String> {
+    let (&compression, data) = payload.split_first().ok_or("empty payload")?;
+    if compression != region::COMPRESSION_ZLIB {
+        return Err(format!("unknown compression {}", compression));
+    }
+
+    // one byte past the blocks is enough to tell the payload is too long
+    let mut raw = Vec::with_capacity(CHUNK_VOLUME * 4);
+    ZlibDecoder::new(data)
+        .take(CHUNK_VOLUME as u64 * 4 + 1)
+        .read_to_end(&mut raw)
+        .map_err(|e| e.to_string())?;
+    if raw.len() > CHUNK_VOLUME * 4 {
+        return Err(format!(
+            "blocks decompress to more than {} bytes",
+            CHUNK_VOLUME * 4
+        ));
+    }
+    if raw.len() != CHUNK_VOLUME * 4 {
+        return Err(format!(
+            "expected {} bytes of blocks, found {}",
+            CHUNK_VOLUME * 4,
+            raw.len()
+        ));
+    }
+
+    let mut blocks = raw
+        .chunks_exact(4)
+        .map(|b| Block(u32::from_le_bytes([b[0], b[1], b[2], b[3]])));
+    let mut chunk = Chunk::default();
+    for x in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                let block = blocks.next().expect("Length was checked");
+                chunk.set_block(LocalBlockPos(x, y, z), block);
+            }
+        }
+    }
+    chunk.compact();
+
+    Ok(chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use crate::{
+        chunk::{registry::BlockRegistry, ChunkPos},
+        world::{
+            generator::RandomGenerator,
+            region::{RegionPos, RegionStorage},
+        },
+    };
+
+    use super::*;
+
+    fn encode_v1_payload(chunk: &Chunk) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![region::COMPRESSION_ZLIB], Compression::default());
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    let block = chunk.get_block(&LocalBlockPos(x, y, z));
+                    encoder.write_all(&block.0.to_le_bytes()).unwrap();
+                }
+            }
+        }
+        encoder.finish().unwrap()
+    }
+
+    /// A region file of an older `version` holding `chunks`, all in the same
+    /// region.
+    fn old_region(version: u16, chunks: &[(ChunkPos, Vec<u8>)]) -> Vec<u8> {
+        let mut entries = vec![(0u32, 0u32); REGION_CHUNKS];
+        let mut payloads = Vec::new();
+        for (pos, payload) in chunks {
+            let (_, slot) = RegionPos::of_chunk(pos);
+            entries[slot] = (
+                (HEADER_LEN as usize + payloads.len()) as u32,
+                payload.len() as u32,
+            );
+            payloads.extend_from_slice(payload);
+        }
+
+        let mut data = MAGIC.to_vec();
+        data.extend(version.to_le_bytes());
+        data.extend((CHUNK_SIZE as u16).to_le_bytes());
+        for (offset, len) in entries {
+            data.extend(offset.to_le_bytes());
+            data.extend(len.to_le_bytes());
+        }
+        data.extend(payloads);
+        data
+    }
+
+    #[test]
+    fn old_worlds_are_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut chunk = Chunk::default();
+        chunk.fill_region(LocalBlockPos(0, 0, 0), LocalBlockPos(5, 3, 2), Block(2));
+        chunk.set_block(LocalBlockPos(1, 9, 4), Block(3).with_level(2));
+        let saved = ChunkPos(1, 2, 3);
+        let corrupt = ChunkPos(2, 2, 3);
+
+        let (region, _) = RegionPos::of_chunk(&saved);
+        fs::write(
+            dir.path().join(region.file_name()),
+            old_region(
+                1,
+                &[
+                    (saved, encode_v1_payload(&chunk)),
+                    (corrupt, vec![region::COMPRESSION_ZLIB, 1, 2, 3]),
+                ],
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(legacy_format(dir.path()).unwrap(), Some((1, CHUNK_SIZE)));
+        let meta = WorldMeta::open(
+            dir.path(),
+            &RandomGenerator::new(0),
+            &BlockRegistry::default(),
+        )
+        .unwrap();
+        assert_eq!(meta.format_version, FORMAT_VERSION);
+        assert_eq!(
+            legacy_format(dir.path()).unwrap(),
+            Some((region::VERSION as u32, CHUNK_SIZE))
+        );
+
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+        let loaded = storage.load_chunk(&saved).unwrap().unwrap();
+        assert!(loaded.blocks().eq(chunk.blocks()));
+        assert!(storage.load_chunk(&corrupt).unwrap().is_none());
+    }
+
+    #[test]
+    fn payloads_without_checksums_get_one() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let chunk = Chunk::random();
+        let pos = ChunkPos(-1, 0, 5);
+        // version 2 payloads are the current ones without the checksum
+        let payload = region::encode_payload(&chunk).unwrap()[4..].to_vec();
+
+        let (region, _) = RegionPos::of_chunk(&pos);
+        fs::write(
+            dir.path().join(region.file_name()),
+            old_region(2, &[(pos, payload)]),
+        )
+        .unwrap();
+
+        assert_eq!(legacy_format(dir.path()).unwrap(), Some((2, CHUNK_SIZE)));
+        WorldMeta::open(
+            dir.path(),
+            &RandomGenerator::new(0),
+            &BlockRegistry::default(),
+        )
+        .unwrap();
+
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+        let loaded = storage.load_chunk(&pos).unwrap().unwrap();
+        assert!(loaded.blocks().eq(chunk.blocks()));
+        assert!(region::verify(dir.path()).unwrap().is_ok());
+    }
+
+    #[test]
+    fn oversized_v1_payloads_are_refused() {
+        let mut encoder = ZlibEncoder::new(vec![region::COMPRESSION_ZLIB], Compression::default());
+        encoder.write_all(&vec![0; CHUNK_VOLUME * 4 * 8]).unwrap();
+
+        let Err(err) = decode_v1_payload(&encoder.finish().unwrap()) else {
+            panic!("the oversized payload decoded");
+        };
+        assert!(err.contains("decompress to more than"), "{}", err);
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::chunk::{
+    block::{Block, Facing},
+    neighborhood::ChunkNeighborhood,
+    Chunk, ChunkPos, LocalBlockPos, CHUNK_SIZE,
+};
+
+pub mod generator;
+pub mod meta;
+mod migrate;
+pub mod region;
+pub mod template;
+
+/// Block position in world space.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct WorldBlockPos(pub i32, pub i32, pub i32);
+
+impl WorldBlockPos {
+    /// Position of the block containing the point.
+    pub fn from_point(p: cgmath::Point3<f32>) -> Self {
+        Self(p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32)
+    }
+
+    pub fn from_chunk_local(chunk: ChunkPos, local: LocalBlockPos) -> Self {
+        let size = CHUNK_SIZE as i32;
+        Self(
+            chunk.0 * size + local.0 as i32,
+            chunk.1 * size + local.1 as i32,
+            chunk.2 * size + local.2 as i32,
+        )
+    }
+
+    /// Splits the position into the chunk it is in and the position inside
+    /// that chunk. Uses floor division, so -1 is the last block of chunk -1,
+    /// not the first block of chunk 0.
+    pub fn to_chunk_local(&self) -> (ChunkPos, LocalBlockPos) {
+        let size = CHUNK_SIZE as i32;
+        (
+            self.chunk_pos(),
+            LocalBlockPos(
+                self.0.rem_euclid(size) as u32,
+                self.1.rem_euclid(size) as u32,
+                self.2.rem_euclid(size) as u32,
+            ),
+        )
+    }
+
+    pub fn chunk_pos(&self) -> ChunkPos {
+        let size = CHUNK_SIZE as i32;
+        ChunkPos(
+            self.0.div_euclid(size),
+            self.1.div_euclid(size),
+            self.2.div_euclid(size),
+        )
+    }
+}
+
+/// The loaded chunks, addressable by world position.
+///
+/// Chunks changed through the world are marked dirty, so they can be saved
+/// before they are unloaded.
+#[derive(Default)]
+pub struct World {
+    chunks: HashMap<ChunkPos, Chunk>,
+    dirty: HashSet<ChunkPos>,
+}
+
+impl World {
+    pub fn get_chunk(&self, pos: &ChunkPos) -> Option<&Chunk> {
+        self.chunks.get(pos)
+    }
+
+    /// Mutable access to a chunk, which marks it as dirty.
+    pub fn get_chunk_mut(&mut self, pos: &ChunkPos) -> Option<&mut Chunk> {
+        let chunk = self.chunks.get_mut(pos)?;
+        self.dirty.insert(*pos);
+        Some(chunk)
+    }
+
+    /// Adds a chunk to the world, returning the chunk it replaced. The chunk
+    /// starts out clean, as it is assumed to match its saved or generated
+    /// version.
+    pub fn insert_chunk(&mut self, pos: ChunkPos, chunk: Chunk) -> Option<Chunk> {
+        self.dirty.remove(&pos);
+        self.chunks.insert(pos, chunk)
+    }
+
+    pub fn remove_chunk(&mut self, pos: &ChunkPos) -> Option<Chunk> {
+        self.dirty.remove(pos);
+        self.chunks.remove(pos)
+    }
+
+    /// Whether the chunk has changed since it was loaded or last saved.
+    pub fn is_dirty(&self, pos: &ChunkPos) -> bool {
+        self.dirty.contains(pos)
+    }
+
+    pub fn mark_dirty(&mut self, pos: ChunkPos) {
+        if self.chunks.contains_key(&pos) {
+            self.dirty.insert(pos);
+        }
+    }
+
+    /// Marks the chunk as matching its saved version.
+    pub fn mark_clean(&mut self, pos: &ChunkPos) {
+        self.dirty.remove(pos);
+    }
+
+    pub fn dirty_chunks(&self) -> impl Iterator<Item = &ChunkPos> {
+        self.dirty.iter()
+    }
+
+    pub fn contains_chunk(&self, pos: &ChunkPos) -> bool {
+        self.chunks.contains_key(pos)
+    }
+
+    /// The chunk at `pos` with whichever of its six neighbours are loaded.
+    pub fn neighborhood(&self, pos: &ChunkPos) -> Option<ChunkNeighborhood<'_>> {
+        let center = self.chunks.get(pos)?;
+
+        let hood = Facing::ALL
+            .into_iter()
+            .fold(ChunkNeighborhood::new(center), |hood, facing| {
+                let (dx, dy, dz) = facing.offset();
+                match self
+                    .chunks
+                    .get(&ChunkPos(pos.0 + dx, pos.1 + dy, pos.2 + dz))
+                {
+                    Some(chunk) => hood.with_neighbor(facing, chunk),
+                    None => hood,
+                }
+            });
+
+        Some(hood)
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = (&ChunkPos, &Chunk)> {
+        self.chunks.iter()
+    }
+
+    pub fn chunk_positions(&self) -> impl Iterator<Item = &ChunkPos> {
+        self.chunks.keys()
+    }
+
+    /// Number of loaded chunks.
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Returns the block at the position, blocks in chunks that aren't loaded
+    /// are air.
+    pub fn get_block(&self, pos: &WorldBlockPos) -> Block {
+        let (chunk_pos, local) = pos.to_chunk_local();
+        self.chunks
+            .get(&chunk_pos)
+            .map_or(Block(0), |chunk| chunk.get_block(&local))
+    }
+
+    /// Sets the block at the position, creating an empty chunk for it if the
+    /// chunk isn't loaded.
+    pub fn set_block(&mut self, pos: WorldBlockPos, b: Block) {
+        let (chunk_pos, local) = pos.to_chunk_local();
+        self.chunks
+            .entry(chunk_pos)
+            .or_default()
+            .set_block(local, b);
+        self.dirty.insert(chunk_pos);
+    }
+
+    pub fn is_collidable(&self, pos: &WorldBlockPos) -> bool {
+        self.get_block(pos).is_collidable()
+    }
+
+    /// Whether an axis-aligned box overlaps any collidable block. Touching the
+    /// side of a block doesn't count as overlapping it.
+    pub fn collides(&self, min: cgmath::Point3<f32>, max: cgmath::Point3<f32>) -> bool {
+        let first = WorldBlockPos::from_point(min);
+        let last = WorldBlockPos(
+            max.x.ceil() as i32,
+            max.y.ceil() as i32,
+            max.z.ceil() as i32,
+        );
+
+        self.iter_box(first, last).any(|(_, b)| b.is_collidable())
+    }
+
+    /// Iterates over the blocks between `min` (inclusive) and `max`
+    /// (exclusive), which may span any number of chunks. The blocks are
+    /// visited chunk by chunk.
+    pub fn iter_box(
+        &self,
+        min: WorldBlockPos,
+        max: WorldBlockPos,
+    ) -> impl Iterator<Item = (WorldBlockPos, Block)> + '_ {
+        chunks_in_box(min, max).flat_map(move |(chunk_pos, local_min, local_max)| {
+            let chunk = self.chunks.get(&chunk_pos);
+
+            local_box(local_min, local_max).map(move |local| {
+                let block = chunk.map_or(Block(0), |c| c.get_block(&local));
+                (WorldBlockPos::from_chunk_local(chunk_pos, local), block)
+            })
+        })
+    }
+
+    /// Sets every block between `min` (inclusive) and `max` (exclusive) to `b`,
+    /// creating empty chunks where needed.
+    pub fn fill_box(&mut self, min: WorldBlockPos, max: WorldBlockPos, b: Block) {
+        for (chunk_pos, local_min, local_max) in chunks_in_box(min, max) {
+            self.chunks
+                .entry(chunk_pos)
+                .or_default()
+                .fill_region(local_min, local_max, b);
+            self.dirty.insert(chunk_pos);
+        }
+    }
+
+    /// Approximate number of bytes used by the loaded chunks.
+    pub fn memory_usage(&self) -> usize {
+        self.chunks.values().map(Chunk::memory_usage).sum()
+    }
+}
+
+/// Splits a box into the chunks it overlaps, with the part of the box inside
+/// each chunk (local min inclusive, local max exclusive).
+fn chunks_in_box(
+    min: WorldBlockPos,
+    max: WorldBlockPos,
+) -> impl Iterator<Item = (ChunkPos, LocalBlockPos, LocalBlockPos)> {
+    let size = CHUNK_SIZE as i32;
+    let empty = max.0 <= min.0 || max.1 <= min.1 || max.2 <= min.2;
+
+    // chunk range covering the box, max inclusive. An empty box's max can be
+    // at i32::MIN, so it isn't stepped back
+    let first = min.chunk_pos();
+    let last = if empty {
+        first
+    } else {
+        WorldBlockPos(max.0 - 1, max.1 - 1, max.2 - 1).chunk_pos()
+    };
+
+    // widened, as a box reaching both ends of i32 overflows the difference
+    let clamp = move |v: i32, chunk: i32| {
+        (v as i64 - chunk as i64 * size as i64).clamp(0, size as i64) as u32
+    };
+
+    (first.0..=last.0)
+        .flat_map(move |x| (first.1..=last.1).map(move |y| (x, y)))
+        .flat_map(move |(x, y)| (first.2..=last.2).map(move |z| ChunkPos(x, y, z)))
+        .filter(move |_| !empty)
+        .map(move |c| {
+            (
+                c,
+                LocalBlockPos(clamp(min.0, c.0), clamp(min.1, c.1), clamp(min.2, c.2)),
+                LocalBlockPos(clamp(max.0, c.0), clamp(max.1, c.1), clamp(max.2, c.2)),
+            )
+        })
+}
+
+fn local_box(min: LocalBlockPos, max: LocalBlockPos) -> impl Iterator<Item = LocalBlockPos> {
+    (min.0..max.0)
+        .flat_map(move |x| (min.1..max.1).map(move |y| (x, y)))
+        .flat_map(move |(x, y)| (min.2..max.2).map(move |z| LocalBlockPos(x, y, z)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_positions_floor_to_previous_chunk() {
+        let size = CHUNK_SIZE as i32;
+
+        let (chunk, local) = WorldBlockPos(-1, 0, -size).to_chunk_local();
+        assert_eq!(chunk, ChunkPos(-1, 0, -1));
+        assert_eq!(local, LocalBlockPos(CHUNK_SIZE - 1, 0, 0));
+
+        let (chunk, local) = WorldBlockPos(-size - 1, size, 5).to_chunk_local();
+        assert_eq!(chunk, ChunkPos(-2, 1, 0));
+        assert_eq!(local, LocalBlockPos(CHUNK_SIZE - 1, 0, 5));
+    }
+
+    #[test]
+    fn chunk_local_round_trip() {
+        for pos in [
+            WorldBlockPos(0, 0, 0),
+            WorldBlockPos(-1, -1, -1),
+            WorldBlockPos(100, -100, 33),
+            WorldBlockPos(-65, 64, -32),
+        ] {
+            let (chunk, local) = pos.to_chunk_local();
+            assert_eq!(WorldBlockPos::from_chunk_local(chunk, local), pos);
+        }
+    }
+
+    #[test]
+    fn point_to_block_pos() {
+        let size = CHUNK_SIZE as f32;
+        let pos = WorldBlockPos::from_point(cgmath::Point3::new(-0.5, 0.5, -size - 0.5));
+        assert_eq!(pos, WorldBlockPos(-1, 0, -(CHUNK_SIZE as i32) - 1));
+        assert_eq!(pos.chunk_pos(), ChunkPos(-1, 0, -2));
+    }
+
+    #[test]
+    fn set_and_get_across_chunks() {
+        let mut world = World::default();
+
+        world.set_block(WorldBlockPos(-1, 0, 0), Block(1));
+        world.set_block(WorldBlockPos(0, 0, 0), Block(2));
+
+        assert_eq!(world.len(), 2);
+        assert_eq!(world.get_block(&WorldBlockPos(-1, 0, 0)), Block(1));
+        assert_eq!(world.get_block(&WorldBlockPos(0, 0, 0)), Block(2));
+        assert_eq!(world.get_block(&WorldBlockPos(1, 0, 0)), Block(0));
+
+        // unloaded chunks are air
+        assert_eq!(world.get_block(&WorldBlockPos(0, 1000, 0)), Block(0));
+    }
+
+    #[test]
+    fn neighborhood_reads_into_loaded_chunks() {
+        let mut world = World::default();
+        world.set_block(WorldBlockPos(0, 0, 0), Block(0));
+        world.set_block(WorldBlockPos(-1, 0, 0), Block(2));
+
+        let hood = world.neighborhood(&ChunkPos(0, 0, 0)).unwrap();
+        assert_eq!(hood.get_block(-1, 0, 0), Some(Block(2)));
+        assert_eq!(hood.get_block(CHUNK_SIZE as i32, 0, 0), None);
+
+        assert!(world.neighborhood(&ChunkPos(5, 0, 0)).is_none());
+    }
+
+    #[test]
+    fn changes_mark_chunks_dirty() {
+        let mut world = World::default();
+        world.insert_chunk(ChunkPos(0, 0, 0), Chunk::default());
+        assert!(!world.is_dirty(&ChunkPos(0, 0, 0)));
+
+        world.set_block(WorldBlockPos(1, 1, 1), Block(1));
+        world.fill_box(WorldBlockPos(-2, 0, 0), WorldBlockPos(-1, 1, 1), Block(1));
+        assert!(world.is_dirty(&ChunkPos(0, 0, 0)));
+        assert!(world.is_dirty(&ChunkPos(-1, 0, 0)));
+
+        world.mark_clean(&ChunkPos(0, 0, 0));
+        assert_eq!(
+            world.dirty_chunks().collect::<Vec<_>>(),
+            [&ChunkPos(-1, 0, 0)]
+        );
+
+        world.remove_chunk(&ChunkPos(-1, 0, 0));
+        assert_eq!(world.dirty_chunks().count(), 0);
+    }
+
+    #[test]
+    fn boxes_collide_with_collidable_blocks() {
+        let mut world = World::default();
+        world.set_block(WorldBlockPos(-1, 0, 0), Block(3));
+
+        let point = |x, y, z| cgmath::Point3::new(x, y, z);
+
+        assert!(world.is_collidable(&WorldBlockPos(-1, 0, 0)));
+        assert!(world.collides(point(-1.5, 0.5, 0.5), point(-0.5, 1.5, 1.5)));
+
+        // touching the block isn't a collision
+        assert!(!world.collides(point(0.0, 0.0, 0.0), point(1.0, 1.0, 1.0)));
+        assert!(!world.collides(point(-1.0, 1.0, 0.0), point(0.0, 2.0, 1.0)));
+    }
+
+    #[test]
+    fn iterate_box_spanning_chunks() {
+        let mut world = World::default();
+        let min = WorldBlockPos(-3, -2, -1);
+        let max = WorldBlockPos(3, 2, 1);
+
+        world.fill_box(min, max, Block(1));
+        assert_eq!(world.len(), 8);
+
+        let blocks: Vec<_> = world.iter_box(min, max).collect();
+        assert_eq!(blocks.len(), 6 * 4 * 2);
+        assert!(blocks.iter().all(|(_, b)| *b == Block(1)));
+
+        // every position is visited exactly once
+        let mut positions: Vec<_> = blocks.iter().map(|(p, _)| (p.0, p.1, p.2)).collect();
+        positions.sort();
+        positions.dedup();
+        assert_eq!(positions.len(), blocks.len());
+
+        // a bigger box also sees the air around it
+        let solid = world
+            .iter_box(WorldBlockPos(-4, -3, -2), WorldBlockPos(4, 3, 2))
+            .filter(|(_, b)| *b == Block(1))
+            .count();
+        assert_eq!(solid, blocks.len());
+
+        assert_eq!(world.iter_box(max, min).count(), 0);
+    }
+
+    #[test]
+    fn boxes_at_the_ends_of_the_range() {
+        let mut world = World::default();
+        let low = WorldBlockPos(i32::MIN, i32::MIN, i32::MIN);
+        let high = WorldBlockPos(i32::MAX, i32::MAX, i32::MAX);
+
+        // empty boxes with their max at the lowest position
+        assert_eq!(world.iter_box(WorldBlockPos(0, 0, 0), low).count(), 0);
+        assert_eq!(world.iter_box(low, low).count(), 0);
+        world.fill_box(WorldBlockPos(5, 5, 5), low, Block(1));
+        assert!(world.is_empty());
+
+        // a single block in each corner
+        world.fill_box(
+            low,
+            WorldBlockPos(i32::MIN + 1, i32::MIN + 1, i32::MIN + 1),
+            Block(1),
+        );
+        assert_eq!(world.get_block(&low), Block(1));
+        let corner = WorldBlockPos(i32::MAX - 1, i32::MAX - 1, i32::MAX - 1);
+        world.fill_box(corner, high, Block(2));
+        assert_eq!(
+            world.iter_box(corner, high).collect::<Vec<_>>(),
+            [(corner, Block(2))]
+        );
+    }
+}
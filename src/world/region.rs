@@ -0,0 +1,831 @@
+//! Region files store a fixed grid of chunks on disk.
+//!
+//! Each file holds `REGION_SIZE`³ chunks and starts with a header:
+//!
+//! ```text
+//! magic        b"VVRG"
+//! version      u16
+//! chunk size   u16
+//! entries      REGION_CHUNKS x (offset: u32, length: u32)
+//! ```
+//!
+//! followed by the chunk payloads. An offset of 0 means the chunk isn't in the
+//! file. Each payload starts with a CRC-32 of the rest of the payload, then a
+//! byte saying how the rest is compressed, the rest is the chunk from
+//! `Chunk::serialize`.
+//! All numbers are little-endian.
+//!
+//! Saving a chunk never overwrites its old payload. The new one goes in the
+//! first gap between the stored payloads that fits it, or after the last one,
+//! and the header entry only points at it once it is written, so a crash while
+//! saving leaves the old version. Offsets are u32, so a file can't grow past
+//! 4 GiB.
+//!
+//! Chunks whose checksum doesn't match, or whose payload was cut off, fail to
+//! load with `RegionError::CorruptChunk`. `RegionStorage::quarantine_chunk`
+//! moves them out of the way, and `verify` finds them without loading the
+//! world.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::chunk::{serialize::MAX_SERIALIZED_LEN, Chunk, ChunkPos, CHUNK_SIZE};
+
+/// Number of chunks along each axis of a region.
+pub const REGION_SIZE: i32 = 8;
+/// Number of chunks stored in a region file.
+pub const REGION_CHUNKS: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+pub(super) const MAGIC: &[u8; 4] = b"VVRG";
+pub(super) const VERSION: u16 = 3;
+pub(super) const HEADER_LEN: u64 = 8 + REGION_CHUNKS as u64 * 8;
+
+/// Where damaged chunks are moved to, inside the world directory.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Compression schemes for chunk payloads.
+pub(super) const COMPRESSION_ZLIB: u8 = 1;
+
+#[derive(Debug)]
+pub enum RegionError {
+    Io(std::io::Error),
+    /// The file doesn't start with the region magic bytes.
+    NotARegion(PathBuf),
+    UnsupportedVersion {
+        path: PathBuf,
+        version: u16,
+    },
+    /// The file was written with a different chunk size.
+    ChunkSizeMismatch {
+        path: PathBuf,
+        found: u16,
+    },
+    /// The payload of a chunk couldn't be decoded.
+    CorruptChunk {
+        pos: ChunkPos,
+        reason: String,
+    },
+    /// The file has no room left that its offsets can address.
+    RegionFull(PathBuf),
+}
+
+impl std::fmt::Display for RegionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegionError::Io(e) => write!(f, "region io error: {}", e),
+            RegionError::NotARegion(path) => {
+                write!(f, "{} is not a region file", path.display())
+            }
+            RegionError::UnsupportedVersion { path, version } => write!(
+                f,
+                "{} has region version {}, only {} is supported",
+                path.display(),
+                version,
+                VERSION
+            ),
+            RegionError::ChunkSizeMismatch { path, found } => write!(
+                f,
+                "{} stores chunks of size {}, but chunks are size {}",
+                path.display(),
+                found,
+                CHUNK_SIZE
+            ),
+            RegionError::CorruptChunk { pos, reason } => {
+                write!(f, "chunk {:?} is corrupt: {}", pos, reason)
+            }
+            RegionError::RegionFull(path) => {
+                write!(f, "{} is too large to store more chunks", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegionError {}
+
+impl From<std::io::Error> for RegionError {
+    fn from(e: std::io::Error) -> Self {
+        RegionError::Io(e)
+    }
+}
+
+/// Position of a region, in regions.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct RegionPos(pub i32, pub i32, pub i32);
+
+impl RegionPos {
+    /// The region containing the chunk, and the chunk's slot in its header.
+    pub fn of_chunk(pos: &ChunkPos) -> (Self, usize) {
+        let region = RegionPos(
+            pos.0.div_euclid(REGION_SIZE),
+            pos.1.div_euclid(REGION_SIZE),
+            pos.2.div_euclid(REGION_SIZE),
+        );
+
+        let (x, y, z) = (
+            pos.0.rem_euclid(REGION_SIZE),
+            pos.1.rem_euclid(REGION_SIZE),
+            pos.2.rem_euclid(REGION_SIZE),
+        );
+        let slot = ((x * REGION_SIZE + y) * REGION_SIZE + z) as usize;
+
+        (region, slot)
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("r.{}.{}.{}.vvr", self.0, self.1, self.2)
+    }
+
+    /// The region a file is for, from its name.
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        let coords = name.strip_prefix("r.")?.strip_suffix(".vvr")?;
+        let mut coords = coords.split('.').map(|c| c.parse().ok());
+        let pos = RegionPos(coords.next()??, coords.next()??, coords.next()??);
+        coords.next().is_none().then_some(pos)
+    }
+
+    /// The chunk stored in `slot`, the inverse of `of_chunk`.
+    pub fn chunk(&self, slot: usize) -> ChunkPos {
+        let slot = slot as i32;
+        ChunkPos(
+            self.0 * REGION_SIZE + slot / (REGION_SIZE * REGION_SIZE),
+            self.1 * REGION_SIZE + slot / REGION_SIZE % REGION_SIZE,
+            self.2 * REGION_SIZE + slot % REGION_SIZE,
+        )
+    }
+}
+
+/// An open region file.
+pub struct RegionFile {
+    path: PathBuf,
+    file: File,
+    /// (offset, length) of each chunk payload, offset 0 if it isn't stored.
+    entries: Vec<(u32, u32)>,
+}
+
+impl RegionFile {
+    /// Opens the region file, creating an empty one if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RegionError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        if file.metadata()?.len() == 0 {
+            let mut region = Self {
+                path,
+                file,
+                entries: vec![(0, 0); REGION_CHUNKS],
+            };
+            region.write_header()?;
+            return Ok(region);
+        }
+
+        let mut header = vec![0; HEADER_LEN as usize];
+        file.read_exact(&mut header).map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => RegionError::NotARegion(path.clone()),
+            _ => RegionError::Io(e),
+        })?;
+        let entries = parse_header(&path, &header)?;
+
+        Ok(Self {
+            path,
+            file,
+            entries,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contains_chunk(&self, pos: &ChunkPos) -> bool {
+        let (_, slot) = RegionPos::of_chunk(pos);
+        self.entries[slot].0 != 0
+    }
+
+    /// Writes the chunk to the file, replacing any earlier copy.
+    pub fn save_chunk(&mut self, pos: &ChunkPos, chunk: &Chunk) -> Result<(), RegionError> {
+        let (_, slot) = RegionPos::of_chunk(pos);
+        let payload = encode_payload(chunk)?;
+
+        let offset = self.free_space(payload.len() as u64);
+        let Ok(offset) = u32::try_from(offset) else {
+            return Err(RegionError::RegionFull(self.path.clone()));
+        };
+
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.write_all(&payload)?;
+
+        self.entries[slot] = (offset, payload.len() as u32);
+        self.write_entry(slot)
+    }
+
+    /// Offset of the first gap between the stored payloads that `len` bytes
+    /// fit in, or the end of the last payload. The payload of a chunk being
+    /// replaced is still stored, so it is never written over.
+    fn free_space(&self, len: u64) -> u64 {
+        let mut used: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(offset, _)| *offset != 0)
+            .map(|&(offset, len)| (offset as u64, offset as u64 + len as u64))
+            .collect();
+        used.sort_unstable();
+
+        let mut start = HEADER_LEN;
+        for (begin, end) in used {
+            if begin >= start + len {
+                break;
+            }
+            start = start.max(end);
+        }
+        start
+    }
+
+    /// Reads the chunk from the file, `None` if it was never saved.
+    pub fn load_chunk(&mut self, pos: &ChunkPos) -> Result<Option<Chunk>, RegionError> {
+        let (_, slot) = RegionPos::of_chunk(pos);
+        let (offset, len) = self.entries[slot];
+        if offset == 0 {
+            return Ok(None);
+        }
+
+        // the header has no checksum, so only read what the file really has
+        // instead of allocating whatever length it claims
+        let mut payload = Vec::new();
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        (&mut self.file)
+            .take(len as u64)
+            .read_to_end(&mut payload)?;
+        if payload.len() < len as usize {
+            return Err(RegionError::CorruptChunk {
+                pos: *pos,
+                reason: "payload is cut off".to_string(),
+            });
+        }
+
+        decode_payload(&payload)
+            .map(Some)
+            .map_err(|reason| RegionError::CorruptChunk { pos: *pos, reason })
+    }
+
+    /// Removes the chunk from the file, returning as much of its payload as
+    /// is still there. `None` if it was never saved.
+    pub fn take_payload(&mut self, pos: &ChunkPos) -> Result<Option<Vec<u8>>, RegionError> {
+        let (_, slot) = RegionPos::of_chunk(pos);
+        let (offset, len) = self.entries[slot];
+        if offset == 0 {
+            return Ok(None);
+        }
+
+        let mut payload = Vec::new();
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        (&mut self.file)
+            .take(len as u64)
+            .read_to_end(&mut payload)?;
+
+        self.entries[slot] = (0, 0);
+        self.write_entry(slot)?;
+        Ok(Some(payload))
+    }
+
+    fn write_header(&mut self) -> Result<(), RegionError> {
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&(CHUNK_SIZE as u16).to_le_bytes());
+        for (offset, len) in &self.entries {
+            header.extend_from_slice(&offset.to_le_bytes());
+            header.extend_from_slice(&len.to_le_bytes());
+        }
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        Ok(())
+    }
+
+    fn write_entry(&mut self, slot: usize) -> Result<(), RegionError> {
+        let (offset, len) = self.entries[slot];
+        let mut entry = [0; 8];
+        entry[..4].copy_from_slice(&offset.to_le_bytes());
+        entry[4..].copy_from_slice(&len.to_le_bytes());
+
+        self.file.seek(SeekFrom::Start(8 + slot as u64 * 8))?;
+        self.file.write_all(&entry)?;
+        Ok(())
+    }
+}
+
+/// The region files in a directory, opened as they are needed.
+pub struct RegionStorage {
+    dir: PathBuf,
+    regions: HashMap<RegionPos, RegionFile>,
+}
+
+impl RegionStorage {
+    /// Stores regions in `dir`, creating it if needed.
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self, RegionError> {
+        std::fs::create_dir_all(dir.as_ref())?;
+
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            regions: HashMap::new(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn save_chunk(&mut self, pos: &ChunkPos, chunk: &Chunk) -> Result<(), RegionError> {
+        self.region(pos)?.save_chunk(pos, chunk)
+    }
+
+    /// Reads a saved chunk, `None` if it was never saved.
+    pub fn load_chunk(&mut self, pos: &ChunkPos) -> Result<Option<Chunk>, RegionError> {
+        let (region, _) = RegionPos::of_chunk(pos);
+
+        // don't create region files just to find out they're empty
+        if !self.regions.contains_key(&region) && !self.dir.join(region.file_name()).exists() {
+            return Ok(None);
+        }
+
+        self.region(pos)?.load_chunk(pos)
+    }
+
+    /// Moves the payload of a chunk that failed to load into a file in the
+    /// quarantine directory, so it is generated again without losing the
+    /// data. Returns the file, or `None` if the chunk wasn't saved.
+    pub fn quarantine_chunk(&mut self, pos: &ChunkPos) -> Result<Option<PathBuf>, RegionError> {
+        let Some(payload) = self.region(pos)?.take_payload(pos)? else {
+            return Ok(None);
+        };
+
+        let dir = self.dir.join(QUARANTINE_DIR);
+        std::fs::create_dir_all(&dir)?;
+
+        // keep earlier copies of the same chunk
+        let name = format!("c.{}.{}.{}", pos.0, pos.1, pos.2);
+        let mut path = dir.join(format!("{}.bad", name));
+        let mut copy = 1;
+        while path.exists() {
+            path = dir.join(format!("{}.{}.bad", name, copy));
+            copy += 1;
+        }
+
+        std::fs::write(&path, payload)?;
+        Ok(Some(path))
+    }
+
+    fn region(&mut self, pos: &ChunkPos) -> Result<&mut RegionFile, RegionError> {
+        let (region, _) = RegionPos::of_chunk(pos);
+
+        match self.regions.entry(region) {
+            std::collections::hash_map::Entry::Occupied(e) => Ok(e.into_mut()),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                let file = RegionFile::open(self.dir.join(region.file_name()))?;
+                Ok(e.insert(file))
+            }
+        }
+    }
+}
+
+/// What `verify` found in a world directory.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of stored chunks that were checked.
+    pub chunks: usize,
+    /// Chunks that can't be loaded, with the reason.
+    pub damaged_chunks: Vec<(ChunkPos, String)>,
+    /// Region files that can't be read at all, with the reason.
+    pub damaged_files: Vec<(PathBuf, String)>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.damaged_chunks.is_empty() && self.damaged_files.is_empty()
+    }
+}
+
+/// Checks every chunk stored in the region files in `dir`, without changing
+/// anything. Only errors listing the directory are returned, damaged files
+/// and chunks are in the report.
+pub fn verify(dir: impl AsRef<Path>) -> Result<VerifyReport, RegionError> {
+    let mut report = VerifyReport::default();
+
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "vvr") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    for path in paths {
+        let region = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(RegionPos::from_file_name);
+        let Some(region) = region else {
+            report
+                .damaged_files
+                .push((path, "not named after a region".to_string()));
+            continue;
+        };
+
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                report.damaged_files.push((path, e.to_string()));
+                continue;
+            }
+        };
+        let entries = match data
+            .get(..HEADER_LEN as usize)
+            .ok_or_else(|| RegionError::NotARegion(path.clone()))
+            .and_then(|header| parse_header(&path, header))
+        {
+            Ok(entries) => entries,
+            Err(e) => {
+                report.damaged_files.push((path, e.to_string()));
+                continue;
+            }
+        };
+
+        for (slot, (offset, len)) in entries.into_iter().enumerate() {
+            if offset == 0 {
+                continue;
+            }
+            report.chunks += 1;
+
+            let payload = data.get(offset as usize..offset as usize + len as usize);
+            let result = payload
+                .ok_or_else(|| "payload is cut off".to_string())
+                .and_then(decode_payload);
+            if let Err(reason) = result {
+                report.damaged_chunks.push((region.chunk(slot), reason));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// The entries of a region header, checking that it's a region of the
+/// current version and chunk size.
+fn parse_header(path: &Path, header: &[u8]) -> Result<Vec<(u32, u32)>, RegionError> {
+    if &header[0..4] != MAGIC {
+        return Err(RegionError::NotARegion(path.to_path_buf()));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != VERSION {
+        return Err(RegionError::UnsupportedVersion {
+            path: path.to_path_buf(),
+            version,
+        });
+    }
+    let chunk_size = u16::from_le_bytes([header[6], header[7]]);
+    if chunk_size as u32 != CHUNK_SIZE {
+        return Err(RegionError::ChunkSizeMismatch {
+            path: path.to_path_buf(),
+            found: chunk_size,
+        });
+    }
+
+    Ok(header[8..HEADER_LEN as usize]
+        .chunks_exact(8)
+        .map(|e| {
+            (
+                u32::from_le_bytes([e[0], e[1], e[2], e[3]]),
+                u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+            )
+        })
+        .collect())
+}
+
+/// The serialized chunk, zlib compressed.
+pub(super) fn encode_payload(chunk: &Chunk) -> Result<Vec<u8>, RegionError> {
+    let mut encoder = ZlibEncoder::new(vec![COMPRESSION_ZLIB], Compression::default());
+    encoder.write_all(&chunk.serialize())?;
+
+    Ok(with_checksum(&encoder.finish()?))
+}
+
+/// Puts the checksum of a payload in front of it.
+pub(super) fn with_checksum(payload: &[u8]) -> Vec<u8> {
+    let mut out = crc32fast::hash(payload).to_le_bytes().to_vec();
+    out.extend_from_slice(payload);
+    out
+}
+
+fn decode_payload(payload: &[u8]) -> Result<Chunk, String> {
+    if payload.len() < 4 {
+        return Err("payload is too short for a checksum".to_string());
+    }
+    let (checksum, payload) = payload.split_at(4);
+    let checksum = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    let actual = crc32fast::hash(payload);
+    if checksum != actual {
+        return Err(format!(
+            "checksum mismatch, stored {:08x} but the payload has {:08x}",
+            checksum, actual
+        ));
+    }
+
+    let (&compression, data) = payload.split_first().ok_or("empty payload")?;
+    if compression != COMPRESSION_ZLIB {
+        return Err(format!("unknown compression {}", compression));
+    }
+
+    // a valid chunk never gets near the limit, so don't inflate a bomb
+    let mut raw = Vec::new();
+    ZlibDecoder::new(data)
+        .take(MAX_SERIALIZED_LEN as u64 + 1)
+        .read_to_end(&mut raw)
+        .map_err(|e| e.to_string())?;
+    if raw.len() > MAX_SERIALIZED_LEN {
+        return Err(format!(
+            "payload decompresses to more than {} bytes",
+            MAX_SERIALIZED_LEN
+        ));
+    }
+
+    Chunk::deserialize(&raw).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{block::Block, LocalBlockPos};
+
+    use super::*;
+
+    fn sample_chunk() -> Chunk {
+        let mut chunk = Chunk::default();
+        chunk.fill_region(LocalBlockPos(0, 0, 0), LocalBlockPos(4, 2, 9), Block(2));
+        chunk.set_block(LocalBlockPos(7, 7, 7), Block(3).with_level(4));
+        chunk
+    }
+
+    fn same_blocks(a: &Chunk, b: &Chunk) -> bool {
+        a.blocks().eq(b.blocks())
+    }
+
+    #[test]
+    fn chunk_slots_cover_negative_positions() {
+        assert_eq!(
+            RegionPos::of_chunk(&ChunkPos(0, 0, 0)),
+            (RegionPos(0, 0, 0), 0)
+        );
+        assert_eq!(
+            RegionPos::of_chunk(&ChunkPos(-1, 0, 1)),
+            (RegionPos(-1, 0, 0), ((7 * 8) * 8 + 1) as usize)
+        );
+        assert_eq!(
+            RegionPos::of_chunk(&ChunkPos(8, -8, 15)).0,
+            RegionPos(1, -1, 1)
+        );
+    }
+
+    #[test]
+    fn saved_chunks_load_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+
+        let chunk = sample_chunk();
+        let positions = [ChunkPos(0, 0, 0), ChunkPos(-1, 3, 9), ChunkPos(7, 7, 7)];
+        for pos in &positions {
+            storage.save_chunk(pos, &chunk).unwrap();
+        }
+        storage
+            .save_chunk(&ChunkPos(1, 0, 0), &Chunk::full())
+            .unwrap();
+
+        // a fresh storage has to read everything back from disk
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+        for pos in &positions {
+            let loaded = storage.load_chunk(pos).unwrap().unwrap();
+            assert!(same_blocks(&loaded, &chunk));
+        }
+        let full = storage.load_chunk(&ChunkPos(1, 0, 0)).unwrap().unwrap();
+        assert_eq!(full.uniform_block(), Some(Block(1)));
+
+        assert!(storage.load_chunk(&ChunkPos(2, 0, 0)).unwrap().is_none());
+        assert!(storage.load_chunk(&ChunkPos(100, 0, 0)).unwrap().is_none());
+        assert!(!dir.path().join(RegionPos(12, 0, 0).file_name()).exists());
+    }
+
+    #[test]
+    fn saving_again_replaces_the_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("r.0.0.0.vvr");
+        let mut region = RegionFile::open(&path).unwrap();
+        let pos = ChunkPos(1, 2, 3);
+
+        // the random chunk doesn't fit where the uniform one was
+        region.save_chunk(&pos, &Chunk::full()).unwrap();
+        let random = Chunk::random();
+        region.save_chunk(&pos, &random).unwrap();
+        region.save_chunk(&pos, &sample_chunk()).unwrap();
+
+        let mut region = RegionFile::open(&path).unwrap();
+        assert!(region.contains_chunk(&pos));
+        let loaded = region.load_chunk(&pos).unwrap().unwrap();
+        assert!(same_blocks(&loaded, &sample_chunk()));
+    }
+
+    #[test]
+    fn saving_never_writes_over_the_old_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("r.0.0.0.vvr");
+        let mut region = RegionFile::open(&path).unwrap();
+        let pos = ChunkPos(1, 2, 3);
+        let slot = RegionPos::of_chunk(&pos).1;
+
+        region.save_chunk(&pos, &sample_chunk()).unwrap();
+        let (first, len) = region.entries[slot];
+        let old = std::fs::read(&path).unwrap()[first as usize..(first + len) as usize].to_vec();
+
+        // the same payload fits in place, but goes after the old one
+        region.save_chunk(&pos, &sample_chunk()).unwrap();
+        let (second, _) = region.entries[slot];
+        assert_eq!(second, first + len);
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data[first as usize..(first + len) as usize], old);
+
+        // and the space it left is used again
+        region.save_chunk(&pos, &sample_chunk()).unwrap();
+        assert_eq!(region.entries[slot].0, first);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), data.len() as u64);
+    }
+
+    #[test]
+    fn offsets_past_4_gib_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("r.0.0.0.vvr");
+        let mut region = RegionFile::open(&path).unwrap();
+
+        // pretend a chunk fills the file up to past the last addressable offset
+        region.entries[0] = (HEADER_LEN as u32, u32::MAX);
+        assert!(matches!(
+            region.save_chunk(&ChunkPos(0, 0, 1), &sample_chunk()),
+            Err(RegionError::RegionFull(p)) if p == path
+        ));
+        assert_eq!(region.entries[1], (0, 0));
+    }
+
+    #[test]
+    fn region_file_names_and_slots_round_trip() {
+        for pos in [ChunkPos(0, 0, 0), ChunkPos(-1, 3, 9), ChunkPos(17, -20, 8)] {
+            let (region, slot) = RegionPos::of_chunk(&pos);
+            assert_eq!(region.chunk(slot), pos);
+            assert_eq!(RegionPos::from_file_name(&region.file_name()), Some(region));
+        }
+        assert_eq!(RegionPos::from_file_name("r.1.2.vvr"), None);
+        assert_eq!(RegionPos::from_file_name("r.1.2.3.4.vvr"), None);
+        assert_eq!(RegionPos::from_file_name("r.1.x.3.vvr"), None);
+    }
+
+    #[test]
+    fn damaged_chunks_are_found_and_quarantined() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+        let damaged = ChunkPos(1, 2, 3);
+        let intact = ChunkPos(1, 2, 4);
+        storage.save_chunk(&damaged, &sample_chunk()).unwrap();
+        storage.save_chunk(&intact, &sample_chunk()).unwrap();
+        assert!(verify(dir.path()).unwrap().is_ok());
+
+        // flip a bit in the middle of the first payload
+        let (region, slot) = RegionPos::of_chunk(&damaged);
+        let path = dir.path().join(region.file_name());
+        let (offset, len) = RegionFile::open(&path).unwrap().entries[slot];
+        let mut data = std::fs::read(&path).unwrap();
+        data[(offset + len / 2) as usize] ^= 0x10;
+        std::fs::write(&path, &data).unwrap();
+
+        let report = verify(dir.path()).unwrap();
+        assert_eq!(report.chunks, 2);
+        assert_eq!(report.damaged_chunks.len(), 1);
+        assert_eq!(report.damaged_chunks[0].0, damaged);
+        assert!(report.damaged_chunks[0].1.contains("checksum"));
+
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+        assert!(matches!(
+            storage.load_chunk(&damaged),
+            Err(RegionError::CorruptChunk { pos, .. }) if pos == damaged
+        ));
+
+        let quarantined = storage.quarantine_chunk(&damaged).unwrap().unwrap();
+        let start = offset as usize;
+        assert_eq!(
+            std::fs::read(&quarantined).unwrap(),
+            data[start..start + len as usize]
+        );
+        assert!(storage.load_chunk(&damaged).unwrap().is_none());
+        assert!(storage.load_chunk(&intact).unwrap().is_some());
+        assert!(verify(dir.path()).unwrap().is_ok());
+
+        // quarantining the same chunk again keeps the first copy
+        storage.save_chunk(&damaged, &sample_chunk()).unwrap();
+        let again = storage.quarantine_chunk(&damaged).unwrap().unwrap();
+        assert_ne!(again, quarantined);
+        assert!(quarantined.exists());
+    }
+
+    #[test]
+    fn cut_off_chunks_only_lose_that_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+        let first = ChunkPos(0, 0, 0);
+        let last = ChunkPos(0, 0, 1);
+        storage.save_chunk(&first, &sample_chunk()).unwrap();
+        storage.save_chunk(&last, &Chunk::random()).unwrap();
+
+        let path = dir.path().join(RegionPos(0, 0, 0).file_name());
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 3).unwrap();
+
+        let report = verify(dir.path()).unwrap();
+        assert_eq!(
+            report.damaged_chunks,
+            [(last, "payload is cut off".to_string())]
+        );
+
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+        assert!(storage.load_chunk(&first).unwrap().is_some());
+        assert!(matches!(
+            storage.load_chunk(&last),
+            Err(RegionError::CorruptChunk { .. })
+        ));
+    }
+
+    #[test]
+    fn payloads_that_decompress_too_far_are_refused() {
+        let mut encoder = ZlibEncoder::new(vec![COMPRESSION_ZLIB], Compression::default());
+        encoder.write_all(&vec![0; MAX_SERIALIZED_LEN * 4]).unwrap();
+        let payload = with_checksum(&encoder.finish().unwrap());
+
+        let Err(err) = decode_payload(&payload) else {
+            panic!("the bomb decoded");
+        };
+        assert!(err.contains("decompresses to more than"), "{}", err);
+
+        // the largest chunk still fits
+        let chunk = Chunk::random();
+        assert!(chunk.serialize().len() <= MAX_SERIALIZED_LEN);
+        assert!(same_blocks(
+            &decode_payload(&encode_payload(&chunk).unwrap()).unwrap(),
+            &chunk
+        ));
+    }
+
+    #[test]
+    fn huge_lengths_in_the_header_only_lose_that_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+        let first = ChunkPos(0, 0, 0);
+        let damaged = ChunkPos(0, 0, 1);
+        storage.save_chunk(&first, &sample_chunk()).unwrap();
+        storage.save_chunk(&damaged, &sample_chunk()).unwrap();
+        drop(storage);
+
+        // a length of 4 GiB would be allocated up front if it was trusted
+        let path = dir.path().join(RegionPos(0, 0, 0).file_name());
+        let mut region = RegionFile::open(&path).unwrap();
+        let (_, slot) = RegionPos::of_chunk(&damaged);
+        region.entries[slot].1 = u32::MAX;
+        region.write_entry(slot).unwrap();
+        drop(region);
+
+        let mut storage = RegionStorage::new(dir.path()).unwrap();
+        assert!(storage.load_chunk(&first).unwrap().is_some());
+        assert!(matches!(
+            storage.load_chunk(&damaged),
+            Err(RegionError::CorruptChunk { .. })
+        ));
+        assert!(storage.quarantine_chunk(&damaged).unwrap().is_some());
+        assert!(storage.load_chunk(&damaged).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_regions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("junk.vvr");
+        std::fs::write(&path, b"definitely not a region").unwrap();
+
+        assert!(matches!(
+            RegionFile::open(&path),
+            Err(RegionError::NotARegion(_))
+        ));
+    }
+}
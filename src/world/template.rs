@@ -0,0 +1,593 @@
+//! Structure templates, boxes of blocks that can be saved and pasted
+//! elsewhere.
+//!
+//! ```text
+//! magic        b"VVST"
+//! version      u8
+//! flags        u8, bit 0 set if air is a mask
+//! name         u16 length, then UTF-8
+//! size         3 x u32
+//! palette      u32 length, then each block as a u32
+//! blocks       zlib compressed u16 palette indices, x varying fastest, then
+//!              y, then z
+//! ```
+//!
+//! All numbers are little-endian. The blocks keep their state bits.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{Read, Write},
+    path::Path,
+};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::chunk::{
+    block::{Block, Facing},
+    ChunkPos,
+};
+
+use super::{World, WorldBlockPos};
+
+const MAGIC: &[u8; 4] = b"VVST";
+const VERSION: u8 = 1;
+
+const FLAG_AIR_MASK: u8 = 1;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Io(std::io::Error),
+    /// The file doesn't start with the template magic bytes.
+    NotATemplate,
+    UnsupportedVersion(u8),
+    /// The data ended before the template was complete.
+    UnexpectedEnd,
+    /// The name isn't UTF-8.
+    InvalidName,
+    InvalidPaletteIndex(u16),
+    /// The blocks don't match the template's size.
+    SizeMismatch {
+        expected: usize,
+        found: usize,
+    },
+    /// The file format only has room for 65536 different blocks.
+    TooManyBlockTypes(usize),
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::Io(e) => write!(f, "template io error: {}", e),
+            TemplateError::NotATemplate => write!(f, "not a structure template"),
+            TemplateError::UnsupportedVersion(v) => write!(
+                f,
+                "template version {} isn't supported, only {} is",
+                v, VERSION
+            ),
+            TemplateError::UnexpectedEnd => write!(f, "template data ended early"),
+            TemplateError::InvalidName => write!(f, "template name isn't valid UTF-8"),
+            TemplateError::InvalidPaletteIndex(i) => {
+                write!(f, "palette index {} is out of range", i)
+            }
+            TemplateError::SizeMismatch { expected, found } => write!(
+                f,
+                "template has {} blocks, but its size needs {}",
+                found, expected
+            ),
+            TemplateError::TooManyBlockTypes(n) => {
+                write!(f, "{} different blocks don't fit in a template", n)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<std::io::Error> for TemplateError {
+    fn from(e: std::io::Error) -> Self {
+        TemplateError::Io(e)
+    }
+}
+
+/// Turns and flips a template about the vertical axis when it's pasted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Transform {
+    /// Quarter turns, each taking +X to +Z.
+    pub quarter_turns: u8,
+    /// Mirrors the template along x before turning it.
+    pub mirror: bool,
+}
+
+impl Transform {
+    pub fn new(quarter_turns: u8, mirror: bool) -> Self {
+        Self {
+            quarter_turns: quarter_turns % 4,
+            mirror,
+        }
+    }
+
+    /// Size of a box of `size` after the transform.
+    pub fn size(&self, size: [u32; 3]) -> [u32; 3] {
+        match self.quarter_turns % 2 {
+            0 => size,
+            _ => [size[2], size[1], size[0]],
+        }
+    }
+
+    /// Where a position inside a box of `size` ends up, relative to the
+    /// minimum corner of the transformed box.
+    pub fn apply(&self, pos: [u32; 3], size: [u32; 3]) -> [u32; 3] {
+        let [mut x, y, mut z] = pos;
+        let [mut size_x, _, mut size_z] = size;
+
+        if self.mirror {
+            x = size_x - 1 - x;
+        }
+        for _ in 0..self.quarter_turns % 4 {
+            (x, z) = (size_z - 1 - z, x);
+            (size_x, size_z) = (size_z, size_x);
+        }
+
+        [x, y, z]
+    }
+
+    /// Turns and flips a direction the same way as positions.
+    pub fn apply_facing(&self, facing: Facing) -> Facing {
+        let (mut dx, dy, mut dz) = facing.offset();
+
+        if self.mirror {
+            dx = -dx;
+        }
+        for _ in 0..self.quarter_turns % 4 {
+            (dx, dz) = (-dz, dx);
+        }
+
+        Facing::from_offset((dx, dy, dz)).expect("Turned facings are still axis aligned")
+    }
+
+    /// The block with its facing turned to match.
+    pub fn apply_block(&self, b: Block) -> Block {
+        b.with_facing(b.facing().map(|facing| self.apply_facing(facing)))
+    }
+}
+
+/// A named box of blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    name: String,
+    size: [u32; 3],
+    /// Whether pasting leaves the world alone where the template has air.
+    air_as_mask: bool,
+    /// x varying fastest, then y, then z.
+    blocks: Vec<Block>,
+}
+
+impl Template {
+    /// Copies the blocks between `min` (inclusive) and `max` (exclusive).
+    /// Air overwrites the world when pasting, see `with_air_mask`.
+    pub fn capture(
+        world: &World,
+        name: impl Into<String>,
+        min: WorldBlockPos,
+        max: WorldBlockPos,
+    ) -> Self {
+        let size = [max.0 - min.0, max.1 - min.1, max.2 - min.2].map(|d| d.max(0) as u32);
+
+        let mut blocks = Vec::with_capacity(size.iter().map(|d| *d as usize).product());
+        for z in min.2..max.2 {
+            for y in min.1..max.1 {
+                for x in min.0..max.0 {
+                    blocks.push(world.get_block(&WorldBlockPos(x, y, z)));
+                }
+            }
+        }
+
+        Self {
+            name: name.into(),
+            size,
+            air_as_mask: false,
+            blocks,
+        }
+    }
+
+    /// Sets whether air in the template is left out when pasting, instead of
+    /// clearing the blocks under it.
+    pub fn with_air_mask(mut self, air_as_mask: bool) -> Self {
+        self.air_as_mask = air_as_mask;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// File name of the template, from its name.
+    pub fn file_name(&self) -> String {
+        format!("{}.vvst", self.name)
+    }
+
+    pub fn size(&self) -> [u32; 3] {
+        self.size
+    }
+
+    pub fn air_as_mask(&self) -> bool {
+        self.air_as_mask
+    }
+
+    pub fn get(&self, x: u32, y: u32, z: u32) -> Option<Block> {
+        let [size_x, size_y, size_z] = self.size;
+        if x >= size_x || y >= size_y || z >= size_z {
+            return None;
+        }
+        Some(self.blocks[self.index([x, y, z])])
+    }
+
+    /// The different blocks in the template, in the order they first appear.
+    pub fn palette(&self) -> Vec<Block> {
+        let mut seen = HashSet::new();
+        self.blocks
+            .iter()
+            .filter(|b| seen.insert(**b))
+            .copied()
+            .collect()
+    }
+
+    /// The box the template covers when pasted at `origin`, min inclusive and
+    /// max exclusive.
+    pub fn bounds(
+        &self,
+        origin: WorldBlockPos,
+        transform: Transform,
+    ) -> (WorldBlockPos, WorldBlockPos) {
+        let [x, y, z] = transform.size(self.size).map(|d| d as i32);
+        (
+            origin,
+            WorldBlockPos(origin.0 + x, origin.1 + y, origin.2 + z),
+        )
+    }
+
+    /// Sets the blocks of the template with its minimum corner at `origin`.
+    /// Returns the chunks that had a block changed.
+    pub fn paste(
+        &self,
+        world: &mut World,
+        origin: WorldBlockPos,
+        transform: Transform,
+    ) -> HashSet<ChunkPos> {
+        let mut changed = HashSet::new();
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            if self.air_as_mask && block.id() == 0 {
+                continue;
+            }
+
+            let [x, y, z] = transform.apply(self.position(i), self.size);
+            let pos = WorldBlockPos(
+                origin.0 + x as i32,
+                origin.1 + y as i32,
+                origin.2 + z as i32,
+            );
+            let block = transform.apply_block(*block);
+
+            if world.get_block(&pos) != block {
+                world.set_block(pos, block);
+                changed.insert(pos.chunk_pos());
+            }
+        }
+
+        changed
+    }
+
+    fn index(&self, [x, y, z]: [u32; 3]) -> usize {
+        let [size_x, size_y, _] = self.size.map(|d| d as usize);
+        (z as usize * size_y + y as usize) * size_x + x as usize
+    }
+
+    fn position(&self, i: usize) -> [u32; 3] {
+        let [size_x, size_y, _] = self.size.map(|d| d as usize);
+        [i % size_x, i / size_x % size_y, i / (size_x * size_y)].map(|d| d as u32)
+    }
+}
+
+impl Template {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, TemplateError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), TemplateError> {
+        Ok(fs::write(path, self.to_bytes()?)?)
+    }
+
+    /// Encodes the template, see the module docs for the format.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TemplateError> {
+        let palette = self.palette();
+        if palette.len() > u16::MAX as usize + 1 {
+            return Err(TemplateError::TooManyBlockTypes(palette.len()));
+        }
+        let indices: HashMap<Block, u16> = palette
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (*b, i as u16))
+            .collect();
+
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.push(if self.air_as_mask { FLAG_AIR_MASK } else { 0 });
+
+        // names longer than a u16 are cut off at a character boundary
+        let mut name_len = self.name.len().min(u16::MAX as usize);
+        while !self.name.is_char_boundary(name_len) {
+            name_len -= 1;
+        }
+        out.extend((name_len as u16).to_le_bytes());
+        out.extend(&self.name.as_bytes()[..name_len]);
+
+        for d in self.size {
+            out.extend(d.to_le_bytes());
+        }
+        out.extend((palette.len() as u32).to_le_bytes());
+        for b in &palette {
+            out.extend(b.0.to_le_bytes());
+        }
+
+        let mut encoder = ZlibEncoder::new(out, Compression::default());
+        for b in &self.blocks {
+            encoder.write_all(&indices[b].to_le_bytes())?;
+        }
+        Ok(encoder.finish()?)
+    }
+
+    /// Decodes a template written by `to_bytes`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, TemplateError> {
+        let mut rest = data;
+        let mut take = |len: usize| {
+            if rest.len() < len {
+                return Err(TemplateError::UnexpectedEnd);
+            }
+            let (taken, remaining) = rest.split_at(len);
+            rest = remaining;
+            Ok(taken)
+        };
+        let u32 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+
+        if take(4).map_err(|_| TemplateError::NotATemplate)? != MAGIC {
+            return Err(TemplateError::NotATemplate);
+        }
+        let version = take(1)?[0];
+        if version != VERSION {
+            return Err(TemplateError::UnsupportedVersion(version));
+        }
+        let flags = take(1)?[0];
+
+        let name_len = take(2)?;
+        let name_len = u16::from_le_bytes([name_len[0], name_len[1]]) as usize;
+        let name = std::str::from_utf8(take(name_len)?)
+            .map_err(|_| TemplateError::InvalidName)?
+            .to_string();
+
+        let size = [u32(take(4)?), u32(take(4)?), u32(take(4)?)];
+        let palette_len = u32(take(4)?) as usize;
+        let palette = take(
+            palette_len
+                .checked_mul(4)
+                .ok_or(TemplateError::UnexpectedEnd)?,
+        )?
+        .chunks_exact(4)
+        .map(|b| Block(u32(b)))
+        .collect::<Vec<_>>();
+
+        // sizes this big can't have all their blocks in the data
+        let expected = size
+            .iter()
+            .try_fold(1usize, |n, d| n.checked_mul(*d as usize))
+            .ok_or(TemplateError::UnexpectedEnd)?;
+        let len = expected
+            .checked_mul(2)
+            .ok_or(TemplateError::UnexpectedEnd)?;
+        let mut raw = Vec::new();
+        ZlibDecoder::new(rest)
+            .take(len as u64 + 1)
+            .read_to_end(&mut raw)?;
+        if raw.len() != len {
+            return Err(TemplateError::SizeMismatch {
+                expected,
+                found: raw.len() / 2,
+            });
+        }
+
+        let blocks = raw
+            .chunks_exact(2)
+            .map(|b| {
+                let index = u16::from_le_bytes([b[0], b[1]]);
+                palette
+                    .get(index as usize)
+                    .copied()
+                    .ok_or(TemplateError::InvalidPaletteIndex(index))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            name,
+            size,
+            air_as_mask: flags & FLAG_AIR_MASK != 0,
+            blocks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An L of stone along +x and +z with a block facing east at the end of
+    /// the x arm, in a box with some air.
+    fn sample_world() -> World {
+        let mut world = World::default();
+        world.fill_box(WorldBlockPos(0, 0, 0), WorldBlockPos(3, 1, 1), Block(2));
+        world.fill_box(WorldBlockPos(0, 0, 1), WorldBlockPos(1, 1, 2), Block(2));
+        world.set_block(
+            WorldBlockPos(2, 1, 0),
+            Block(3).with_facing(Some(Facing::East)),
+        );
+        world
+    }
+
+    fn sample() -> Template {
+        Template::capture(
+            &sample_world(),
+            "corner",
+            WorldBlockPos(0, 0, 0),
+            WorldBlockPos(3, 2, 2),
+        )
+    }
+
+    #[test]
+    fn templates_round_trip() {
+        let template = sample().with_air_mask(true);
+        assert_eq!(template.size(), [3, 2, 2]);
+        assert_eq!(template.get(2, 0, 0), Some(Block(2)));
+        assert_eq!(template.get(1, 0, 1), Some(Block(0)));
+        assert_eq!(template.get(3, 0, 0), None);
+        assert_eq!(template.palette().len(), 3);
+
+        let data = template.to_bytes().unwrap();
+        assert_eq!(Template::from_bytes(&data).unwrap(), template);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(template.file_name());
+        template.save(&path).unwrap();
+        assert_eq!(Template::load(&path).unwrap(), template);
+    }
+
+    #[test]
+    fn rejects_bad_data() {
+        let data = sample().to_bytes().unwrap();
+
+        assert!(matches!(
+            Template::from_bytes(b"VVRG"),
+            Err(TemplateError::NotATemplate)
+        ));
+
+        let mut wrong_version = data.clone();
+        wrong_version[4] = VERSION + 1;
+        assert!(matches!(
+            Template::from_bytes(&wrong_version),
+            Err(TemplateError::UnsupportedVersion(_))
+        ));
+
+        assert!(matches!(
+            Template::from_bytes(&data[..12]),
+            Err(TemplateError::UnexpectedEnd)
+        ));
+
+        // claim a bigger size than there are blocks
+        let mut wrong_size = data.clone();
+        let size_x = 4 + 2 + 2 + "corner".len();
+        wrong_size[size_x] = 4;
+        assert!(matches!(
+            Template::from_bytes(&wrong_size),
+            Err(TemplateError::SizeMismatch {
+                expected: 16,
+                found: 12
+            })
+        ));
+
+        // a size whose block count fits in a usize, but not twice over
+        let mut huge_size = data.clone();
+        huge_size[size_x..size_x + 12]
+            .copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1, 0, 0, 0]);
+        assert!(matches!(
+            Template::from_bytes(&huge_size),
+            Err(TemplateError::UnexpectedEnd)
+        ));
+    }
+
+    #[test]
+    fn transforms_keep_positions_in_the_box() {
+        let size = [3, 1, 2];
+        for quarter_turns in 0..4 {
+            for mirror in [false, true] {
+                let transform = Transform::new(quarter_turns, mirror);
+                let [sx, sy, sz] = transform.size(size);
+
+                let mut seen = HashSet::new();
+                for x in 0..3 {
+                    for z in 0..2 {
+                        let [tx, ty, tz] = transform.apply([x, 0, z], size);
+                        assert!(tx < sx && ty < sy && tz < sz);
+                        assert!(seen.insert((tx, tz)));
+                    }
+                }
+            }
+        }
+
+        // four quarter turns bring a facing back round
+        let turn = Transform::new(1, false);
+        let mut facing = Facing::North;
+        for expected in [Facing::East, Facing::South, Facing::West, Facing::North] {
+            facing = turn.apply_facing(facing);
+            assert_eq!(facing, expected);
+        }
+        assert_eq!(turn.apply_facing(Facing::Up), Facing::Up);
+        assert_eq!(
+            Transform::new(0, true).apply_facing(Facing::East),
+            Facing::West
+        );
+    }
+
+    #[test]
+    fn pasting_turns_and_mirrors() {
+        let template = sample();
+        let origin = WorldBlockPos(-1, 10, 5);
+
+        // a quarter turn takes the x arm to +z, and east to south
+        let mut world = World::default();
+        let changed = template.paste(&mut world, origin, Transform::new(1, false));
+        assert_eq!(
+            changed,
+            HashSet::from([ChunkPos(-1, 0, 0), ChunkPos(0, 0, 0)])
+        );
+        let at = |world: &World, x, y, z| world.get_block(&WorldBlockPos(x, y, z));
+        assert_eq!(at(&world, 0, 10, 5), Block(2));
+        assert_eq!(at(&world, 0, 10, 7), Block(2));
+        assert_eq!(at(&world, -1, 10, 5), Block(2));
+        assert_eq!(
+            at(&world, 0, 11, 7),
+            Block(3).with_facing(Some(Facing::South))
+        );
+        assert_eq!(
+            world
+                .iter_box(origin, WorldBlockPos(1, 12, 8))
+                .filter(|(_, b)| b.id() != 0)
+                .count(),
+            5
+        );
+
+        // mirroring swaps the arm over to the other side of the box
+        let mut world = World::default();
+        template.paste(&mut world, origin, Transform::new(0, true));
+        assert_eq!(at(&world, 1, 10, 6), Block(2));
+        assert_eq!(
+            at(&world, -1, 11, 5),
+            Block(3).with_facing(Some(Facing::West))
+        );
+    }
+
+    #[test]
+    fn air_is_only_pasted_without_a_mask() {
+        let mut world = World::default();
+        world.fill_box(WorldBlockPos(0, 0, 0), WorldBlockPos(3, 2, 2), Block(1));
+
+        let masked = sample().with_air_mask(true);
+        masked.paste(&mut world, WorldBlockPos(0, 0, 0), Transform::default());
+        assert_eq!(world.get_block(&WorldBlockPos(1, 0, 1)), Block(1));
+
+        // pasting the same blocks again changes nothing
+        let changed = masked.paste(&mut world, WorldBlockPos(0, 0, 0), Transform::default());
+        assert!(changed.is_empty());
+
+        sample().paste(&mut world, WorldBlockPos(0, 0, 0), Transform::default());
+        assert_eq!(world.get_block(&WorldBlockPos(1, 0, 1)), Block(0));
+    }
+}